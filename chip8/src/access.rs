@@ -0,0 +1,197 @@
+use crate::cpu::CPU;
+use crate::instructions::Instruction;
+use crate::memory::MEM_SIZE;
+
+// registers and memory the next instruction reads and writes, worked out
+// from the state before it executes. the opcode fetch and the stack are
+// not included
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+pub struct Access {
+    //bit n is set when VN is read or written
+    pub register_reads: u16,
+    pub register_writes: u16,
+    pub i_read: bool,
+    pub i_written: bool,
+    //LD DT, VX and LD ST, VX, the 60hz ticks are not instructions
+    pub dt_written: bool,
+    pub st_written: bool,
+    //(start, len), addresses past the end of memory wrap around
+    pub memory_read: Option<(usize, usize)>,
+    pub memory_written: Option<(usize, usize)>,
+}
+
+// bits for V0 through V`last`
+fn registers_up_to(last: u32) -> u16 {
+    ((1u32 << (last + 1)) - 1) as u16
+}
+
+fn overlaps(range: Option<(usize, usize)>, start: usize, end: usize) -> bool {
+    match range {
+        Some((from, len)) => (0..len).any(|offset| {
+            let addr = (from + offset) % MEM_SIZE;
+            addr >= start && addr < end
+        }),
+        None => false,
+    }
+}
+
+impl Access {
+    pub fn of(cpu: &CPU) -> Access {
+        let (instr, value) = Instruction::decode(cpu.fetch_current_instruction());
+        let x = 1 << CPU::get_x_reg(value);
+        let y = 1 << CPU::get_y_reg(value);
+        let vf = 1 << 0xF;
+        let i = cpu.get_i() as usize;
+        let mut access = Access::default();
+        match instr {
+            Instruction::SYS | Instruction::CLS | Instruction::RET | Instruction::JP | Instruction::CALL
+            | Instruction::INVALID => {}
+            Instruction::SE_VX_BT | Instruction::SNE_VX_BT | Instruction::SKP_VX | Instruction::SKNP_VX => {
+                access.register_reads = x;
+            }
+            Instruction::LD_DT_VX => {
+                access.register_reads = x;
+                access.dt_written = true;
+            }
+            Instruction::LD_ST_VX => {
+                access.register_reads = x;
+                access.st_written = true;
+            }
+            Instruction::SE_VX_VY | Instruction::SNE_VX_VY => {
+                access.register_reads = x | y;
+            }
+            Instruction::LD_VX_BT | Instruction::RND_VX_BT | Instruction::LD_VX_DT => {
+                access.register_writes = x;
+            }
+            Instruction::ADD_VX_BT => {
+                access.register_reads = x;
+                access.register_writes = x;
+            }
+            Instruction::LD_VX_VY => {
+                access.register_reads = y;
+                access.register_writes = x;
+            }
+            Instruction::OR_VX_VY | Instruction::AND_VX_VY | Instruction::XOR_VX_VY => {
+                access.register_reads = x | y;
+                access.register_writes = if cpu.quirks.logic_resets_vf { x | vf } else { x };
+            }
+            Instruction::ADD_VX_VY | Instruction::SUB_VX_VY | Instruction::SUBN_VX_VY => {
+                access.register_reads = x | y;
+                access.register_writes = x | vf;
+            }
+            Instruction::SHR_VX_VY | Instruction::SHL_VX_VY => {
+                access.register_reads = 1 << cpu.shift_source(value);
+                access.register_writes = x | vf;
+            }
+            Instruction::LD_I_ADDR => {
+                access.i_written = true;
+            }
+            Instruction::JP_V0_ADDR => {
+                access.register_reads = if cpu.quirks.jump_uses_vx { x } else { 1 };
+            }
+            Instruction::DRW_VX_VY_NIB => {
+                //draw_sprite clips the sprite at the end of memory
+                let start = i.min(MEM_SIZE);
+                let end = (start + (value & 0x000F) as usize).min(MEM_SIZE);
+                access.register_reads = x | y;
+                access.register_writes = vf;
+                access.i_read = true;
+                access.memory_read = Some((start, end - start));
+            }
+            Instruction::LD_VX_K => {
+                if cpu.keys != 0 {
+                    access.register_writes = x;
+                }
+            }
+            Instruction::ADD_I_VX => {
+                access.register_reads = x;
+                access.i_read = true;
+                access.i_written = true;
+            }
+            Instruction::LD_F_VX => {
+                access.register_reads = x;
+                access.i_written = true;
+            }
+            Instruction::LD_B_VX => {
+                access.register_reads = x;
+                access.i_read = true;
+                access.memory_written = Some((i, 3));
+            }
+            Instruction::LD_I_VX => {
+                let last = CPU::get_x_reg(value);
+                access.register_reads = registers_up_to(last);
+                access.i_read = true;
+                access.i_written = cpu.quirks.load_store_increments_i;
+                access.memory_written = Some((i, last as usize + 1));
+            }
+            Instruction::LD_VX_I => {
+                let last = CPU::get_x_reg(value);
+                access.register_writes = registers_up_to(last);
+                access.i_read = true;
+                access.i_written = cpu.quirks.load_store_increments_i;
+                access.memory_read = Some((i, last as usize + 1));
+            }
+        }
+        access
+    }
+
+    // whether memory in [start, end) is read
+    pub fn reads_memory(&self, start: usize, end: usize) -> bool {
+        overlaps(self.memory_read, start, end)
+    }
+
+    pub fn writes_memory(&self, start: usize, end: usize) -> bool {
+        overlaps(self.memory_written, start, end)
+    }
+
+    pub fn reads_register(&self, register: u32) -> bool {
+        self.register_reads & (1 << register) != 0
+    }
+
+    pub fn writes_register(&self, register: u32) -> bool {
+        self.register_writes & (1 << register) != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::access::Access;
+    use crate::cpu::CPU;
+    use crate::memory::Memory;
+
+    #[test]
+    fn test_indirect_access() {
+        let mut mem = Memory::new();
+        mem.load_program(&[
+            // LD I, 0xFFE
+            0xAF, 0xFE, //0x200
+            // LD [I], 3
+            0xF3, 0x55, //0x202
+            // LD 2, [I]
+            0xF2, 0x65, //0x204
+            // DRW 0, 1, 5
+            0xD0, 0x15, //0x206
+        ]);
+        let mut cpu = CPU::new(mem);
+        assert!(Access::of(&cpu).i_written);
+        cpu.step();
+
+        let store = Access::of(&cpu);
+        assert_eq!(0x000F, store.register_reads);
+        assert!(store.writes_memory(0xFFF, 0x1000));
+        //the last two registers wrap to the start of memory
+        assert!(store.writes_memory(0x000, 0x002));
+        assert!(!store.writes_memory(0x002, 0x200));
+        cpu.step();
+
+        let load = Access::of(&cpu);
+        assert_eq!(0x0007, load.register_writes);
+        assert!(load.reads_memory(0x000, 0x001));
+        cpu.step();
+
+        let draw = Access::of(&cpu);
+        assert!(draw.reads_register(0x0) && draw.reads_register(0x1));
+        assert!(draw.writes_register(0xF));
+        assert_eq!(Some((0xFFE, 2)), draw.memory_read);
+    }
+}
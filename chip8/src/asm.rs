@@ -0,0 +1,430 @@
+// Two-pass assembler for the Cowgod mnemonics the disassembler prints.
+//
+//   ; comments run to the end of the line
+//   SPEED = 2               ; constants
+//   main:                   ; labels
+//       LD I, sprite
+//       ADD V0, SPEED + 1   ; operands can add and subtract symbols
+//       JP main
+//   sprite:
+//       db 0xF0, 0x90, 0b11110000
+//       dw main             ; 16 bit, big endian
+//   include "font.asm"      ; relative to the including file
+//
+// The first pass expands includes and assigns every label an address, the
+// second encodes instructions once all symbols are known. Mnemonics,
+// registers and directives are case insensitive, symbols are not.
+use crate::memory::{MEM_SIZE, PROGRAM_LOAD_OFFSET};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct AsmError {
+    pub file: String,
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.file, self.line, self.message)
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+#[derive(Debug, Clone)]
+struct Location {
+    file: String,
+    line: usize,
+}
+
+impl Location {
+    fn error<T>(&self, message: String) -> Result<T, AsmError> {
+        Err(AsmError { file: self.file.clone(), line: self.line, message })
+    }
+}
+
+struct Statement {
+    location: Location,
+    addr: usize,
+    mnemonic: String,
+    operands: Vec<String>,
+}
+
+enum Symbol {
+    Label(usize),
+    //evaluated when used, so constants may refer to labels defined later
+    Constant(String, Location),
+}
+
+struct Assembler<F> {
+    read: F,
+    symbols: HashMap<String, Symbol>,
+    statements: Vec<Statement>,
+    addr: usize,
+}
+
+fn strip_comment(line: &str) -> &str {
+    match line.find(';') {
+        Some(at) => &line[..at],
+        None => line,
+    }
+}
+
+fn is_identifier(word: &str) -> bool {
+    let mut chars = word.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn parse_literal(word: &str) -> Option<i64> {
+    let lower = word.to_ascii_lowercase();
+    if let Some(hex) = lower.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok()
+    } else if let Some(binary) = lower.strip_prefix("0b") {
+        i64::from_str_radix(binary, 2).ok()
+    } else {
+        lower.parse().ok()
+    }
+}
+
+// V0-VF
+fn parse_v(operand: &str) -> Option<u16> {
+    let lower = operand.to_ascii_lowercase();
+    let digit = lower.strip_prefix('v')?;
+    if digit.len() != 1 {
+        return None;
+    }
+    u16::from_str_radix(digit, 16).ok()
+}
+
+// names that can not be used for symbols
+fn is_reserved(name: &str) -> bool {
+    parse_v(name).is_some() || matches!(name.to_ascii_lowercase().as_str(), "i" | "dt" | "st" | "k" | "f" | "b")
+}
+
+impl<F: FnMut(&str) -> Result<String, String>> Assembler<F> {
+    fn new(read: F) -> Assembler<F> {
+        Assembler {
+            read,
+            symbols: HashMap::new(),
+            statements: vec![],
+            addr: PROGRAM_LOAD_OFFSET,
+        }
+    }
+
+    fn define(&mut self, name: &str, symbol: Symbol, location: &Location) -> Result<(), AsmError> {
+        if !is_identifier(name) || is_reserved(name) {
+            return location.error(format!("invalid symbol name {}", name));
+        }
+        if self.symbols.contains_key(name) {
+            return location.error(format!("{} is already defined", name));
+        }
+        self.symbols.insert(name.to_string(), symbol);
+        Ok(())
+    }
+
+    // first pass over one file
+    fn layout(&mut self, file: &str, source: &str, depth: usize) -> Result<(), AsmError> {
+        for (index, line) in source.lines().enumerate() {
+            let location = Location { file: file.to_string(), line: index + 1 };
+            let mut rest = strip_comment(line).trim();
+            //any number of labels can precede a statement
+            while let Some(colon) = rest.find(':') {
+                let name = rest[..colon].trim();
+                if !is_identifier(name) {
+                    break;
+                }
+                self.define(name, Symbol::Label(self.addr), &location)?;
+                rest = rest[colon + 1..].trim();
+            }
+            if rest.is_empty() {
+                continue;
+            }
+            if let Some((name, value)) = rest.split_once('=') {
+                self.define(name.trim(), Symbol::Constant(value.trim().to_string(), location.clone()), &location)?;
+                continue;
+            }
+            let (mnemonic, operands) = match rest.find(char::is_whitespace) {
+                Some(at) => (rest[..at].to_ascii_uppercase(), rest[at..].trim()),
+                None => (rest.to_ascii_uppercase(), ""),
+            };
+            if mnemonic == "INCLUDE" {
+                let name = operands.trim_matches('"');
+                if depth >= MAX_INCLUDE_DEPTH {
+                    return location.error(format!("includes nested too deeply at {}", name));
+                }
+                let path = Path::new(file).parent().map(|dir| dir.join(name)).unwrap_or_else(|| name.into());
+                let path = path.to_string_lossy().into_owned();
+                let included = match (self.read)(&path) {
+                    Ok(included) => included,
+                    Err(err) => return location.error(format!("cannot include {}: {}", path, err)),
+                };
+                self.layout(&path, &included, depth + 1)?;
+                continue;
+            }
+            let operands: Vec<String> = if operands.is_empty() {
+                vec![]
+            } else {
+                operands.split(',').map(|operand| operand.trim().to_string()).collect()
+            };
+            let size = match mnemonic.as_str() {
+                "DB" => operands.len(),
+                "DW" => operands.len() * 2,
+                _ => 2,
+            };
+            self.statements.push(Statement { location, addr: self.addr, mnemonic, operands });
+            self.addr += size;
+            if self.addr > MEM_SIZE {
+                let location = &self.statements.last().unwrap().location;
+                return location.error("program does not fit in memory".to_string());
+            }
+        }
+        Ok(())
+    }
+
+    fn symbol_value(&self, name: &str, location: &Location, depth: usize) -> Result<i64, AsmError> {
+        match self.symbols.get(name) {
+            Some(Symbol::Label(addr)) => Ok(*addr as i64),
+            Some(Symbol::Constant(_, _)) if depth > self.symbols.len() => {
+                location.error(format!("{} is defined in terms of itself", name))
+            }
+            Some(Symbol::Constant(expr, defined_at)) => self.evaluate(expr, defined_at, depth + 1),
+            None => location.error(format!("undefined symbol {}", name)),
+        }
+    }
+
+    // numbers and symbols joined by + and -
+    fn evaluate(&self, expr: &str, location: &Location, depth: usize) -> Result<i64, AsmError> {
+        let mut total = 0i64;
+        let mut sign = 1;
+        let mut term = String::new();
+        let mut terms = vec![];
+        for c in expr.chars().chain(std::iter::once('+')) {
+            if (c == '+' || c == '-') && !term.trim().is_empty() {
+                terms.push((sign, term.trim().to_string()));
+                term.clear();
+                sign = if c == '-' { -1 } else { 1 };
+            } else if c == '-' {
+                sign = -sign;
+            } else if c != '+' {
+                term.push(c);
+            }
+        }
+        if terms.is_empty() {
+            return location.error("missing operand".to_string());
+        }
+        for (sign, term) in terms {
+            let value = match parse_literal(&term) {
+                Some(value) => value,
+                None if is_identifier(&term) => self.symbol_value(&term, location, depth)?,
+                None => return location.error(format!("invalid operand {}", term)),
+            };
+            total += sign * value;
+        }
+        Ok(total)
+    }
+
+    fn value(&self, operand: &str, location: &Location, max: i64) -> Result<u16, AsmError> {
+        let value = self.evaluate(operand, location, 0)?;
+        //negative bytes are written in two's complement
+        let value = if value < 0 && max == 0xFF && value >= -0x80 { value + 0x100 } else { value };
+        if value < 0 || value > max {
+            return location.error(format!("{} is out of range 0 to 0x{:X}", operand, max));
+        }
+        Ok(value as u16)
+    }
+
+    fn encode(&self, statement: &Statement) -> Result<Vec<u8>, AsmError> {
+        let location = &statement.location;
+        let operands: Vec<&str> = statement.operands.iter().map(|operand| operand.as_str()).collect();
+        let upper: Vec<String> = operands.iter().map(|operand| operand.to_ascii_uppercase()).collect();
+        let upper: Vec<&str> = upper.iter().map(|operand| operand.as_str()).collect();
+        let v = |index: usize| parse_v(operands[index]);
+        let vx = |index: usize| v(index).map(|x| x << 8);
+        let vy = |index: usize| v(index).map(|y| y << 4);
+        let addr = |index: usize| self.value(operands[index], location, 0xFFF);
+        let byte = |index: usize| self.value(operands[index], location, 0xFF);
+
+        match statement.mnemonic.as_str() {
+            "DB" => return operands.iter().map(|operand| self.value(operand, location, 0xFF).map(|b| b as u8)).collect(),
+            "DW" => {
+                let mut out = vec![];
+                for operand in operands.iter() {
+                    out.extend_from_slice(&self.value(operand, location, 0xFFFF)?.to_be_bytes());
+                }
+                return Ok(out);
+            }
+            _ => {}
+        }
+
+        let opcode = match (statement.mnemonic.as_str(), upper.as_slice()) {
+            ("CLS", []) => Some(0x00E0),
+            ("RET", []) => Some(0x00EE),
+            ("SYS", [_]) => Some(addr(0)?),
+            //other registers have to be the top nibble of the address, as the jump quirk reads it
+            ("JP", [_, _]) => match v(0) {
+                Some(x) if x == 0 || addr(1)? >> 8 == x => Some(0xB000 | addr(1)?),
+                _ => None,
+            },
+            ("JP", [_]) => Some(0x1000 | addr(0)?),
+            ("CALL", [_]) => Some(0x2000 | addr(0)?),
+            ("SE", [_, _]) => match vy(1) {
+                Some(y) => vx(0).map(|x| 0x5000 | x | y),
+                None => vx(0).map(|x| byte(1).map(|b| 0x3000 | x | b)).transpose()?,
+            },
+            ("SNE", [_, _]) => match vy(1) {
+                Some(y) => vx(0).map(|x| 0x9000 | x | y),
+                None => vx(0).map(|x| byte(1).map(|b| 0x4000 | x | b)).transpose()?,
+            },
+            ("LD", ["I", _]) => Some(0xA000 | addr(1)?),
+            ("LD", ["DT", _]) => vx(1).map(|x| 0xF015 | x),
+            ("LD", ["ST", _]) => vx(1).map(|x| 0xF018 | x),
+            ("LD", ["F", _]) => vx(1).map(|x| 0xF029 | x),
+            ("LD", ["B", _]) => vx(1).map(|x| 0xF033 | x),
+            ("LD", ["[I]", _]) => vx(1).map(|x| 0xF055 | x),
+            ("LD", [_, "DT"]) => vx(0).map(|x| 0xF007 | x),
+            ("LD", [_, "K"]) => vx(0).map(|x| 0xF00A | x),
+            ("LD", [_, "[I]"]) => vx(0).map(|x| 0xF065 | x),
+            ("LD", [_, _]) => match vy(1) {
+                Some(y) => vx(0).map(|x| 0x8000 | x | y),
+                None => vx(0).map(|x| byte(1).map(|b| 0x6000 | x | b)).transpose()?,
+            },
+            ("ADD", ["I", _]) => vx(1).map(|x| 0xF01E | x),
+            ("ADD", [_, _]) => match vy(1) {
+                Some(y) => vx(0).map(|x| 0x8004 | x | y),
+                None => vx(0).map(|x| byte(1).map(|b| 0x7000 | x | b)).transpose()?,
+            },
+            ("OR", [_, _]) => vx(0).and_then(|x| vy(1).map(|y| 0x8001 | x | y)),
+            ("AND", [_, _]) => vx(0).and_then(|x| vy(1).map(|y| 0x8002 | x | y)),
+            ("XOR", [_, _]) => vx(0).and_then(|x| vy(1).map(|y| 0x8003 | x | y)),
+            ("SUB", [_, _]) => vx(0).and_then(|x| vy(1).map(|y| 0x8005 | x | y)),
+            ("SUBN", [_, _]) => vx(0).and_then(|x| vy(1).map(|y| 0x8007 | x | y)),
+            //without VY the register shifts itself under either quirk
+            ("SHR", [_]) => v(0).map(|x| 0x8006 | x << 8 | x << 4),
+            ("SHR", [_, _]) => vx(0).and_then(|x| vy(1).map(|y| 0x8006 | x | y)),
+            ("SHL", [_]) => v(0).map(|x| 0x800E | x << 8 | x << 4),
+            ("SHL", [_, _]) => vx(0).and_then(|x| vy(1).map(|y| 0x800E | x | y)),
+            ("RND", [_, _]) => vx(0).map(|x| byte(1).map(|b| 0xC000 | x | b)).transpose()?,
+            ("DRW", [_, _, _]) => match (vx(0), vy(1)) {
+                (Some(x), Some(y)) => Some(0xD000 | x | y | self.value(operands[2], location, 0xF)?),
+                _ => None,
+            },
+            ("SKP", [_]) => vx(0).map(|x| 0xE09E | x),
+            ("SKNP", [_]) => vx(0).map(|x| 0xE0A1 | x),
+            _ => None,
+        };
+        match opcode {
+            Some(opcode) => Ok(opcode.to_be_bytes().to_vec()),
+            None => location.error(format!("invalid instruction {} {}", statement.mnemonic, operands.join(", ")).trim().to_string()),
+        }
+    }
+
+    fn assemble(&mut self, name: &str, source: &str) -> Result<Vec<u8>, AsmError> {
+        self.layout(name, source, 0)?;
+        let mut rom = vec![0; self.addr - PROGRAM_LOAD_OFFSET];
+        for statement in self.statements.iter() {
+            let bytes = self.encode(statement)?;
+            let at = statement.addr - PROGRAM_LOAD_OFFSET;
+            rom[at..at + bytes.len()].copy_from_slice(&bytes);
+        }
+        Ok(rom)
+    }
+}
+
+// `read` loads include files by path
+pub fn assemble_with<F: FnMut(&str) -> Result<String, String>>(name: &str, source: &str, read: F) -> Result<Vec<u8>, AsmError> {
+    Assembler::new(read).assemble(name, source)
+}
+
+pub fn assemble(source: &str) -> Result<Vec<u8>, AsmError> {
+    assemble_with("<source>", source, |path| fs::read_to_string(path).map_err(|err| err.to_string()))
+}
+
+pub fn assemble_file(path: &str) -> Result<Vec<u8>, AsmError> {
+    let read = |path: &str| fs::read_to_string(path).map_err(|err| err.to_string());
+    let source = fs::read_to_string(path).map_err(|err| AsmError { file: path.to_string(), line: 0, message: err.to_string() })?;
+    assemble_with(path, &source, read)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::asm::{assemble, assemble_with, AsmError};
+    use crate::disasm::{disassemble, Syntax};
+    use crate::rng::Rng;
+
+    #[test]
+    fn test_assemble() {
+        let source = "
+            SPEED = STEP + 1   ; constants can use later symbols
+            STEP = 2
+            main:
+                CLS
+                LD I, sprite
+                LD V0, SPEED
+                add v1, -1
+                SE V0, V1
+                CALL draw
+                JP main
+            draw: DRW V0, V1, 3
+                shr v2
+                RET
+            sprite:
+                db 0xF0, 0b10010000, 240
+                dw main + 2
+        ";
+        assert_eq!(
+            Ok(vec![
+                0x00, 0xE0, 0xA2, 0x14, 0x60, 0x03, 0x71, 0xFF, 0x50, 0x10, 0x22, 0x0E, 0x12, 0x00, 0xD0, 0x13,
+                0x82, 0x26, 0x00, 0xEE, 0xF0, 0x90, 0xF0, 0x02, 0x02,
+            ]),
+            assemble(source)
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = |source: &str| assemble(source).unwrap_err();
+        assert_eq!(
+            AsmError { file: "<source>".to_string(), line: 3, message: "undefined symbol nowhere".to_string() },
+            error("main:\n  CLS\n  JP nowhere\n")
+        );
+        assert_eq!(2, error("CLS\nLD V0, 0x100").line);
+        assert_eq!(2, error("a:\na: CLS").line);
+        assert_eq!("invalid instruction DRW V0, 3, 4", error("DRW V0, 3, 4").message);
+        assert_eq!(Ok(vec![0xB2, 0x10]), assemble("JP V2, 0x210"));
+        assert_eq!("invalid instruction JP V2, 0x310", error("JP V2, 0x310").message);
+        assert_eq!("x is defined in terms of itself", error("x = x + 1\nLD V0, x").message);
+        assert!(error("v3 = 1").message.starts_with("invalid symbol name"));
+    }
+
+    #[test]
+    fn test_include() {
+        let rom = assemble_with("game.asm", "CALL font\ninclude \"lib/font.asm\"", |path| {
+            assert_eq!("lib/font.asm", path);
+            Ok("font:\n  db 0xF0\n  JP font\n  FOO bar".to_string())
+        });
+        let error = rom.unwrap_err();
+        assert_eq!(("lib/font.asm".to_string(), 4), (error.file, error.line));
+
+        let rom = assemble_with("game.asm", "CALL font\ninclude \"font.asm\"", |_| Ok("font: RET".to_string()));
+        assert_eq!(Ok(vec![0x22, 0x02, 0x00, 0xEE]), rom);
+        let error = assemble_with("a.asm", "include \"a.asm\"", |_| Ok("include \"a.asm\"".to_string())).unwrap_err();
+        assert!(error.message.contains("nested too deeply"));
+    }
+
+    #[test]
+    fn test_disassembly_round_trip() {
+        let mut rng = Rng::new(41);
+        for length in 0..200 {
+            let rom: Vec<u8> = (0..length).map(|_| rng.next_u8()).collect();
+            let listing = disassemble(&rom, Syntax::Cowgod);
+            assert_eq!(Ok(rom), assemble(&listing), "{}", listing);
+        }
+    }
+}
@@ -0,0 +1,254 @@
+use crate::cpu::CPU;
+#[cfg(feature = "std")]
+use crate::instructions::Instruction;
+#[cfg(feature = "std")]
+use crate::memory::MEM_SIZE;
+
+pub trait Backend {
+    fn name(&self) -> &'static str;
+
+    // executes exactly one instruction at the current program counter
+    fn step(&mut self, cpu: &mut CPU);
+
+    fn run(&mut self, cpu: &mut CPU, steps: usize) {
+        for _ in 0..steps {
+            self.step(cpu);
+        }
+    }
+}
+
+// the reference implementation, decodes every instruction through CPU::step
+pub struct Interpreter;
+
+impl Backend for Interpreter {
+    fn name(&self) -> &'static str {
+        "interpreter"
+    }
+
+    fn step(&mut self, cpu: &mut CPU) {
+        cpu.step();
+    }
+}
+
+pub struct Machine<B: Backend> {
+    pub cpu: CPU,
+    pub backend: B,
+}
+
+impl<B: Backend> Machine<B> {
+    pub fn new(cpu: CPU, backend: B) -> Machine<B> {
+        Machine { cpu, backend }
+    }
+
+    pub fn step(&mut self) {
+        self.backend.step(&mut self.cpu);
+    }
+
+    pub fn run(&mut self, steps: usize) {
+        self.backend.run(&mut self.cpu, steps);
+    }
+}
+
+// remembers the decoded instruction per address, entries are keyed by the
+// raw opcode so self-modifying code is decoded again
+#[cfg(feature = "std")]
+pub struct CachedInterpreter {
+    cache: Vec<Option<(u32, Instruction, u32)>>,
+}
+
+#[cfg(feature = "std")]
+impl CachedInterpreter {
+    pub fn new() -> CachedInterpreter {
+        CachedInterpreter {
+            cache: vec![None; MEM_SIZE],
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Default for CachedInterpreter {
+    fn default() -> Self {
+        CachedInterpreter::new()
+    }
+}
+
+#[cfg(feature = "std")]
+impl Backend for CachedInterpreter {
+    fn name(&self) -> &'static str {
+        "cached"
+    }
+
+    fn step(&mut self, cpu: &mut CPU) {
+        let opcode = cpu.fetch_current_instruction();
+        let entry = &mut self.cache[cpu.get_pc() as usize];
+        let (instr, value) = match *entry {
+            Some((cached, instr, value)) if cached == opcode => (instr, value),
+            _ => {
+                let (instr, value) = Instruction::decode(opcode);
+                *entry = Some((opcode, instr, value));
+                (instr, value)
+            }
+        };
+        cpu.execute(instr, value);
+    }
+}
+
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Divergence {
+    //number of instructions both backends executed before they disagreed
+    pub step: u64,
+    pub pc: u16,
+    pub opcode: u32,
+    pub difference: String,
+}
+
+// runs `primary` on the real machine and `shadow` on a copy of it taken
+// right before every instruction, so changes made between steps such as
+// key presses reach both, and compares them afterwards
+#[cfg(feature = "std")]
+pub struct Lockstep<A: Backend, B: Backend> {
+    primary: A,
+    shadow: B,
+    steps: u64,
+    divergence: Option<Divergence>,
+}
+
+#[cfg(feature = "std")]
+impl<A: Backend, B: Backend> Lockstep<A, B> {
+    pub fn new(primary: A, shadow: B) -> Lockstep<A, B> {
+        Lockstep {
+            primary,
+            shadow,
+            steps: 0,
+            divergence: None,
+        }
+    }
+
+    pub fn divergence(&self) -> Option<&Divergence> {
+        self.divergence.as_ref()
+    }
+}
+
+#[cfg(feature = "std")]
+impl<A: Backend, B: Backend> Backend for Lockstep<A, B> {
+    fn name(&self) -> &'static str {
+        "lockstep"
+    }
+
+    fn step(&mut self, cpu: &mut CPU) {
+        if self.divergence.is_some() {
+            self.primary.step(cpu);
+            return;
+        }
+        let mut shadow_cpu = cpu.clone();
+        let pc = cpu.get_pc();
+        let opcode = cpu.fetch_current_instruction();
+        self.primary.step(cpu);
+        self.shadow.step(&mut shadow_cpu);
+        if let Some(difference) = cpu.diff(&shadow_cpu) {
+            self.divergence = Some(Divergence {
+                step: self.steps,
+                pc,
+                opcode,
+                difference,
+            });
+        }
+        self.steps += 1;
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use crate::backend::{Backend, CachedInterpreter, Interpreter, Lockstep, Machine};
+    use crate::cpu::CPU;
+    use crate::memory::Memory;
+
+    fn prepare_cpu(prg: Vec<u8>) -> CPU {
+        let mut mem = Memory::new();
+        mem.load_program(&prg);
+        CPU::new(mem)
+    }
+
+    // increments VA on every step, unlike the interpreter
+    struct Broken;
+
+    impl Backend for Broken {
+        fn name(&self) -> &'static str {
+            "broken"
+        }
+
+        fn step(&mut self, cpu: &mut CPU) {
+            cpu.step();
+            cpu.write_register(0xA, cpu.read_register(0xA).wrapping_add(1));
+        }
+    }
+
+    #[test]
+    fn test_cached_matches_interpreter() {
+        let prg = vec![
+            // LD A, 0x05
+            0x6A, 0x05, //0x200
+            // ADD A, 0x01
+            0x7A, 0x01, //0x202
+            // JP 0x202
+            0x12, 0x02, //0x204
+        ];
+        let mut machine = Machine::new(prepare_cpu(prg), Lockstep::new(Interpreter, CachedInterpreter::new()));
+        machine.run(50);
+        assert_eq!(None, machine.backend.divergence());
+        assert_eq!(0x1E, machine.cpu.read_register(0xA));
+    }
+
+    #[test]
+    fn test_cache_invalidation() {
+        let mut cpu = prepare_cpu(vec![
+            // LD A, 0x05
+            0x6A, 0x05, //0x200
+            // JP 0x200
+            0x12, 0x00, //0x202
+        ]);
+        let mut backend = CachedInterpreter::new();
+        backend.run(&mut cpu, 2);
+        cpu.memory.memory[0x201] = 0x07;
+        backend.step(&mut cpu);
+        assert_eq!(0x07, cpu.read_register(0xA));
+    }
+
+    #[test]
+    fn test_lockstep_divergence() {
+        let prg = vec![
+            // LD B, 0x01
+            0x6B, 0x01, //0x200
+            // JP 0x200
+            0x12, 0x00, //0x202
+        ];
+        let mut machine = Machine::new(prepare_cpu(prg), Lockstep::new(Interpreter, Broken));
+        machine.run(4);
+        let divergence = machine.backend.divergence().unwrap();
+        assert_eq!(0, divergence.step);
+        assert_eq!(0x200, divergence.pc);
+        assert_eq!(0x6B01, divergence.opcode);
+        assert_eq!("VA: 0x00 != 0x01", divergence.difference);
+    }
+
+    #[test]
+    fn test_lockstep_input() {
+        let prg = vec![
+            // LD 0, 0x05
+            0x60, 0x05, //0x200
+            // SKP 0
+            0xE0, 0x9E, //0x202
+            // JP 0x202
+            0x12, 0x02, //0x204
+            // LD A, 0x01
+            0x6A, 0x01, //0x206
+        ];
+        let mut machine = Machine::new(prepare_cpu(prg), Lockstep::new(Interpreter, CachedInterpreter::new()));
+        machine.run(5);
+        machine.cpu.press_key(0x5);
+        machine.run(2);
+        assert_eq!(None, machine.backend.divergence());
+        assert_eq!(0x01, machine.cpu.read_register(0xA));
+    }
+}
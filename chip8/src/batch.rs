@@ -0,0 +1,171 @@
+use crate::cpu::{CPU, CYCLES_PER_FRAME};
+use crate::display::{HEIGHT, WIDTH};
+use crate::memory::Memory;
+use std::thread;
+
+pub const FRAMEBUFFER_SIZE: usize = WIDTH * HEIGHT;
+
+// owns many independent machines running the same rom and advances them
+// frame by frame on a pool of scoped worker threads, machine `index` draws
+// its random numbers from seed `base_seed + index`
+pub struct Batch {
+    machines: Vec<CPU>,
+    pub cycles_per_frame: usize,
+    pub threads: usize,
+}
+
+impl Batch {
+    pub fn new(rom: &[u8], count: usize, base_seed: u64) -> Batch {
+        let mut mem = Memory::new();
+        mem.load_program(rom);
+        let template = CPU::new(mem);
+        let machines = (0..count)
+            .map(|index| {
+                let mut cpu = template.clone();
+                cpu.seed_rng(base_seed.wrapping_add(index as u64));
+                cpu
+            })
+            .collect();
+        Batch {
+            machines,
+            cycles_per_frame: CYCLES_PER_FRAME,
+            threads: thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.machines.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.machines.is_empty()
+    }
+
+    pub fn machine(&self, index: usize) -> &CPU {
+        &self.machines[index]
+    }
+
+    pub fn machine_mut(&mut self, index: usize) -> &mut CPU {
+        &mut self.machines[index]
+    }
+
+    pub fn step_frame(&mut self) {
+        self.run_frames(1);
+    }
+
+    pub fn run_frames(&mut self, frames: usize) {
+        if self.machines.is_empty() {
+            return;
+        }
+        let cycles = self.cycles_per_frame;
+        let chunk_size = self.machines.len().div_ceil(self.threads.max(1));
+        thread::scope(|scope| {
+            for chunk in self.machines.chunks_mut(chunk_size) {
+                scope.spawn(move || {
+                    for cpu in chunk.iter_mut() {
+                        for _ in 0..frames {
+                            cpu.run_frame(cycles);
+                        }
+                    }
+                });
+            }
+        });
+    }
+
+    // every framebuffer back to back, FRAMEBUFFER_SIZE bytes per machine
+    pub fn framebuffers(&self) -> Vec<u8> {
+        let mut out = vec![0; self.machines.len() * FRAMEBUFFER_SIZE];
+        for (cpu, dst) in self.machines.iter().zip(out.chunks_mut(FRAMEBUFFER_SIZE)) {
+            dst.copy_from_slice(&cpu.display.pixels);
+        }
+        out
+    }
+
+    // V0 to VF of every machine, 16 bytes per machine
+    pub fn registers(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.machines.len() * 16);
+        for cpu in self.machines.iter() {
+            for reg in 0..16 {
+                out.push(cpu.read_register(reg));
+            }
+        }
+        out
+    }
+
+    pub fn index_registers(&self) -> Vec<u16> {
+        self.machines.iter().map(|cpu| cpu.get_i()).collect()
+    }
+
+    pub fn program_counters(&self) -> Vec<u16> {
+        self.machines.iter().map(|cpu| cpu.get_pc()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::batch::{Batch, FRAMEBUFFER_SIZE};
+
+    #[test]
+    fn test_independent_machines() {
+        let rom = vec![
+            // ADD A, 0x01
+            0x7A, 0x01, //0x200
+            // LD I, 0x20A
+            0xA2, 0x0A, //0x202
+            // DRW B, 0, 1
+            0xDB, 0x01, //0x204
+            // JP 0x206
+            0x12, 0x06, //0x206
+            // unused
+            0x00, 0x00, //0x208
+            // sprite
+            0x80, //0x20A
+        ];
+        let mut batch = Batch::new(&rom, 5, 0);
+        batch.threads = 2;
+        for index in 0..batch.len() {
+            batch.machine_mut(index).write_register(0xB, index as u8);
+        }
+        batch.run_frames(3);
+
+        let registers = batch.registers();
+        assert_eq!(5 * 16, registers.len());
+        for index in 0..batch.len() {
+            assert_eq!(0x01, registers[index * 16 + 0xA]);
+            assert_eq!(index as u8, registers[index * 16 + 0xB]);
+        }
+        let framebuffers = batch.framebuffers();
+        assert_eq!(5 * FRAMEBUFFER_SIZE, framebuffers.len());
+        for index in 0..batch.len() {
+            let framebuffer = &framebuffers[index * FRAMEBUFFER_SIZE..(index + 1) * FRAMEBUFFER_SIZE];
+            assert_eq!(1, framebuffer.iter().filter(|&&p| p == 1).count());
+            assert_eq!(1, framebuffer[index]);
+        }
+        assert_eq!(vec![0x206; 5], batch.program_counters());
+    }
+
+    #[test]
+    fn test_seeds() {
+        let rom = vec![
+            // RND A, 0xFF
+            0xCA, 0xFF, //0x200
+            // RND B, 0xFF
+            0xCB, 0xFF, //0x202
+            // JP 0x204
+            0x12, 0x04, //0x204
+        ];
+        let mut batch = Batch::new(&rom, 4, 100);
+        batch.run_frames(1);
+        let mut again = Batch::new(&rom, 4, 100);
+        again.run_frames(1);
+        assert_eq!(batch.registers(), again.registers());
+
+        let mut shifted = Batch::new(&rom, 4, 101);
+        shifted.run_frames(1);
+        let registers = batch.registers();
+        //machine 1 of the first batch and machine 0 of the second share a seed
+        assert_eq!(registers[16..32], shifted.registers()[..16]);
+        let pairs: Vec<&[u8]> = registers.chunks(16).map(|regs| &regs[0xA..0xC]).collect();
+        assert!(pairs.iter().skip(1).any(|&pair| pair != pairs[0]));
+    }
+}
@@ -0,0 +1,329 @@
+// Code coverage. Every byte of memory collects flags for how it was used:
+// executed as an instruction, read as data by DRW, LD VX, [I] and the like,
+// or written at run time. Coverage of several runs merges by combining the
+// flags, so a test suite can run each test separately.
+//
+// Coverage file format:
+//
+//   magic "C8CV", version: u16 little endian, one flag byte per address
+//
+// With std the flags turn into a listing of the rom, each line marked with
+// X for executed, R for read and W for written:
+//
+//   X--  0x200  LD I, data_20e
+//   -R-  0x20E  db 0xF0, 0x90
+use crate::access::Access;
+use crate::cpu::CPU;
+use crate::memory::MEM_SIZE;
+use core::fmt;
+
+pub const EXECUTED: u8 = 0x1;
+pub const READ: u8 = 0x2;
+pub const WRITTEN: u8 = 0x4;
+
+pub const MAGIC: [u8; 4] = *b"C8CV";
+pub const FORMAT_VERSION: u16 = 1;
+//magic, version and the flags
+pub const FILE_LEN: usize = 6 + MEM_SIZE;
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum CoverageError {
+    BadMagic,
+    UnsupportedVersion(u16),
+    Truncated,
+}
+
+impl fmt::Display for CoverageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CoverageError::BadMagic => write!(f, "not a coverage file"),
+            CoverageError::UnsupportedVersion(version) => write!(f, "unsupported coverage version {}", version),
+            CoverageError::Truncated => write!(f, "coverage file is truncated"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for CoverageError {}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Coverage {
+    flags: [u8; MEM_SIZE],
+}
+
+impl Default for Coverage {
+    fn default() -> Coverage {
+        Coverage::new()
+    }
+}
+
+impl Coverage {
+    pub fn new() -> Coverage {
+        Coverage { flags: [0; MEM_SIZE] }
+    }
+
+    // EXECUTED, READ and WRITTEN bits of the byte at addr
+    pub fn flags(&self, addr: usize) -> u8 {
+        self.flags[addr % MEM_SIZE]
+    }
+
+    fn mark(&mut self, range: Option<(usize, usize)>, flag: u8) {
+        if let Some((start, len)) = range {
+            for offset in 0..len {
+                self.flags[(start + offset) % MEM_SIZE] |= flag;
+            }
+        }
+    }
+
+    // the instruction at PC, before it executes
+    pub fn record(&mut self, cpu: &CPU) {
+        let access = Access::of(cpu);
+        self.mark(Some((cpu.get_pc() as usize, 2)), EXECUTED);
+        self.mark(access.memory_read, READ);
+        self.mark(access.memory_written, WRITTEN);
+    }
+
+    // executes one instruction on `cpu` and records it
+    pub fn step(&mut self, cpu: &mut CPU) {
+        self.record(cpu);
+        cpu.step();
+    }
+
+    pub fn run_frame(&mut self, cpu: &mut CPU, cycles: usize) {
+        cpu.run_frame_with(cycles, |cpu| self.step(cpu));
+    }
+
+    pub fn run(&mut self, cpu: &mut CPU, cycles: usize) {
+        cpu.run_with(cycles, |cpu| self.step(cpu));
+    }
+
+    // adds the coverage of another run
+    pub fn merge(&mut self, other: &Coverage) {
+        for (flags, other) in self.flags.iter_mut().zip(other.flags.iter()) {
+            *flags |= other;
+        }
+    }
+
+    // addresses in [start, end) with all of `flags` set
+    pub fn count(&self, start: usize, end: usize, flags: u8) -> usize {
+        self.flags[start.min(MEM_SIZE)..end.min(MEM_SIZE)].iter().filter(|&&bits| bits & flags == flags).count()
+    }
+
+    pub fn to_bytes(&self) -> [u8; FILE_LEN] {
+        let mut out = [0; FILE_LEN];
+        out[..4].copy_from_slice(&MAGIC);
+        out[4..6].copy_from_slice(&FORMAT_VERSION.to_le_bytes());
+        out[6..].copy_from_slice(&self.flags);
+        out
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Coverage, CoverageError> {
+        if data.len() < 6 {
+            return Err(CoverageError::Truncated);
+        }
+        if data[..4] != MAGIC {
+            return Err(CoverageError::BadMagic);
+        }
+        let version = u16::from_le_bytes([data[4], data[5]]);
+        if version > FORMAT_VERSION {
+            return Err(CoverageError::UnsupportedVersion(version));
+        }
+        if data.len() < FILE_LEN {
+            return Err(CoverageError::Truncated);
+        }
+        let mut coverage = Coverage::new();
+        coverage.flags.copy_from_slice(&data[6..FILE_LEN]);
+        Ok(coverage)
+    }
+}
+
+#[cfg(feature = "std")]
+mod report {
+    use crate::coverage::{Coverage, EXECUTED, READ, WRITTEN};
+    use crate::disasm::{Disassembly, Syntax};
+    use crate::memory::{MEM_SIZE, PROGRAM_LOAD_OFFSET};
+    use std::fmt::{self, Write};
+
+    // data bytes per listing line
+    const BYTES_PER_LINE: usize = 8;
+
+    // byte counts for a rom, code being what the disassembler reaches
+    #[derive(Debug, Copy, Clone, Eq, PartialEq)]
+    pub struct Summary {
+        pub code: usize,
+        pub code_executed: usize,
+        pub data: usize,
+        pub data_read: usize,
+        //anywhere in memory
+        pub written: usize,
+        pub written_in_rom: usize,
+    }
+
+    impl Summary {
+        // executed share of the code, 100 without code
+        pub fn percent(&self) -> f64 {
+            percent(self.code_executed, self.code)
+        }
+    }
+
+    fn percent(part: usize, total: usize) -> f64 {
+        if total == 0 {
+            100.0
+        } else {
+            part as f64 * 100.0 / total as f64
+        }
+    }
+
+    impl fmt::Display for Summary {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            writeln!(f, "code: {:.1}% ({} of {} bytes executed)", self.percent(), self.code_executed, self.code)?;
+            writeln!(f, "data: {:.1}% ({} of {} bytes read)", percent(self.data_read, self.data), self.data_read, self.data)?;
+            write!(f, "written: {} bytes, {} in the rom", self.written, self.written_in_rom)
+        }
+    }
+
+    fn marker(flags: u8) -> String {
+        [(EXECUTED, 'X'), (READ, 'R'), (WRITTEN, 'W')]
+            .iter()
+            .map(|&(flag, mark)| if flags & flag != 0 { mark } else { '-' })
+            .collect()
+    }
+
+    impl Coverage {
+        pub fn summary(&self, rom: &[u8]) -> Summary {
+            let disassembly = Disassembly::new(rom);
+            let (start, end) = (PROGRAM_LOAD_OFFSET, disassembly.rom_end());
+            let mut code = vec![false; MEM_SIZE];
+            for &addr in disassembly.code.iter() {
+                let addr = addr as usize;
+                code[addr.min(end)..(addr + 2).min(end)].fill(true);
+            }
+            let count = |is_code: bool, flag: u8| (start..end).filter(|&addr| code[addr] == is_code && self.flags(addr) & flag != 0).count();
+            let code_bytes = (start..end).filter(|&addr| code[addr]).count();
+            Summary {
+                code: code_bytes,
+                code_executed: count(true, EXECUTED),
+                data: end - start - code_bytes,
+                data_read: count(false, READ),
+                written: self.count(0, MEM_SIZE, WRITTEN),
+                written_in_rom: self.count(start, end, WRITTEN),
+            }
+        }
+
+        // the disassembly of the rom with the flags of every line
+        pub fn listing(&self, rom: &[u8]) -> String {
+            let disassembly = Disassembly::new(rom);
+            let end = disassembly.rom_end();
+            let mut out = String::new();
+            let mut addr = PROGRAM_LOAD_OFFSET;
+            while addr < end {
+                if let Some(label) = disassembly.labels.get(&(addr as u16)) {
+                    writeln!(out, "{:>10}{}:", "", label).unwrap();
+                }
+                if disassembly.code.contains(&(addr as u16)) {
+                    let opcode = disassembly.fetch(addr as u16).unwrap();
+                    let flags = self.flags(addr) | self.flags(addr + 1);
+                    let text = disassembly.format_instruction(opcode, Syntax::Cowgod);
+                    writeln!(out, "{}  0x{:03X}  {}", marker(flags), addr, text).unwrap();
+                    addr += 2;
+                    continue;
+                }
+                //data runs while the flags stay the same
+                let (first, flags) = (addr, self.flags(addr));
+                let mut bytes = vec![];
+                while addr < end && bytes.len() < BYTES_PER_LINE && self.flags(addr) == flags {
+                    bytes.push(format!("0x{:02X}", rom[addr - PROGRAM_LOAD_OFFSET]));
+                    addr += 1;
+                    if disassembly.code.contains(&(addr as u16)) || disassembly.labels.contains_key(&(addr as u16)) {
+                        break;
+                    }
+                }
+                writeln!(out, "{}  0x{:03X}  db {}", marker(flags), first, bytes.join(", ")).unwrap();
+            }
+            out
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+pub use report::Summary;
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use crate::asm::assemble;
+    use crate::coverage::{Coverage, CoverageError, EXECUTED, READ, WRITTEN};
+    use crate::cpu::CPU;
+    use crate::memory::Memory;
+
+    fn run(keys: u16) -> (Vec<u8>, Coverage) {
+        let rom = assemble(
+            "
+            main:
+                LD I, sprite
+                SKNP V0
+                DRW V0, V0, 2
+                LD I, 0x300
+                LD B, V1
+            halt:
+                JP halt
+            sprite:
+                db 0x60, 0x90, 0x60
+            ",
+        )
+        .unwrap();
+        let mut mem = Memory::new();
+        mem.load_program(&rom);
+        let mut cpu = CPU::new(mem);
+        cpu.keys = keys;
+        let mut coverage = Coverage::new();
+        coverage.run_frame(&mut cpu, 10);
+        (rom, coverage)
+    }
+
+    #[test]
+    fn test_collect_and_merge() {
+        let (rom, released) = run(0);
+        assert_eq!(EXECUTED, released.flags(0x200));
+        assert_eq!(0, released.flags(0x204));
+        assert_eq!(WRITTEN, released.flags(0x302));
+        assert_eq!(0, released.flags(0x20C));
+        assert_eq!(10, released.summary(&rom).code_executed);
+        assert_eq!(83.33, (released.summary(&rom).percent() * 100.0).round() / 100.0);
+
+        let (_, pressed) = run(1);
+        assert_eq!(READ, pressed.flags(0x20C));
+        let mut merged = released.clone();
+        merged.merge(&pressed);
+        assert_eq!(EXECUTED, merged.flags(0x204));
+        assert_eq!(100.0, merged.summary(&rom).percent());
+        let expected = "\
+code: 100.0% (12 of 12 bytes executed)
+data: 66.7% (2 of 3 bytes read)
+written: 3 bytes, 0 in the rom";
+        assert_eq!(expected, merged.summary(&rom).to_string());
+
+        assert_eq!(Ok(merged.clone()), Coverage::from_bytes(&merged.to_bytes()));
+        assert_eq!(Err(CoverageError::BadMagic), Coverage::from_bytes(b"C8TR\x01\x00"));
+        assert_eq!(Err(CoverageError::Truncated), Coverage::from_bytes(&merged.to_bytes()[..100]));
+        let mut newer = merged.to_bytes();
+        newer[4] = 0xFF;
+        assert_eq!(Err(CoverageError::UnsupportedVersion(0xFF)), Coverage::from_bytes(&newer));
+    }
+
+    #[test]
+    fn test_listing() {
+        let (rom, coverage) = run(0);
+        let expected = "          main:
+X--  0x200  LD I, data_20c
+X--  0x202  SKNP V0
+---  0x204  DRW V0, V0, 2
+X--  0x206  LD I, 0x300
+X--  0x208  LD B, V1
+          label_20a:
+X--  0x20A  JP label_20a
+          data_20c:
+---  0x20C  db 0x60, 0x90, 0x60
+";
+        assert_eq!(expected, coverage.listing(&rom));
+    }
+}
@@ -1,13 +1,73 @@
-use crate::memory::{Memory, PROGRAM_LOAD_OFFSET};
+use crate::memory::{Memory, FONT_OFFSET, MEM_SIZE, PROGRAM_LOAD_OFFSET};
 use crate::instructions::Instruction;
-use std::num::Wrapping;
-use rand::{thread_rng, Rng};
+use crate::display::Display;
+use crate::rng::Rng;
+use core::fmt;
+use core::num::Wrapping;
 
-#[derive(Debug)]
+// instructions executed per 60hz timer tick
+pub const CYCLES_PER_FRAME: usize = 10;
+
+// behaviour that differs between interpreters, the defaults match what
+// this emulator always did
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+pub struct Quirks {
+    //SHR and SHL shift VY into VX instead of shifting VX in place
+    pub shift_uses_vy: bool,
+    //LD [I], VX and LD VX, [I] leave I pointing past the last register
+    pub load_store_increments_i: bool,
+    //JP V0, addr jumps to XNN + VX
+    pub jump_uses_vx: bool,
+    //OR, AND and XOR clear VF
+    pub logic_resets_vf: bool,
+}
+
+impl Quirks {
+    pub fn to_bits(&self) -> u8 {
+        self.shift_uses_vy as u8
+            | (self.load_store_increments_i as u8) << 1
+            | (self.jump_uses_vx as u8) << 2
+            | (self.logic_resets_vf as u8) << 3
+    }
+
+    pub fn from_bits(bits: u8) -> Quirks {
+        Quirks {
+            shift_uses_vy: bits & 0x1 != 0,
+            load_store_increments_i: bits & 0x2 != 0,
+            jump_uses_vx: bits & 0x4 != 0,
+            logic_resets_vf: bits & 0x8 != 0,
+        }
+    }
+}
+
+// guest errors that would make step panic
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Fault {
+    //pc does not point at a whole instruction
+    PcOutOfMemory,
+    //RET with an empty stack
+    StackUnderflow,
+    //CALL with all stack slots in use
+    StackOverflow,
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Fault::PcOutOfMemory => write!(f, "pc out of memory"),
+            Fault::StackUnderflow => write!(f, "return with an empty stack"),
+            Fault::StackOverflow => write!(f, "call with a full stack"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub struct Registers {
     prg_regs: [u8; 16],
     //timer
     dt: u8,
+    //sound timer
+    st: u8,
     //index
     i: u16,
     //program counter
@@ -16,28 +76,57 @@ pub struct Registers {
     sp: usize,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub struct CPU {
     pub memory: Memory,
+    pub display: Display,
     pub stack: [u16; 16],
     pub registers: Registers,
+    //bit n is set while key n is held down
+    pub keys: u16,
+    pub rng: Rng,
+    pub quirks: Quirks,
 }
 
 impl CPU {
     pub fn new(memory: Memory) -> CPU {
         CPU {
             memory,
+            display: Display::new(),
             stack: [0x0; 16],
             registers: Registers {
                 prg_regs: [0x0; 16],
                 dt: 0x0,
+                st: 0x0,
                 i: 0x0,
                 pc: PROGRAM_LOAD_OFFSET as u16,
                 sp: 0x0,
             },
+            keys: 0x0,
+            #[cfg(feature = "std")]
+            rng: Rng::from_entropy(),
+            #[cfg(not(feature = "std"))]
+            rng: Rng::new(0),
+            quirks: Quirks::default(),
         }
     }
 
+    pub fn seed_rng(&mut self, seed: u64) {
+        self.rng.seed(seed);
+    }
+
+    pub fn press_key(&mut self, key: u8) {
+        self.keys |= 1 << (key & 0xF);
+    }
+
+    pub fn release_key(&mut self, key: u8) {
+        self.keys &= !(1 << (key & 0xF));
+    }
+
+    pub fn is_key_pressed(&self, key: u8) -> bool {
+        self.keys & (1 << (key & 0xF)) != 0
+    }
+
     pub fn fetch_current_instruction(&self) -> u32 {
         (self.memory.memory[self.registers.pc as usize] as u32) << 8
             | (self.memory.memory[self.registers.pc as usize + 1] as u32)
@@ -47,6 +136,91 @@ impl CPU {
         self.stack[self.registers.sp - 1]
     }
 
+    pub fn push_stack(&mut self, value: u16) {
+        self.stack[self.registers.sp] = value;
+        self.registers.sp += 1;
+    }
+
+    pub fn pop_stack(&mut self) -> u16 {
+        self.registers.sp -= 1;
+        self.stack[self.registers.sp]
+    }
+
+    pub fn get_pc(&self) -> u16 {
+        self.registers.pc
+    }
+
+    pub fn set_pc(&mut self, pc: u16) {
+        self.registers.pc = pc;
+    }
+
+    pub fn get_i(&self) -> u16 {
+        self.registers.i
+    }
+
+    pub fn set_i(&mut self, i: u16) {
+        self.registers.i = i;
+    }
+
+    pub fn get_sp(&self) -> usize {
+        self.registers.sp
+    }
+
+    pub fn set_sp(&mut self, sp: usize) {
+        self.registers.sp = sp;
+    }
+
+    pub fn get_dt(&self) -> u8 {
+        self.registers.dt
+    }
+
+    pub fn set_dt(&mut self, dt: u8) {
+        self.registers.dt = dt;
+    }
+
+    pub fn get_st(&self) -> u8 {
+        self.registers.st
+    }
+
+    pub fn set_st(&mut self, st: u8) {
+        self.registers.st = st;
+    }
+
+    // called at 60hz
+    pub fn tick_timers(&mut self) {
+        self.registers.dt = self.registers.dt.saturating_sub(1);
+        self.registers.st = self.registers.st.saturating_sub(1);
+    }
+
+    // runs the given number of instructions followed by a timer tick
+    pub fn run_frame(&mut self, cycles: usize) {
+        self.run_frame_with(cycles, CPU::step);
+    }
+
+    // run_frame with every instruction executed by `step`, which lets the
+    // tracer, profiler and coverage look at the machine around it
+    pub fn run_frame_with<F: FnMut(&mut CPU)>(&mut self, cycles: usize, mut step: F) {
+        for _ in 0..cycles {
+            step(self);
+        }
+        self.tick_timers();
+    }
+
+    // `cycles` instructions split into frames of CYCLES_PER_FRAME
+    pub fn run_with<F: FnMut(&mut CPU)>(&mut self, cycles: usize, mut step: F) {
+        for frame in (0..cycles).step_by(CYCLES_PER_FRAME) {
+            self.run_frame_with(CYCLES_PER_FRAME.min(cycles - frame), &mut step);
+        }
+    }
+
+    // draws `rows` bytes starting at I, VF is set on collision
+    pub fn draw_sprite(&mut self, x: u8, y: u8, rows: usize) {
+        let start = (self.registers.i as usize).min(MEM_SIZE);
+        let end = (start + rows).min(MEM_SIZE);
+        let collision = self.display.draw(x, y, &self.memory.memory[start..end]);
+        self.registers.prg_regs[0xF] = collision as u8;
+    }
+
     pub fn write_register(&mut self, register: u32, value: u8) {
         self.registers.prg_regs[register as usize] = value;
     }
@@ -55,6 +229,48 @@ impl CPU {
         self.registers.prg_regs[register as usize]
     }
 
+    // describes the first piece of machine state that differs from `other`
+    #[cfg(feature = "std")]
+    pub fn diff(&self, other: &CPU) -> Option<String> {
+        let (a, b) = (&self.registers, &other.registers);
+        for reg in 0..16 {
+            if a.prg_regs[reg] != b.prg_regs[reg] {
+                return Some(format!("V{:X}: 0x{:02X} != 0x{:02X}", reg, a.prg_regs[reg], b.prg_regs[reg]));
+            }
+        }
+        if a.i != b.i {
+            return Some(format!("I: 0x{:03X} != 0x{:03X}", a.i, b.i));
+        }
+        if a.pc != b.pc {
+            return Some(format!("PC: 0x{:03X} != 0x{:03X}", a.pc, b.pc));
+        }
+        if a.sp != b.sp {
+            return Some(format!("SP: {} != {}", a.sp, b.sp));
+        }
+        if a.dt != b.dt {
+            return Some(format!("DT: {} != {}", a.dt, b.dt));
+        }
+        if a.st != b.st {
+            return Some(format!("ST: {} != {}", a.st, b.st));
+        }
+        for (slot, (x, y)) in self.stack.iter().zip(other.stack.iter()).enumerate() {
+            if x != y {
+                return Some(format!("stack[{}]: 0x{:03X} != 0x{:03X}", slot, x, y));
+            }
+        }
+        for (addr, (x, y)) in self.memory.memory.iter().zip(other.memory.memory.iter()).enumerate() {
+            if x != y {
+                return Some(format!("memory[0x{:03X}]: 0x{:02X} != 0x{:02X}", addr, x, y));
+            }
+        }
+        for (pixel, (x, y)) in self.display.pixels.iter().zip(other.display.pixels.iter()).enumerate() {
+            if x != y {
+                return Some(format!("pixel[{}]: {} != {}", pixel, x, y));
+            }
+        }
+        None
+    }
+
     pub fn get_x_reg(value: u32) -> u32 {
         value >> 8 & 0x000F
     }
@@ -63,27 +279,49 @@ impl CPU {
         value >> 4 & 0x000F
     }
 
+    // the register SHR and SHL read from
+    pub fn shift_source(&self, value: u32) -> u32 {
+        if self.quirks.shift_uses_vy {
+            CPU::get_y_reg(value)
+        } else {
+            CPU::get_x_reg(value)
+        }
+    }
+
+    // the fault the next step would run into
+    pub fn fault(&self) -> Option<Fault> {
+        if self.registers.pc as usize + 1 >= MEM_SIZE {
+            return Some(Fault::PcOutOfMemory);
+        }
+        match Instruction::decode(self.fetch_current_instruction()).0 {
+            Instruction::RET if self.registers.sp == 0 => Some(Fault::StackUnderflow),
+            Instruction::CALL if self.registers.sp == self.stack.len() => Some(Fault::StackOverflow),
+            _ => None,
+        }
+    }
+
     pub fn step(&mut self) {
         let (instr, value) = Instruction::decode(self.fetch_current_instruction());
+        self.execute(instr, value);
+    }
+
+    pub fn execute(&mut self, instr: Instruction, value: u32) {
         self.registers.pc += 2;
-        //println!("{:?} 0x{:X}", instr, value);
         match instr {
             Instruction::SYS => {
                 panic!("machine code execution not supported");
             }
             Instruction::CLS => {
-                println!("Clear screen");
+                self.display.clear();
             }
             Instruction::RET => {
-                self.registers.sp -= 1;
-                self.registers.pc = self.stack[self.registers.sp];
+                self.registers.pc = self.pop_stack();
             }
             Instruction::JP => {
                 self.registers.pc = (value & 0x0FFF) as u16;
             }
             Instruction::CALL => {
-                self.stack[self.registers.sp] = self.registers.pc;
-                self.registers.sp += 1;
+                self.push_stack(self.registers.pc);
                 self.registers.pc = (value & 0x0FFF) as u16;
             }
             Instruction::SE_VX_BT => {
@@ -111,7 +349,7 @@ impl CPU {
             Instruction::ADD_VX_BT => {
                 let register = CPU::get_x_reg(value);
                 let to_add = (value & 0x00FF) as u8;
-                self.write_register(register, self.read_register(register) as u8 + to_add);
+                self.write_register(register, self.read_register(register).wrapping_add(to_add));
             }
             Instruction::LD_VX_VY => {
                 let content_y = self.read_register(CPU::get_y_reg(value));
@@ -122,18 +360,27 @@ impl CPU {
                 let content_x = self.read_register(register_x);
                 let content_y = self.read_register(CPU::get_y_reg(value));
                 self.write_register(register_x, content_x | content_y);
+                if self.quirks.logic_resets_vf {
+                    self.registers.prg_regs[0xF] = 0;
+                }
             }
             Instruction::AND_VX_VY => {
                 let register_x = CPU::get_x_reg(value);
                 let content_x = self.read_register(register_x);
                 let content_y = self.read_register(CPU::get_y_reg(value));
                 self.write_register(register_x, content_x & content_y);
+                if self.quirks.logic_resets_vf {
+                    self.registers.prg_regs[0xF] = 0;
+                }
             }
             Instruction::XOR_VX_VY => {
                 let register_x = CPU::get_x_reg(value);
                 let content_x = self.read_register(register_x);
                 let content_y = self.read_register(CPU::get_y_reg(value));
                 self.write_register(register_x, content_x ^ content_y);
+                if self.quirks.logic_resets_vf {
+                    self.registers.prg_regs[0xF] = 0;
+                }
             }
             Instruction::ADD_VX_VY => {
                 let register_x = CPU::get_x_reg(value);
@@ -161,7 +408,7 @@ impl CPU {
             }
             Instruction::SHR_VX_VY => {
                 let register_x = CPU::get_x_reg(value);
-                let content_x = self.read_register(register_x);
+                let content_x = self.read_register(self.shift_source(value));
                 self.registers.prg_regs[0xF] = content_x % 2;
                 self.write_register(register_x, content_x / 2);
             }
@@ -179,7 +426,7 @@ impl CPU {
             }
             Instruction::SHL_VX_VY => {
                 let register_x = CPU::get_x_reg(value);
-                let content_x = self.read_register(register_x);
+                let content_x = self.read_register(self.shift_source(value));
                 if content_x >= 0x80 { //msb = 1
                     self.registers.prg_regs[0xF] = 1;
                 } else {
@@ -202,14 +449,83 @@ impl CPU {
             }
             Instruction::JP_V0_ADDR => {
                 let addr = Wrapping((value & 0x0FFF) as u16);
-                self.registers.pc = (addr + Wrapping(self.registers.prg_regs[0] as u16)).0;
+                let offset_reg = if self.quirks.jump_uses_vx { CPU::get_x_reg(value) as usize } else { 0 };
+                self.registers.pc = (addr + Wrapping(self.registers.prg_regs[offset_reg] as u16)).0;
             }
             Instruction::RND_VX_BT => {
                 let reg_x = CPU::get_x_reg(value);
-                let rnd = thread_rng().gen_range(0x00, 0x100) as u8;
+                let rnd = self.rng.next_u8();
                 self.write_register(reg_x, rnd & ((value & 0x00FF) as u8))
             }
-            _ => {
+            Instruction::DRW_VX_VY_NIB => {
+                let x = self.read_register(CPU::get_x_reg(value));
+                let y = self.read_register(CPU::get_y_reg(value));
+                self.draw_sprite(x, y, (value & 0x000F) as usize);
+            }
+            Instruction::LD_VX_DT => {
+                self.write_register(CPU::get_x_reg(value), self.registers.dt);
+            }
+            Instruction::LD_DT_VX => {
+                self.registers.dt = self.read_register(CPU::get_x_reg(value));
+            }
+            Instruction::LD_ST_VX => {
+                self.registers.st = self.read_register(CPU::get_x_reg(value));
+            }
+            Instruction::SKP_VX => {
+                if self.is_key_pressed(self.read_register(CPU::get_x_reg(value))) {
+                    self.registers.pc += 2;
+                }
+            }
+            Instruction::SKNP_VX => {
+                if !self.is_key_pressed(self.read_register(CPU::get_x_reg(value))) {
+                    self.registers.pc += 2;
+                }
+            }
+            Instruction::LD_VX_K => {
+                if self.keys == 0 {
+                    //block by executing this instruction again
+                    self.registers.pc -= 2;
+                } else {
+                    self.write_register(CPU::get_x_reg(value), self.keys.trailing_zeros() as u8);
+                }
+            }
+            Instruction::ADD_I_VX => {
+                let content_x = self.read_register(CPU::get_x_reg(value)) as u16;
+                self.registers.i = self.registers.i.wrapping_add(content_x);
+            }
+            Instruction::LD_F_VX => {
+                let digit = (self.read_register(CPU::get_x_reg(value)) & 0x0F) as usize;
+                self.registers.i = (FONT_OFFSET + digit * 5) as u16;
+            }
+            Instruction::LD_B_VX => {
+                let content_x = self.read_register(CPU::get_x_reg(value));
+                let i = self.registers.i as usize;
+                self.memory.memory[i % MEM_SIZE] = content_x / 100;
+                self.memory.memory[(i + 1) % MEM_SIZE] = content_x / 10 % 10;
+                self.memory.memory[(i + 2) % MEM_SIZE] = content_x % 10;
+            }
+            Instruction::LD_I_VX => {
+                let i = self.registers.i as usize;
+                let last = CPU::get_x_reg(value) as usize;
+                for reg in 0..=last {
+                    self.memory.memory[(i + reg) % MEM_SIZE] = self.registers.prg_regs[reg];
+                }
+                if self.quirks.load_store_increments_i {
+                    self.registers.i = self.registers.i.wrapping_add(last as u16 + 1);
+                }
+            }
+            Instruction::LD_VX_I => {
+                let i = self.registers.i as usize;
+                let last = CPU::get_x_reg(value) as usize;
+                for reg in 0..=last {
+                    self.registers.prg_regs[reg] = self.memory.memory[(i + reg) % MEM_SIZE];
+                }
+                if self.quirks.load_store_increments_i {
+                    self.registers.i = self.registers.i.wrapping_add(last as u16 + 1);
+                }
+            }
+            Instruction::INVALID => {
+                #[cfg(feature = "std")]
                 println!("not implemented");
             }
         }
@@ -459,3 +775,162 @@ mod tests {
         assert_eq!(0x01, cpu.registers.prg_regs[0xF]);
     }
 }
+
+// these need the assembler and the expression registers, so they only
+// build with std
+#[cfg(all(test, feature = "std"))]
+mod asm_tests {
+    use crate::cpu::Quirks;
+    use crate::expr::Register::{Pc, I, V};
+    use crate::testing::Machine;
+
+    #[test]
+    fn test_load_add_instr() {
+        Machine::asm("
+            LD VA, 0xFF
+            LD VA, 0xAF
+            ADD VA, 0x01
+            LD VB, VA
+            LD I, 0x3AB
+            LD V0, 0x10
+            JP V0, 0x1F0
+        ")
+        .steps(1).expect(V(0xA), 0xFF)
+        .steps(1).expect(V(0xA), 0xAF)
+        .steps(1).expect(V(0xA), 0xB0).expect(V(0xB), 0x00)
+        .steps(1).expect(V(0xB), 0xB0)
+        .steps(1).expect(I, 0x3AB)
+        .steps(1).expect(V(0x0), 0x10)
+        .steps(1).expect(Pc, 0x200);
+    }
+
+    #[test]
+    fn test_math() {
+        //xor, add, sub
+        Machine::asm("
+            OR VA, VB
+            LD VA, 0xF0
+            AND VA, VB
+            XOR VA, VC
+            ADD VD, VE
+            ADD VD, VE
+            LD VD, 0xFF
+            SUB VD, VE
+            SUB V0, V1
+        ")
+        .set(V(0x0), 0x01)
+        .set(V(0x1), 0x03)
+        .set(V(0xA), 0x02)
+        .set(V(0xB), 0xA1)
+        .set(V(0xC), 0x02)
+        .set(V(0xD), 0xFE)
+        .set(V(0xE), 0x01)
+        .steps(1).expect(V(0xA), 0xA3)
+        .steps(2).expect(V(0xA), 0xA0)
+        .steps(1).expect(V(0xA), 0xA2)
+        .steps(1).expect(V(0xD), 0xFF).expect(V(0xF), 0x0)
+        .steps(1).expect(V(0xD), 0x00).expect(V(0xF), 0x1)
+        .steps(2).expect(V(0xD), 0xFE).expect(V(0xF), 0x01)
+        .steps(1).expect(V(0x0), 0xFE).expect(V(0xF), 0x00);
+    }
+
+    #[test]
+    fn test_additional_math() {
+        //xor, add, sub
+        Machine::asm("
+            SHR VA, VB
+            SHR VA, VB
+            SUBN VA, VB
+            SUBN VB, VA
+            SHL VC, VA
+            SHL VD, VA
+        ")
+        .set(V(0xA), 0x0A)
+        .set(V(0xB), 0x01)
+        .set(V(0xC), 0x05)
+        .set(V(0xD), 0xA0)
+        .steps(1).expect(V(0xA), 0x05).expect(V(0xF), 0x00)
+        .steps(1).expect(V(0xA), 0x02).expect(V(0xF), 0x01)
+        .steps(1).expect(V(0xA), 0xFF).expect(V(0xF), 0x00)
+        .steps(1).expect(V(0xB), 0xFE).expect(V(0xF), 0x01)
+        .steps(1).expect(V(0xC), 0x0A).expect(V(0xF), 0x00)
+        .steps(1).expect(V(0xD), 0x40).expect(V(0xF), 0x01);
+    }
+
+    #[test]
+    fn test_draw_and_timers() {
+        Machine::asm("
+            LD I, sprite
+            DRW V0, V1, 1
+            DRW V0, V1, 1    ; erases the sprite again
+            LD DT, VA
+            LD VB, DT
+            CLS
+        sprite:
+            db 0xC0
+        ")
+        .set(V(0x0), 0x3F)
+        .set(V(0x1), 0x02)
+        .set(V(0xA), 0x05)
+        //clipped at the right edge
+        .steps(2).expect_screen(63, 2, &["#."]).expect(V(0xF), 0x00)
+        .steps(1).expect_screen(63, 2, &[".."]).expect(V(0xF), 0x01)
+        .steps(1)
+        .tick_timers()
+        .steps(1).expect(V(0xB), 0x04)
+        .with(|cpu| cpu.display.pixels[0] = 1)
+        .steps(1).expect_screen(0, 0, &["."]);
+    }
+
+    #[test]
+    fn test_keys_and_memory() {
+        Machine::asm("
+            LD V0, K         ; blocks until a key is pressed
+            SKP V0
+            db 0x00, 0x00    ; skipped
+            SKNP V0
+            LD I, 0x300
+            LD B, VA
+            LD [I], V2
+            ADD I, V0
+            LD V1, [I]
+            LD F, VA
+        ")
+        .set(V(0xA), 0x9C)
+        .steps(1).expect(Pc, 0x200)
+        .press_key(0x7)
+        .steps(1).expect(V(0x0), 0x07)
+        .steps(1).expect(Pc, 0x206)
+        .steps(1).expect(Pc, 0x208)
+        .steps(2).expect_memory(0x300, &[1, 5, 6])
+        .steps(1).expect_memory(0x300, &[0x07, 0x00, 0x00])
+        .steps(1).expect(I, 0x307)
+        .poke(0x307, &[0x11, 0x22])
+        .steps(1).expect(V(0x0), 0x11).expect(V(0x1), 0x22)
+        .steps(1).expect(I, 0x3C).expect_memory(0x3C, &[0xF0, 0x80, 0x80, 0x80, 0xF0]);
+    }
+
+    #[test]
+    fn test_quirks() {
+        Machine::asm("
+            SHR VA, VB
+            OR VA, VB
+            LD I, 0x300
+            LD [I], V1
+            JP V2, 0x210
+        ")
+        .quirks(Quirks {
+            shift_uses_vy: true,
+            load_store_increments_i: true,
+            jump_uses_vx: true,
+            logic_resets_vf: true,
+        })
+        .set(V(0x2), 0x10)
+        .set(V(0xA), 0x40)
+        .set(V(0xB), 0x03)
+        .steps(1).expect(V(0xA), 0x01).expect(V(0xF), 0x01)
+        .steps(1).expect(V(0xA), 0x03).expect(V(0xF), 0x00)
+        .steps(2).expect(I, 0x302)
+        .steps(1).expect(Pc, 0x220);
+    }
+}
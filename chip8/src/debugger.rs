@@ -0,0 +1,971 @@
+use crate::access::Access;
+use crate::cpu::{Fault, CPU, CYCLES_PER_FRAME};
+use crate::expr::{Expr, Register};
+use crate::instructions::Instruction;
+use crate::memory::MEM_SIZE;
+use crate::octo::SourceMap;
+use crate::savestate::{self, SaveStateError};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+// upper bound for continue and friends, the rom cannot be interrupted
+pub const DEFAULT_STEP_LIMIT: u64 = 10_000_000;
+// instructions between two snapshots for reverse execution
+pub const DEFAULT_SNAPSHOT_INTERVAL: u64 = 1000;
+// the oldest snapshots are dropped beyond this, limiting how far back
+// reverse execution can go
+pub const DEFAULT_MAX_SNAPSHOTS: usize = 1000;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum StopReason {
+    Step,
+    Breakpoint(u16),
+    //a breakpoint expression became true
+    Condition(usize),
+    //the instruction at pc triggered the watchpoint
+    Watchpoint { index: usize, pc: u16 },
+    //step over or step out reached its target
+    Returned,
+    //the program counter did not move, either a jump to itself or a
+    //LD VX, K waiting for a key
+    Halted(u16),
+    StepLimit,
+    //the instruction at pc cannot be executed, nothing was changed
+    Fault { fault: Fault, pc: u16 },
+    //reverse execution ran out of snapshots
+    StartOfHistory,
+    //a snapshot reverse execution needed could not be loaded, the machine
+    //is left where reverse execution started
+    BadSnapshot(SaveStateError),
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StopReason::Step => write!(f, "stepped"),
+            StopReason::Breakpoint(addr) => write!(f, "breakpoint at 0x{:03X}", addr),
+            StopReason::Condition(index) => write!(f, "condition #{} became true", index),
+            StopReason::Watchpoint { index, pc } => write!(f, "watchpoint {} triggered at 0x{:03X}", index, pc),
+            StopReason::Returned => write!(f, "returned"),
+            StopReason::Halted(addr) => write!(f, "halted at 0x{:03X}", addr),
+            StopReason::StepLimit => write!(f, "step limit reached"),
+            StopReason::Fault { fault, pc } => write!(f, "{} at 0x{:03X}", fault, pc),
+            StopReason::StartOfHistory => write!(f, "reached the start of the recorded history"),
+            StopReason::BadSnapshot(error) => write!(f, "cannot restore snapshot: {}", error),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    Access,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum WatchTarget {
+    //addresses in [start, end)
+    Memory(usize, usize),
+    //one of v0-vf or i
+    Register(Register),
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Watchpoint {
+    pub target: WatchTarget,
+    pub kind: WatchKind,
+}
+
+impl Watchpoint {
+    // `target` is a register, an address or an exclusive start..end range
+    pub fn parse(target: &str, kind: WatchKind) -> Result<Watchpoint, String> {
+        let target = match Register::parse(target) {
+            Some(register @ Register::V(_)) | Some(register @ Register::I) => WatchTarget::Register(register),
+            Some(register) => return Err(format!("cannot watch {}", register)),
+            None => {
+                let (start, end) = match target.find("..") {
+                    Some(split) => (&target[..split], Some(&target[split + 2..])),
+                    None => (target, None),
+                };
+                let start = parse_number(start).ok_or_else(|| format!("invalid address {}", start))?;
+                let end = match end {
+                    Some(end) => parse_number(end).ok_or_else(|| format!("invalid address {}", end))?,
+                    None => start + 1,
+                };
+                if start >= end || end > MEM_SIZE {
+                    return Err(format!("invalid range {}", target));
+                }
+                WatchTarget::Memory(start, end)
+            }
+        };
+        Ok(Watchpoint { target, kind })
+    }
+
+    pub fn triggered_by(&self, access: &Access) -> bool {
+        let (read, written) = match self.target {
+            WatchTarget::Memory(start, end) => (access.reads_memory(start, end), access.writes_memory(start, end)),
+            WatchTarget::Register(Register::V(reg)) => {
+                (access.reads_register(reg as u32), access.writes_register(reg as u32))
+            }
+            WatchTarget::Register(_) => (access.i_read, access.i_written),
+        };
+        match self.kind {
+            WatchKind::Read => read,
+            WatchKind::Write => written,
+            WatchKind::Access => read || written,
+        }
+    }
+}
+
+impl fmt::Display for Watchpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self.kind {
+            WatchKind::Read => "read",
+            WatchKind::Write => "write",
+            WatchKind::Access => "access",
+        };
+        match self.target {
+            WatchTarget::Memory(start, end) if end == start + 1 => write!(f, "{} 0x{:03X}", kind, start),
+            WatchTarget::Memory(start, end) => write!(f, "{} 0x{:03X}..0x{:03X}", kind, start, end),
+            WatchTarget::Register(register) => write!(f, "{} {}", kind, register),
+        }
+    }
+}
+
+pub struct Debugger {
+    pub cpu: CPU,
+    pub breakpoints: BTreeSet<u16>,
+    //breakpoints with a condition only stop while it holds
+    pub conditions: BTreeMap<u16, Expr>,
+    //stop on any instruction where one of these becomes true
+    pub break_conditions: Vec<Expr>,
+    pub watchpoints: Vec<Watchpoint>,
+    //instructions executed so far, timers tick every CYCLES_PER_FRAME
+    pub cycles: u64,
+    pub step_limit: u64,
+    pub snapshot_interval: u64,
+    pub max_snapshots: usize,
+    //set when debugging a compiled octo program
+    pub source_map: Option<SourceMap>,
+    //save states keyed by cycles. going back restores the closest one and
+    //re-executes, which is exact as long as the keypad does not change
+    snapshots: BTreeMap<u64, Vec<u8>>,
+}
+
+impl Debugger {
+    pub fn new(cpu: CPU) -> Debugger {
+        Debugger {
+            cpu,
+            breakpoints: BTreeSet::new(),
+            conditions: BTreeMap::new(),
+            break_conditions: vec![],
+            watchpoints: vec![],
+            cycles: 0,
+            step_limit: DEFAULT_STEP_LIMIT,
+            snapshot_interval: DEFAULT_SNAPSHOT_INTERVAL,
+            max_snapshots: DEFAULT_MAX_SNAPSHOTS,
+            source_map: None,
+            snapshots: BTreeMap::new(),
+        }
+    }
+
+    // executes one instruction and reports the first watchpoint it
+    // triggered, a faulting instruction is not executed
+    fn execute_one(&mut self) -> Option<StopReason> {
+        if let Some(fault) = self.cpu.fault() {
+            return Some(StopReason::Fault { fault, pc: self.cpu.get_pc() });
+        }
+        if self.cycles.is_multiple_of(self.snapshot_interval.max(1)) && !self.snapshots.contains_key(&self.cycles) {
+            self.snapshots.insert(self.cycles, savestate::save(&self.cpu));
+        }
+        while self.snapshots.len() > self.max_snapshots.max(1) {
+            let oldest = *self.snapshots.keys().next().unwrap();
+            self.snapshots.remove(&oldest);
+        }
+        let pc = self.cpu.get_pc();
+        let access = if self.watchpoints.is_empty() { Access::default() } else { Access::of(&self.cpu) };
+        self.cpu.step();
+        self.cycles += 1;
+        if self.cycles.is_multiple_of(CYCLES_PER_FRAME as u64) {
+            self.cpu.tick_timers();
+        }
+        self.watchpoints.iter()
+            .position(|watchpoint| watchpoint.triggered_by(&access))
+            .map(|index| StopReason::Watchpoint { index, pc })
+    }
+
+    pub fn step(&mut self) -> StopReason {
+        self.execute_one().unwrap_or(StopReason::Step)
+    }
+
+    fn breakpoint_hit(&self, pc: u16) -> bool {
+        self.breakpoints.contains(&pc) && self.conditions.get(&pc).is_none_or(|condition| condition.is_true(&self.cpu))
+    }
+
+    // runs until `done` holds after an instruction or a breakpoint is hit
+    fn run_until<F: Fn(&CPU) -> bool>(&mut self, done: F) -> StopReason {
+        let mut held: Vec<bool> = self.break_conditions.iter().map(|condition| condition.is_true(&self.cpu)).collect();
+        for executed in 0..self.step_limit {
+            let pc = self.cpu.get_pc();
+            if executed > 0 {
+                if self.breakpoint_hit(pc) {
+                    return StopReason::Breakpoint(pc);
+                }
+                for (index, condition) in self.break_conditions.iter().enumerate() {
+                    let holds = condition.is_true(&self.cpu);
+                    if holds && !held[index] {
+                        return StopReason::Condition(index);
+                    }
+                    held[index] = holds;
+                }
+            }
+            if let Some(reason) = self.execute_one() {
+                return reason;
+            }
+            if done(&self.cpu) {
+                return StopReason::Returned;
+            }
+            if self.cpu.get_pc() == pc {
+                return StopReason::Halted(pc);
+            }
+        }
+        StopReason::StepLimit
+    }
+
+    pub fn resume(&mut self) -> StopReason {
+        self.run_until(|_| false)
+    }
+
+    // false when there is no snapshot that old
+    fn restore_snapshot(&mut self, at_or_before: u64) -> Result<bool, SaveStateError> {
+        let (&cycles, state) = match self.snapshots.range(..=at_or_before).next_back() {
+            Some(snapshot) => snapshot,
+            None => return Ok(false),
+        };
+        self.cpu = savestate::load(state)?;
+        self.cycles = cycles;
+        Ok(true)
+    }
+
+    // goes to the state after `cycles` instructions, false when that is
+    // older than the oldest snapshot
+    fn replay_to(&mut self, cycles: u64) -> Result<bool, SaveStateError> {
+        if !self.restore_snapshot(cycles)? {
+            return Ok(false);
+        }
+        while self.cycles < cycles {
+            if let Some(StopReason::Fault { .. }) = self.execute_one() {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    // the state edits start a new history, snapshots after it are stale
+    fn rewrite_history(&mut self) {
+        self.snapshots.split_off(&self.cycles);
+        self.snapshots.insert(self.cycles, savestate::save(&self.cpu));
+    }
+
+    // undoes the last instruction
+    pub fn reverse_step(&mut self) -> StopReason {
+        if self.cycles == 0 {
+            return StopReason::StartOfHistory;
+        }
+        match self.replay_to(self.cycles - 1) {
+            Ok(true) => StopReason::Step,
+            Ok(false) => StopReason::StartOfHistory,
+            Err(error) => StopReason::BadSnapshot(error),
+        }
+    }
+
+    // replays the snapshot at `start` up to `end` and finds the last place
+    // before `limit` where going forward would have stopped
+    fn last_stop_between(&mut self, start: u64, end: u64, limit: u64) -> Result<Option<(u64, StopReason)>, SaveStateError> {
+        self.restore_snapshot(start)?;
+        let mut held: Vec<bool> = self.break_conditions.iter().map(|condition| condition.is_true(&self.cpu)).collect();
+        let mut last = None;
+        while self.cycles < end {
+            let watchpoint = self.execute_one();
+            if self.cycles >= limit {
+                break;
+            }
+            if let Some(StopReason::Fault { .. }) = watchpoint {
+                break;
+            }
+            if let Some(reason) = watchpoint {
+                last = Some((self.cycles, reason));
+            }
+            let pc = self.cpu.get_pc();
+            if self.breakpoint_hit(pc) {
+                last = Some((self.cycles, StopReason::Breakpoint(pc)));
+            }
+            for (index, condition) in self.break_conditions.iter().enumerate() {
+                let holds = condition.is_true(&self.cpu);
+                if holds && !held[index] {
+                    last = Some((self.cycles, StopReason::Condition(index)));
+                }
+                held[index] = holds;
+            }
+        }
+        Ok(last)
+    }
+
+    // runs backwards to the previous breakpoint, condition or watchpoint
+    // hit, one snapshot interval at a time
+    pub fn reverse_continue(&mut self) -> StopReason {
+        let (cpu, cycles) = (self.cpu.clone(), self.cycles);
+        match self.find_previous_stop() {
+            Ok(reason) => reason,
+            Err(error) => {
+                self.cpu = cpu;
+                self.cycles = cycles;
+                StopReason::BadSnapshot(error)
+            }
+        }
+    }
+
+    fn find_previous_stop(&mut self) -> Result<StopReason, SaveStateError> {
+        let limit = self.cycles;
+        let mut end = limit;
+        while let Some((&start, _)) = self.snapshots.range(..end).next_back() {
+            if let Some((cycles, reason)) = self.last_stop_between(start, end, limit)? {
+                self.replay_to(cycles)?;
+                return Ok(reason);
+            }
+            end = start;
+        }
+        if let Some(&oldest) = self.snapshots.keys().next() {
+            self.replay_to(oldest.min(limit))?;
+        }
+        Ok(StopReason::StartOfHistory)
+    }
+
+    // steps over CALLs, any other instruction is a single step
+    pub fn step_over(&mut self) -> StopReason {
+        if self.cpu.fault().is_some() {
+            return self.step();
+        }
+        let (instr, _) = Instruction::decode(self.cpu.fetch_current_instruction());
+        if instr != Instruction::CALL {
+            return self.step();
+        }
+        let return_to = self.cpu.get_pc() + 2;
+        let sp = self.cpu.get_sp();
+        self.run_until(|cpu| cpu.get_pc() == return_to && cpu.get_sp() == sp)
+    }
+
+    // runs until the current subroutine returned
+    pub fn step_out(&mut self) -> Result<StopReason, String> {
+        let sp = self.cpu.get_sp();
+        if sp == 0 {
+            return Err("not inside a subroutine".to_string());
+        }
+        Ok(self.run_until(|cpu| cpu.get_sp() < sp))
+    }
+
+    // return addresses, innermost call first
+    pub fn call_stack(&self) -> Vec<u16> {
+        self.cpu.stack[..self.cpu.get_sp()].iter().rev().cloned().collect()
+    }
+
+    pub fn current_instruction(&self) -> String {
+        let pc = self.cpu.get_pc() as usize;
+        if pc + 1 >= MEM_SIZE {
+            return format!("0x{:03X}: <out of memory>", pc);
+        }
+        let opcode = self.cpu.fetch_current_instruction();
+        let (instr, _) = Instruction::decode(opcode);
+        let source = self.source_map.as_ref().and_then(|map| {
+            let line = map.line(pc as u16)?;
+            Some(format!("    line {}: {}", line, map.text(line)?))
+        });
+        format!("0x{:03X}: {:04X} {:?}{}", pc, opcode, instr, source.unwrap_or_default())
+    }
+
+    pub fn dump_registers(&self) -> String {
+        let mut out = String::new();
+        for reg in 0..16 {
+            out.push_str(&format!("V{:X}={:02X} ", reg, self.cpu.read_register(reg)));
+            if reg == 7 {
+                out.push('\n');
+            }
+        }
+        out.push_str(&format!(
+            "\nI={:03X} PC={:03X} SP={:X} DT={:02X} ST={:02X} cycles={}",
+            self.cpu.get_i(),
+            self.cpu.get_pc(),
+            self.cpu.get_sp(),
+            self.cpu.get_dt(),
+            self.cpu.get_st(),
+            self.cycles
+        ));
+        out
+    }
+
+    pub fn dump_memory(&self, start: usize, len: usize) -> String {
+        let mut out = String::new();
+        let _ = self.cpu.memory.write_hex_dump(&mut out, start, len);
+        out.trim_end().to_string()
+    }
+
+    pub fn dump_stack(&self) -> String {
+        let stack = self.call_stack();
+        if stack.is_empty() {
+            return "<empty>".to_string();
+        }
+        stack.iter().enumerate()
+            .map(|(depth, addr)| format!("#{} return to 0x{:03X}", depth, addr))
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+
+    // v0-vf, i, pc, sp, dt and st
+    pub fn set_register(&mut self, name: &str, value: usize) -> Result<(), String> {
+        let register = Register::parse(name).ok_or_else(|| format!("unknown register {}", name))?;
+        self.set_registers(&[(register, value)])
+    }
+
+    // writes all registers or none of them and starts a single new history
+    pub fn set_registers(&mut self, values: &[(Register, usize)]) -> Result<(), String> {
+        let sp_limit = self.cpu.stack.len();
+        if values.iter().any(|&(register, value)| register == Register::Sp && value > sp_limit) {
+            return Err(format!("sp must be at most {}", sp_limit));
+        }
+        for &(register, value) in values {
+            match register {
+                Register::V(reg) => self.cpu.write_register(reg as u32, value as u8),
+                Register::I => self.cpu.set_i(value as u16),
+                Register::Pc => self.cpu.set_pc(value as u16),
+                Register::Sp => self.cpu.set_sp(value),
+                Register::Dt => self.cpu.set_dt(value as u8),
+                Register::St => self.cpu.set_st(value as u8),
+            }
+        }
+        self.rewrite_history();
+        Ok(())
+    }
+
+    pub fn list_breakpoints(&self) -> String {
+        let addresses = self.breakpoints.iter().map(|addr| match self.conditions.get(addr) {
+            Some(condition) => format!("0x{:03X} if {}", addr, condition),
+            None => format!("0x{:03X}", addr),
+        });
+        let conditions = self.break_conditions.iter().enumerate()
+            .map(|(index, condition)| format!("#{} if {}", index, condition));
+        addresses.chain(conditions).collect::<Vec<String>>().join("\n")
+    }
+
+    pub fn list_watchpoints(&self) -> String {
+        self.watchpoints.iter().enumerate()
+            .map(|(index, watchpoint)| format!("{}: {}", index, watchpoint))
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+
+    pub fn poke_memory(&mut self, addr: usize, bytes: &[u8]) -> Result<(), String> {
+        let end = match addr.checked_add(bytes.len()) {
+            Some(end) if end <= MEM_SIZE => end,
+            _ => return Err(format!("0x{:X} is out of memory", addr.saturating_add(bytes.len()).saturating_sub(1))),
+        };
+        self.cpu.memory.memory[addr..end].copy_from_slice(bytes);
+        self.rewrite_history();
+        Ok(())
+    }
+
+    pub fn execute_command(&mut self, line: &str) -> Result<String, String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let stopped = |debugger: &Debugger, reason: StopReason| {
+            format!("{}\n{}", reason, debugger.current_instruction())
+        };
+        match words.first().cloned() {
+            None => Ok(String::new()),
+            Some("step") | Some("s") => {
+                let count = if words.len() > 1 { number(&words, 1)? } else { 1 };
+                for _ in 0..count {
+                    let reason = self.step();
+                    if reason != StopReason::Step {
+                        return Ok(stopped(self, reason));
+                    }
+                }
+                Ok(self.current_instruction())
+            }
+            Some("continue") | Some("c") => {
+                let reason = self.resume();
+                Ok(stopped(self, reason))
+            }
+            Some("next") | Some("n") => {
+                let reason = self.step_over();
+                Ok(stopped(self, reason))
+            }
+            Some("reverse-step") | Some("rs") => {
+                let count = if words.len() > 1 { number(&words, 1)? } else { 1 };
+                for _ in 0..count {
+                    let reason = self.reverse_step();
+                    if reason != StopReason::Step {
+                        return Ok(stopped(self, reason));
+                    }
+                }
+                Ok(self.current_instruction())
+            }
+            Some("reverse-continue") | Some("rc") => {
+                let reason = self.reverse_continue();
+                Ok(stopped(self, reason))
+            }
+            Some("finish") | Some("out") => {
+                let reason = self.step_out()?;
+                Ok(stopped(self, reason))
+            }
+            Some("break") | Some("b") => {
+                //everything after `if` is the condition
+                let (head, condition) = match line.split_once(" if ") {
+                    Some((head, condition)) => (head, Some(Expr::parse(condition)?)),
+                    None => (line, None),
+                };
+                let head: Vec<&str> = head.split_whitespace().collect();
+                if head.len() == 1 {
+                    let condition = condition.ok_or("missing address or condition")?;
+                    let out = format!("#{} if {}", self.break_conditions.len(), condition);
+                    self.break_conditions.push(condition);
+                    return Ok(out);
+                }
+                let addr = number(&head, 1)? as u16;
+                self.breakpoints.insert(addr);
+                match condition {
+                    Some(condition) => {
+                        let out = format!("breakpoint at 0x{:03X} if {}", addr, condition);
+                        self.conditions.insert(addr, condition);
+                        Ok(out)
+                    }
+                    None => {
+                        self.conditions.remove(&addr);
+                        Ok(format!("breakpoint at 0x{:03X}", addr))
+                    }
+                }
+            }
+            Some("delete") | Some("d") => {
+                if let Some(index) = words.get(1).and_then(|word| word.strip_prefix('#')) {
+                    let index = parse_number(index).filter(|&index| index < self.break_conditions.len())
+                        .ok_or_else(|| format!("no condition #{}", index))?;
+                    let condition = self.break_conditions.remove(index);
+                    return Ok(format!("deleted #{} if {}", index, condition));
+                }
+                let addr = number(&words, 1)? as u16;
+                self.conditions.remove(&addr);
+                if self.breakpoints.remove(&addr) {
+                    Ok(format!("deleted breakpoint at 0x{:03X}", addr))
+                } else {
+                    Err(format!("no breakpoint at 0x{:03X}", addr))
+                }
+            }
+            Some("breakpoints") => Ok(self.list_breakpoints()),
+            Some("watch") | Some("rwatch") | Some("awatch") => {
+                let kind = match words[0] {
+                    "watch" => WatchKind::Write,
+                    "rwatch" => WatchKind::Read,
+                    _ => WatchKind::Access,
+                };
+                let watchpoint = Watchpoint::parse(words.get(1).ok_or("missing watch target")?, kind)?;
+                self.watchpoints.push(watchpoint);
+                Ok(format!("{}: {}", self.watchpoints.len() - 1, watchpoint))
+            }
+            Some("unwatch") => {
+                let index = number(&words, 1)?;
+                if index >= self.watchpoints.len() {
+                    return Err(format!("no watchpoint {}", index));
+                }
+                let watchpoint = self.watchpoints.remove(index);
+                Ok(format!("deleted watchpoint {}", watchpoint))
+            }
+            Some("watchpoints") => Ok(self.list_watchpoints()),
+            Some("regs") | Some("r") => Ok(self.dump_registers()),
+            Some("mem") | Some("x") => {
+                let start = number(&words, 1)?;
+                let len = if words.len() > 2 { number(&words, 2)? } else { 0x40 };
+                Ok(self.dump_memory(start, len))
+            }
+            Some("set") => {
+                let name = words.get(1).ok_or("missing register")?;
+                self.set_register(name, number(&words, 2)?)?;
+                Ok(self.dump_registers())
+            }
+            Some("poke") => {
+                let addr = number(&words, 1)?;
+                let bytes = (2..words.len())
+                    .map(|index| number(&words, index).map(|value| value as u8))
+                    .collect::<Result<Vec<u8>, String>>()?;
+                self.poke_memory(addr, &bytes)?;
+                Ok(self.dump_memory(addr, bytes.len()))
+            }
+            Some("stack") | Some("bt") => Ok(self.dump_stack()),
+            Some("where") => Ok(self.current_instruction()),
+            Some("help") => Ok(HELP.to_string()),
+            Some(other) => Err(format!("unknown command {}, try help", other)),
+        }
+    }
+}
+
+pub const HELP: &str = "commands:
+  step|s [count]            execute instructions
+  continue|c                run until a breakpoint
+  next|n                    step over a CALL
+  finish|out                run until the current subroutine returns
+  reverse-step|rs [count]   undo instructions
+  reverse-continue|rc       run backwards to the previous breakpoint,
+                            condition or watchpoint hit
+  break|b <addr> [if expr]  add a breakpoint, optionally with a condition
+  break|b if <expr>         stop when the expression becomes true
+  delete|d <addr>|#<n>      remove a breakpoint or a condition
+  breakpoints               list breakpoints
+  watch <target>            stop after a write, targets are v0-vf, i,
+                            an address or an exclusive start..end range
+  rwatch <target>           stop after a read
+  awatch <target>           stop after a read or write
+  unwatch <n>               remove a watchpoint
+  watchpoints               list watchpoints
+  regs|r                    dump registers
+  mem|x <addr> [len]        dump memory
+  set <reg> <value>         write v0-vf, i, pc, sp, dt or st
+  poke <addr> <byte>...     write memory
+  stack|bt                  show the call stack
+  where                     show the next instruction
+  quit";
+
+// accepts decimal and 0x prefixed hex
+pub fn parse_number(word: &str) -> Option<usize> {
+    match word.strip_prefix("0x").or_else(|| word.strip_prefix("0X")) {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => word.parse().ok(),
+    }
+}
+
+fn number(words: &[&str], index: usize) -> Result<usize, String> {
+    let word = words.get(index).ok_or("missing argument")?;
+    parse_number(word).ok_or_else(|| format!("invalid number {}", word))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu::{Fault, CPU};
+    use crate::debugger::{Debugger, StopReason, WatchKind, Watchpoint};
+    use crate::expr::Expr;
+    use crate::memory::Memory;
+    use crate::savestate::SaveStateError;
+
+    fn prepare_debugger() -> Debugger {
+        let mut mem = Memory::new();
+        mem.load_program(&[
+            // CALL 0x208
+            0x22, 0x08, //0x200
+            // ADD 0, 0x01
+            0x70, 0x01, //0x202
+            // JP 0x206
+            0x12, 0x06, //0x204
+            // JP 0x206
+            0x12, 0x06, //0x206
+            // CALL 0x20E
+            0x22, 0x0E, //0x208
+            // LD 1, 0x05
+            0x61, 0x05, //0x20A
+            // RET
+            0x00, 0xEE, //0x20C
+            // LD 2, 0x07
+            0x62, 0x07, //0x20E
+            // RET
+            0x00, 0xEE, //0x210
+        ]);
+        Debugger::new(CPU::new(mem))
+    }
+
+    #[test]
+    fn test_step_over_and_out() {
+        let mut debugger = prepare_debugger();
+        assert_eq!(StopReason::Returned, debugger.step_over());
+        assert_eq!(0x202, debugger.cpu.get_pc());
+        assert_eq!(0x05, debugger.cpu.read_register(0x1));
+        assert_eq!(0x07, debugger.cpu.read_register(0x2));
+
+        let mut debugger = prepare_debugger();
+        debugger.step();
+        debugger.step();
+        assert_eq!(vec![0x20A, 0x202], debugger.call_stack());
+        assert_eq!(Ok(StopReason::Returned), debugger.step_out());
+        assert_eq!(0x20A, debugger.cpu.get_pc());
+        assert_eq!(Ok(StopReason::Returned), debugger.step_out());
+        assert_eq!(0x202, debugger.cpu.get_pc());
+        assert!(debugger.step_out().is_err());
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        let mut debugger = prepare_debugger();
+        debugger.breakpoints.insert(0x20E);
+        assert_eq!(StopReason::Breakpoint(0x20E), debugger.resume());
+        //a breakpoint on the current instruction does not stop continue again
+        assert_eq!(StopReason::Halted(0x206), debugger.resume());
+        assert_eq!(0x01, debugger.cpu.read_register(0x0));
+
+        let mut debugger = prepare_debugger();
+        debugger.breakpoints.insert(0x20E);
+        assert_eq!(StopReason::Breakpoint(0x20E), debugger.step_over());
+    }
+
+    #[test]
+    fn test_commands() {
+        let mut debugger = prepare_debugger();
+        assert_eq!(Ok("0x208: 220E CALL".to_string()), debugger.execute_command("step"));
+        debugger.execute_command("set v3 0x2A").unwrap();
+        debugger.execute_command("set I 0x300").unwrap();
+        assert_eq!(0x2A, debugger.cpu.read_register(0x3));
+        assert_eq!(0x300, debugger.cpu.get_i());
+        assert_eq!(Ok("0x300: 01 02 FF".to_string()), debugger.execute_command("poke 0x300 1 2 0xFF"));
+        assert_eq!(Ok("#0 return to 0x202".to_string()), debugger.execute_command("bt"));
+        assert!(debugger.execute_command("set v10 1").is_err());
+        assert!(debugger.execute_command("poke 0xFFF 1 2").is_err());
+        assert!(debugger.execute_command("frobnicate").is_err());
+        assert!(debugger.execute_command("mem 0x200 0x20").unwrap().starts_with("0x200: 22 08 70 01"));
+        assert!(debugger.execute_command("mem 0xFF0 0xFFFFFFFFFFFFFFFF").unwrap().starts_with("0xFF0: 00"));
+
+        let mut debugger = indirect_debugger();
+        debugger.execute_command("watch 0x303").unwrap();
+        assert_eq!(
+            Ok("watchpoint 0 triggered at 0x204\n0x206: F165 LD_VX_I".to_string()),
+            debugger.execute_command("step 100")
+        );
+        assert_eq!(3, debugger.cycles);
+    }
+
+    #[test]
+    fn test_faults() {
+        let mut mem = Memory::new();
+        mem.load_program(&[
+            // RET
+            0x00, 0xEE, //0x200
+        ]);
+        let mut debugger = Debugger::new(CPU::new(mem));
+        let underflow = StopReason::Fault { fault: Fault::StackUnderflow, pc: 0x200 };
+        assert_eq!(underflow, debugger.step());
+        assert_eq!(underflow, debugger.resume());
+        assert_eq!(underflow, debugger.step_over());
+        assert_eq!(0, debugger.cycles);
+        assert_eq!(0x200, debugger.cpu.get_pc());
+
+        debugger.execute_command("set pc 0xfff").unwrap();
+        assert_eq!(StopReason::Fault { fault: Fault::PcOutOfMemory, pc: 0xFFF }, debugger.step());
+        assert_eq!(StopReason::StartOfHistory, debugger.reverse_continue());
+        assert_eq!(
+            Ok("pc out of memory at 0xFFF\n0xFFF: <out of memory>".to_string()),
+            debugger.execute_command("step")
+        );
+
+        let mut mem = Memory::new();
+        mem.load_program(&[
+            // CALL 0x200
+            0x22, 0x00, //0x200
+        ]);
+        let mut debugger = Debugger::new(CPU::new(mem));
+        for _ in 0..16 {
+            assert_eq!(StopReason::Step, debugger.step());
+        }
+        assert_eq!(StopReason::Fault { fault: Fault::StackOverflow, pc: 0x200 }, debugger.step());
+        assert_eq!(16, debugger.cycles);
+    }
+
+    fn indirect_debugger() -> Debugger {
+        let mut mem = Memory::new();
+        mem.load_program(&[
+            // ADD 3, 0x04
+            0x73, 0x04, //0x200
+            // LD I, 0x300
+            0xA3, 0x00, //0x202
+            // LD [I], 3
+            0xF3, 0x55, //0x204
+            // LD 1, [I]
+            0xF1, 0x65, //0x206
+            // DRW 0, 1, 4
+            0xD0, 0x14, //0x208
+            // JP 0x200
+            0x12, 0x00, //0x20A
+        ]);
+        Debugger::new(CPU::new(mem))
+    }
+
+    #[test]
+    fn test_conditions() {
+        let mut debugger = indirect_debugger();
+        debugger.breakpoints.insert(0x204);
+        debugger.conditions.insert(0x204, Expr::parse("v3 == 0x10 && i >= 0x300").unwrap());
+        assert_eq!(StopReason::Breakpoint(0x204), debugger.resume());
+        assert_eq!(0x10, debugger.cpu.read_register(0x3));
+
+        let mut debugger = indirect_debugger();
+        debugger.break_conditions.push(Expr::parse("[i+3] != 0").unwrap());
+        assert_eq!(StopReason::Condition(0), debugger.resume());
+        assert_eq!(0x206, debugger.cpu.get_pc());
+        //the condition still holds, it has to become true again
+        debugger.execute_command("poke 0x303 0").unwrap();
+        assert_eq!(StopReason::Condition(0), debugger.resume());
+        assert_eq!(0x206, debugger.cpu.get_pc());
+        assert_eq!(0x08, debugger.cpu.read_register(0x3));
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let mut debugger = indirect_debugger();
+        debugger.watchpoints.push(Watchpoint::parse("0x301", WatchKind::Write).unwrap());
+        assert_eq!(StopReason::Watchpoint { index: 0, pc: 0x204 }, debugger.resume());
+
+        debugger.watchpoints[0] = Watchpoint::parse("0x302..0x304", WatchKind::Read).unwrap();
+        assert_eq!(StopReason::Watchpoint { index: 0, pc: 0x208 }, debugger.resume());
+
+        debugger.watchpoints[0] = Watchpoint::parse("v1", WatchKind::Write).unwrap();
+        assert_eq!(StopReason::Watchpoint { index: 0, pc: 0x206 }, debugger.resume());
+
+        debugger.watchpoints[0] = Watchpoint::parse("vf", WatchKind::Write).unwrap();
+        assert_eq!(StopReason::Watchpoint { index: 0, pc: 0x208 }, debugger.resume());
+
+        debugger.watchpoints[0] = Watchpoint::parse("v2", WatchKind::Access).unwrap();
+        assert_eq!(StopReason::Watchpoint { index: 0, pc: 0x204 }, debugger.resume());
+        assert!(Watchpoint::parse("pc", WatchKind::Read).is_err());
+        assert!(Watchpoint::parse("0x300..0x300", WatchKind::Read).is_err());
+    }
+
+    #[test]
+    fn test_condition_commands() {
+        let mut debugger = indirect_debugger();
+        assert_eq!(
+            Ok("breakpoint at 0x208 if v3 == 0xC".to_string()),
+            debugger.execute_command("break 0x208 if v3 == 12")
+        );
+        assert_eq!(Ok("#0 if [0x303] > 4".to_string()), debugger.execute_command("b if [0x303] > 4"));
+        assert_eq!(Ok("0: access 0x300..0x310".to_string()), debugger.execute_command("awatch 0x300..0x310"));
+        assert_eq!(Ok("0x208 if v3 == 0xC\n#0 if [0x303] > 4".to_string()), debugger.execute_command("breakpoints"));
+        debugger.execute_command("unwatch 0").unwrap();
+        assert!(debugger.execute_command("unwatch 0").is_err());
+        assert!(debugger.execute_command("break 0x208 if v3 ==").is_err());
+        assert!(debugger.execute_command("delete #1").is_err());
+        assert!(debugger.execute_command("continue").unwrap().starts_with("condition #0 became true"));
+        debugger.execute_command("delete #0").unwrap();
+        assert!(debugger.execute_command("continue").unwrap().starts_with("breakpoint at 0x208"));
+        assert_eq!(0x0C, debugger.cpu.read_register(0x3));
+    }
+
+    #[test]
+    fn test_reverse_step() {
+        let mut debugger = indirect_debugger();
+        debugger.snapshot_interval = 64;
+        let mut history = vec![];
+        for _ in 0..1000 {
+            history.push((debugger.cpu.clone(), debugger.cycles));
+            debugger.step();
+        }
+        while let Some((cpu, cycles)) = history.pop() {
+            assert_eq!(StopReason::Step, debugger.reverse_step());
+            assert_eq!(cycles, debugger.cycles);
+            assert_eq!(cpu, debugger.cpu);
+        }
+        assert_eq!(StopReason::StartOfHistory, debugger.reverse_step());
+
+        //only the newest snapshots are kept
+        debugger.max_snapshots = 2;
+        for _ in 0..1000 {
+            debugger.step();
+        }
+        assert!(debugger.execute_command("rs 1000").unwrap().starts_with("reached the start of the recorded history"));
+        assert_eq!(1000 / 64 * 64 - 64, debugger.cycles);
+    }
+
+    #[test]
+    fn test_reverse_step_after_fault() {
+        let mut mem = Memory::new();
+        mem.load_program(&[
+            // LD 1, 0x05
+            0x61, 0x05, //0x200
+            // ADD 1, 0x01
+            0x71, 0x01, //0x202
+            // RET
+            0x00, 0xEE, //0x204
+        ]);
+        let mut debugger = Debugger::new(CPU::new(mem));
+        let fault = StopReason::Fault { fault: Fault::StackUnderflow, pc: 0x204 };
+        assert_eq!(fault, debugger.resume());
+        assert_eq!(0x06, debugger.cpu.read_register(0x1));
+        assert_eq!(StopReason::Step, debugger.reverse_step());
+        assert_eq!(0x202, debugger.cpu.get_pc());
+        assert_eq!(0x05, debugger.cpu.read_register(0x1));
+        assert_eq!(StopReason::Step, debugger.reverse_step());
+        assert_eq!(0x200, debugger.cpu.get_pc());
+        assert_eq!(0x00, debugger.cpu.read_register(0x1));
+        //going forward again runs into the same fault
+        assert_eq!(StopReason::Step, debugger.step());
+        assert_eq!(StopReason::Step, debugger.step());
+        assert_eq!(fault, debugger.step());
+        assert_eq!(0x06, debugger.cpu.read_register(0x1));
+        assert_eq!(StopReason::StartOfHistory, debugger.reverse_continue());
+        assert_eq!(0, debugger.cycles);
+    }
+
+    #[test]
+    fn test_bad_snapshot() {
+        let mut mem = Memory::new();
+        mem.load_program(&[
+            // ADD 1, 0x01
+            0x71, 0x01, //0x200
+            // JP 0x200
+            0x12, 0x00, //0x202
+        ]);
+        let mut debugger = Debugger::new(CPU::new(mem));
+        debugger.execute_command("step 3").unwrap();
+        let state = debugger.snapshots.get_mut(&0).unwrap();
+        state.truncate(state.len() - 1);
+
+        let bad = StopReason::BadSnapshot(SaveStateError::Truncated);
+        assert_eq!(bad, debugger.reverse_step());
+        assert_eq!(bad, debugger.reverse_continue());
+        assert_eq!(3, debugger.cycles);
+        assert_eq!(0x02, debugger.cpu.read_register(0x1));
+        assert_eq!(
+            Ok("cannot restore snapshot: save state is truncated\n0x202: 1200 JP".to_string()),
+            debugger.execute_command("rs")
+        );
+    }
+
+    #[test]
+    fn test_reverse_continue() {
+        let mut debugger = indirect_debugger();
+        debugger.snapshot_interval = 7;
+        debugger.watchpoints.push(Watchpoint::parse("0x303", WatchKind::Write).unwrap());
+        for _ in 0..5 {
+            debugger.resume();
+        }
+        //every pass through the loop adds 4 to V3 before storing it
+        assert_eq!(0x14, debugger.cpu.memory.memory[0x303]);
+        assert_eq!(StopReason::Watchpoint { index: 0, pc: 0x204 }, debugger.reverse_continue());
+        assert_eq!(0x10, debugger.cpu.memory.memory[0x303]);
+        assert_eq!(0x206, debugger.cpu.get_pc());
+        assert_eq!(StopReason::Watchpoint { index: 0, pc: 0x204 }, debugger.reverse_continue());
+        assert_eq!(0x0C, debugger.cpu.memory.memory[0x303]);
+
+        debugger.watchpoints.clear();
+        debugger.breakpoints.insert(0x20A);
+        debugger.conditions.insert(0x20A, Expr::parse("v3 == 4").unwrap());
+        assert_eq!(StopReason::Breakpoint(0x20A), debugger.reverse_continue());
+        assert_eq!(4, debugger.cycles - 1);
+        assert_eq!(StopReason::StartOfHistory, debugger.reverse_continue());
+        assert_eq!(0, debugger.cycles);
+
+        //an edit replaces everything recorded after it
+        debugger.step();
+        debugger.execute_command("set v3 0x40").unwrap();
+        for _ in 0..20 {
+            debugger.step();
+        }
+        for _ in 0..20 {
+            debugger.reverse_step();
+        }
+        assert_eq!(0x40, debugger.cpu.read_register(0x3));
+        debugger.reverse_step();
+        assert_eq!(0x00, debugger.cpu.read_register(0x3));
+    }
+}
@@ -0,0 +1,539 @@
+// Decompiler to C-like pseudocode, one function per subroutine.
+//
+// CHIP-8 has no structured control flow, only skips and jumps. A skip over a
+// jump is a condition, the jump goes to the else branch or past the end:
+//
+//   SE V0, 1          if (v0 == 0x01) {
+//   JP skip               ...
+//   ...               }
+//   skip:
+//
+// A jump back to an earlier address makes a loop, with a skip over it
+// the loop continues while the skip is not taken. Whatever does not fit
+// these shapes is kept as a goto.
+//
+// Registers are named by how the subroutine uses them, V0 holding the X of
+// DRW becomes sprite_x. Names are only guesses, the comment at the top of
+// each function maps them back to registers.
+use crate::graph::Analysis;
+use crate::instructions::Instruction;
+use crate::platform::{extension, instruction_len, Platform};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+const INDENT: &str = "    ";
+
+enum Stmt {
+    Line(u16, String),
+    //a goto, or a tail call when the target is another subroutine
+    Goto { addr: u16, cond: Option<String>, target: u16 },
+    If { addr: u16, cond: String, then: Vec<Stmt>, otherwise: Vec<Stmt> },
+    Loop { addr: u16, body: Vec<Stmt> },
+    DoWhile { addr: u16, body: Vec<Stmt>, cond: String },
+}
+
+impl Stmt {
+    fn addr(&self) -> u16 {
+        match self {
+            Stmt::Line(addr, _) => *addr,
+            Stmt::Goto { addr, .. } | Stmt::If { addr, .. } | Stmt::Loop { addr, .. } | Stmt::DoWhile { addr, .. } => *addr,
+        }
+    }
+}
+
+// the loop a statement is in, jumps to head or to the jump back at latch
+// continue and to exit break
+#[derive(Copy, Clone)]
+struct Scope {
+    head: u16,
+    latch: u16,
+    exit: u16,
+}
+
+// usage of a register and the name it gives, the first role wins a tie
+const ROLES: [&str; 7] = ["sprite_x", "sprite_y", "counter", "key", "timer", "digit", "random"];
+
+// register names by their most frequent role, registers sharing a role get
+// their number appended
+fn register_names(opcodes: &[u16]) -> [String; 16] {
+    let mut counts = [[0; ROLES.len()]; 16];
+    let mut compared = [false; 16];
+    let mut added = [false; 16];
+    for &opcode in opcodes {
+        let (instr, value) = Instruction::decode(opcode as u32);
+        let (x, y) = ((value >> 8 & 0xF) as usize, (value >> 4 & 0xF) as usize);
+        match instr {
+            Instruction::DRW_VX_VY_NIB => {
+                counts[x][0] += 1;
+                counts[y][1] += 1;
+            }
+            Instruction::ADD_VX_BT => added[x] = true,
+            Instruction::SE_VX_BT | Instruction::SNE_VX_BT => compared[x] = true,
+            Instruction::LD_VX_K | Instruction::SKP_VX | Instruction::SKNP_VX => counts[x][3] += 1,
+            Instruction::LD_VX_DT | Instruction::LD_DT_VX | Instruction::LD_ST_VX => counts[x][4] += 1,
+            Instruction::LD_F_VX | Instruction::LD_B_VX => counts[x][5] += 1,
+            Instruction::RND_VX_BT => counts[x][6] += 1,
+            _ => {}
+        }
+    }
+    //stepped by a constant and tested against one
+    for x in 0..16 {
+        if added[x] && compared[x] {
+            counts[x][2] += 1;
+        }
+    }
+    let roles: Vec<Option<usize>> = counts
+        .iter()
+        .map(|count| {
+            let max = *count.iter().max().unwrap();
+            count.iter().position(|&n| n == max && n > 0)
+        })
+        .collect();
+    let mut names: [String; 16] = Default::default();
+    for (x, role) in roles.iter().enumerate() {
+        names[x] = match role {
+            Some(role) if roles.iter().filter(|&other| other == &Some(*role)).count() > 1 => format!("{}{:x}", ROLES[*role], x),
+            Some(role) => ROLES[*role].to_string(),
+            None => format!("v{:x}", x),
+        };
+    }
+    names
+}
+
+struct Function<'a> {
+    analysis: &'a Analysis,
+    //instructions of the subroutine
+    code: BTreeSet<u16>,
+    //addresses some jump or skip goes to, with where they come from
+    targets: BTreeMap<u16, BTreeSet<u16>>,
+    //targets of the emitted gotos, they get labels
+    gotos: BTreeSet<u16>,
+    names: [String; 16],
+}
+
+impl Function<'_> {
+    fn opcode(&self, addr: u16) -> u16 {
+        self.analysis.disassembly.fetch(addr).unwrap_or(0)
+    }
+
+    fn decode(&self, addr: u16) -> (Instruction, u16) {
+        let (instr, value) = Instruction::decode(self.opcode(addr) as u32);
+        (instr, value as u16)
+    }
+
+    fn is_jump(&self, addr: u16) -> Option<u16> {
+        match self.decode(addr) {
+            (Instruction::JP, value) if self.code.contains(&addr) => Some(value & 0xFFF),
+            _ => None,
+        }
+    }
+
+    // the condition under which the skip at addr skips
+    fn condition(&self, addr: u16, negate: bool) -> Option<String> {
+        let (instr, value) = self.decode(addr);
+        let x = &self.names[(value >> 8 & 0xF) as usize];
+        let y = &self.names[(value >> 4 & 0xF) as usize];
+        let (equal, unequal) = if negate { ("!=", "==") } else { ("==", "!=") };
+        let (pressed, released) = if negate { ("!", "") } else { ("", "!") };
+        Some(match instr {
+            Instruction::SE_VX_BT => format!("{} {} 0x{:02X}", x, equal, value & 0xFF),
+            Instruction::SNE_VX_BT => format!("{} {} 0x{:02X}", x, unequal, value & 0xFF),
+            Instruction::SE_VX_VY => format!("{} {} {}", x, equal, y),
+            Instruction::SNE_VX_VY => format!("{} {} {}", x, unequal, y),
+            Instruction::SKP_VX => format!("{}key_down({})", pressed, x),
+            Instruction::SKNP_VX => format!("{}key_down({})", released, x),
+            _ => return None,
+        })
+    }
+
+    fn expression(&self, addr: u16) -> String {
+        let opcode = self.opcode(addr);
+        let (instr, value) = self.decode(addr);
+        let x = &self.names[(value >> 8 & 0xF) as usize];
+        let y = &self.names[(value >> 4 & 0xF) as usize];
+        let vf = &self.names[0xF];
+        let byte = value & 0xFF;
+        let target = value & 0xFFF;
+        match instr {
+            Instruction::CLS => "clear_screen();".to_string(),
+            Instruction::RET => "return;".to_string(),
+            Instruction::SYS => format!("sys(0x{:03X});", target),
+            Instruction::JP => format!("jump(0x{:03X});", target),
+            Instruction::CALL => format!("{}();", self.function_name(target)),
+            Instruction::LD_VX_BT => format!("{} = 0x{:02X};", x, byte),
+            Instruction::ADD_VX_BT => format!("{} += 0x{:02X};", x, byte),
+            Instruction::LD_VX_VY => format!("{} = {};", x, y),
+            Instruction::OR_VX_VY => format!("{} |= {};", x, y),
+            Instruction::AND_VX_VY => format!("{} &= {};", x, y),
+            Instruction::XOR_VX_VY => format!("{} ^= {};", x, y),
+            Instruction::ADD_VX_VY => format!("{} += {}; // {} = carry", x, y, vf),
+            Instruction::SUB_VX_VY => format!("{} -= {}; // {} = no borrow", x, y, vf),
+            Instruction::SUBN_VX_VY => format!("{} = {} - {}; // {} = no borrow", x, y, x, vf),
+            Instruction::SHR_VX_VY | Instruction::SHL_VX_VY => {
+                let op = if instr == Instruction::SHR_VX_VY { ">>" } else { "<<" };
+                if x == y {
+                    format!("{} {}= 1; // {} = shifted out bit", x, op, vf)
+                } else {
+                    format!("{} = {} {} 1; // {} = shifted out bit, {} {} 1 with the shift quirk", x, y, op, vf, x, op)
+                }
+            }
+            Instruction::LD_I_ADDR => format!("I = {};", self.address(target)),
+            Instruction::JP_V0_ADDR => format!("jump({} + {});", self.address(target), self.names[0]),
+            Instruction::RND_VX_BT => format!("{} = random() & 0x{:02X};", x, byte),
+            Instruction::DRW_VX_VY_NIB => format!("{} = draw({}, {}, {});", vf, x, y, value & 0xF),
+            Instruction::SKP_VX | Instruction::SKNP_VX | Instruction::SE_VX_BT | Instruction::SNE_VX_BT
+            | Instruction::SE_VX_VY | Instruction::SNE_VX_VY => {
+                format!("if ({}) skip();", self.condition(addr, false).unwrap())
+            }
+            Instruction::LD_VX_DT => format!("{} = delay_timer;", x),
+            Instruction::LD_VX_K => format!("{} = wait_key();", x),
+            Instruction::LD_DT_VX => format!("delay_timer = {};", x),
+            Instruction::LD_ST_VX => format!("sound_timer = {};", x),
+            Instruction::ADD_I_VX => format!("I += {};", x),
+            Instruction::LD_F_VX => format!("I = font({});", x),
+            Instruction::LD_B_VX => format!("bcd({});", x),
+            Instruction::LD_I_VX => format!("save({});", self.names[..=(value >> 8 & 0xF) as usize].join(", ")),
+            Instruction::LD_VX_I => format!("load({});", self.names[..=(value >> 8 & 0xF) as usize].join(", ")),
+            Instruction::INVALID => {
+                let operand = if instruction_len(opcode) == 4 {
+                    format!(", 0x{:04X}", self.opcode(addr + 2))
+                } else {
+                    String::new()
+                };
+                match extension(opcode) {
+                    Some((_, name)) => format!("raw(0x{:04X}{}); // {}", opcode, operand, name),
+                    None => format!("raw(0x{:04X});", opcode),
+                }
+            }
+        }
+    }
+
+    fn function_name(&self, entry: u16) -> String {
+        match self.analysis.subroutines.get(&entry) {
+            Some(subroutine) => subroutine.name.clone(),
+            None => format!("sub_{:03x}", entry),
+        }
+    }
+
+    fn address(&self, addr: u16) -> String {
+        match self.analysis.disassembly.labels.get(&addr) {
+            Some(label) => label.clone(),
+            None => format!("0x{:03X}", addr),
+        }
+    }
+
+    fn jump(&mut self, addr: u16, cond: Option<String>, target: u16, scope: Option<Scope>) -> Stmt {
+        let keyword = match scope {
+            Some(scope) if target == scope.head || target == scope.latch => Some("continue;"),
+            Some(scope) if target == scope.exit => Some("break;"),
+            _ => None,
+        };
+        match (keyword, cond) {
+            (Some(keyword), Some(cond)) => Stmt::Line(addr, format!("if ({}) {}", cond, keyword)),
+            (Some(keyword), None) => Stmt::Line(addr, keyword.to_string()),
+            (None, cond) => {
+                if self.code.contains(&target) {
+                    self.gotos.insert(target);
+                }
+                Stmt::Goto { addr, cond, target }
+            }
+        }
+    }
+
+    // whether anything outside [lo, hi) jumps or skips to target
+    fn entered_from_outside(&self, target: u16, lo: u16, hi: u16) -> bool {
+        self.targets.get(&target).is_some_and(|sources| sources.iter().any(|&source| source < lo || source >= hi))
+    }
+
+    // the statements of the instructions in [lo, hi)
+    fn structure(&mut self, lo: u16, hi: u16, scope: Option<Scope>) -> Vec<Stmt> {
+        let mut stmts = vec![];
+        let mut next = self.code.range(lo..hi).next().copied();
+        while let Some(addr) = next {
+            let (stmt, resume) = self.statement(addr, hi, scope);
+            stmts.push(stmt);
+            next = if resume < hi { self.code.range(resume..hi).next().copied() } else { None };
+        }
+        stmts
+    }
+
+    // the last jump back to head in [head, hi) that only the loop jumps to
+    fn back_jump(&self, head: u16, hi: u16) -> Option<u16> {
+        self.code
+            .range(head..hi)
+            .rev()
+            .find(|&&addr| self.is_jump(addr) == Some(head) && (addr == head || !self.entered_from_outside(addr, head, addr)))
+            .copied()
+    }
+
+    // a statement starting at addr and the address after it
+    fn statement(&mut self, addr: u16, hi: u16, scope: Option<Scope>) -> (Stmt, u16) {
+        if self.targets.contains_key(&addr) {
+            if let Some(end) = self.back_jump(addr, hi) {
+                //jumps to the test of a do while continue
+                let test = end.wrapping_sub(2);
+                let skip = end > addr
+                    && self.code.contains(&test)
+                    && !self.entered_from_outside(test, addr, test)
+                    && !self.targets.contains_key(&end);
+                if let Some(cond) = self.condition(test, true).filter(|_| skip) {
+                    let body = self.structure(addr, test, Some(Scope { head: test, latch: test, exit: end + 2 }));
+                    return (Stmt::DoWhile { addr, body, cond }, end + 2);
+                }
+                let body = self.structure(addr, end, Some(Scope { head: addr, latch: end, exit: end + 2 }));
+                return (Stmt::Loop { addr, body }, end + 2);
+            }
+        }
+
+        let (instr, value) = self.decode(addr);
+        if let Some(cond) = self.condition(addr, false) {
+            let next = addr + 2;
+            let single = next < hi && self.code.contains(&next) && !self.targets.contains_key(&next);
+            if let Some(target) = self.is_jump(next).filter(|_| single) {
+                let negated = self.condition(addr, true);
+                let leaves = scope.is_some_and(|scope| [scope.head, scope.latch, scope.exit].contains(&target));
+                if target >= addr + 4 && target <= hi && !leaves {
+                    //a jump at the end of the then branch over the else branch
+                    let last = self.code.range(addr + 4..target).next_back().copied();
+                    let over = last.and_then(|last| self.is_jump(last).map(|end| (last, end)));
+                    if let Some((last, end)) = over {
+                        if end > target && end <= hi && !self.targets.contains_key(&last) {
+                            let then = self.structure(addr + 4, last, scope);
+                            let otherwise = self.structure(target, end, scope);
+                            return (Stmt::If { addr, cond, then, otherwise }, end);
+                        }
+                    }
+                    let then = self.structure(addr + 4, target, scope);
+                    return (Stmt::If { addr, cond, then, otherwise: vec![] }, target);
+                }
+                return (self.jump(addr, negated, target, scope), addr + 4);
+            }
+            let (next_instr, _) = self.decode(next);
+            let plain = self.condition(next, false).is_none() && instruction_len(self.opcode(next)) == 2;
+            if single && plain && next_instr != Instruction::JP {
+                let negated = self.condition(addr, true).unwrap();
+                return (Stmt::Line(addr, format!("if ({}) {}", negated, self.expression(next))), addr + 4);
+            }
+            return (self.jump(addr, Some(cond), addr + 4, scope), addr + 2);
+        }
+
+        if instr == Instruction::JP {
+            return (self.jump(addr, None, value & 0xFFF, scope), addr + 2);
+        }
+        (Stmt::Line(addr, self.expression(addr)), addr + instruction_len(self.opcode(addr)))
+    }
+
+    // labelled is the address of the enclosing statement, whose label is
+    // already written
+    fn render(&self, out: &mut String, stmts: &[Stmt], depth: usize, labelled: Option<u16>) {
+        let indent = INDENT.repeat(depth);
+        for stmt in stmts {
+            if self.gotos.contains(&stmt.addr()) && labelled != Some(stmt.addr()) {
+                writeln!(out, "{}label_{:03x}:", INDENT.repeat(depth - 1), stmt.addr()).unwrap();
+            }
+            match stmt {
+                Stmt::Line(_, text) => writeln!(out, "{}{}", indent, text).unwrap(),
+                Stmt::Goto { cond, target, .. } => {
+                    let jump = if self.code.contains(target) {
+                        format!("goto label_{:03x};", target)
+                    } else if self.analysis.subroutines.contains_key(target) {
+                        format!("return {}();", self.function_name(*target))
+                    } else {
+                        format!("jump(0x{:03X});", target)
+                    };
+                    match cond {
+                        Some(cond) => writeln!(out, "{}if ({}) {}", indent, cond, jump).unwrap(),
+                        None => writeln!(out, "{}{}", indent, jump).unwrap(),
+                    }
+                }
+                Stmt::If { cond, then, otherwise, .. } => {
+                    writeln!(out, "{}if ({}) {{", indent, cond).unwrap();
+                    self.render(out, then, depth + 1, None);
+                    if !otherwise.is_empty() {
+                        writeln!(out, "{}}} else {{", indent).unwrap();
+                        self.render(out, otherwise, depth + 1, None);
+                    }
+                    writeln!(out, "{}}}", indent).unwrap();
+                }
+                Stmt::Loop { addr, body } => {
+                    writeln!(out, "{}while (true) {{", indent).unwrap();
+                    self.render(out, body, depth + 1, Some(*addr));
+                    writeln!(out, "{}}}", indent).unwrap();
+                }
+                Stmt::DoWhile { addr, body, cond } => {
+                    writeln!(out, "{}do {{", indent).unwrap();
+                    self.render(out, body, depth + 1, Some(*addr));
+                    writeln!(out, "{}}} while ({});", indent, cond).unwrap();
+                }
+            }
+        }
+    }
+}
+
+// pseudocode of every subroutine the analysis found
+pub fn decompile(rom: &[u8], platform: Platform) -> String {
+    let analysis = Analysis::for_platform(rom, platform);
+    let mut out = String::new();
+    for subroutine in analysis.subroutines.values() {
+        let mut code = BTreeSet::new();
+        for block in subroutine.blocks.values() {
+            let mut addr = block.start;
+            while addr < block.end {
+                code.insert(addr);
+                addr += instruction_len(analysis.disassembly.fetch(addr).unwrap_or(0));
+            }
+        }
+        let opcodes: Vec<u16> = code.iter().map(|&addr| analysis.disassembly.fetch(addr).unwrap_or(0)).collect();
+        let mut targets: BTreeMap<u16, BTreeSet<u16>> = BTreeMap::new();
+        for block in subroutine.blocks.values() {
+            for &(to, _) in block.successors.iter() {
+                if to != block.end {
+                    targets.entry(to).or_default().insert(block.end - 2);
+                }
+            }
+        }
+        let mut function = Function {
+            analysis: &analysis,
+            targets,
+            gotos: BTreeSet::new(),
+            names: register_names(&opcodes),
+            code,
+        };
+        let hi = function.code.iter().next_back().map_or(subroutine.entry, |&last| last + 2);
+        let stmts = function.structure(subroutine.entry, hi, None);
+        //code before the entry, reached by a jump back
+        let before = function.structure(0, subroutine.entry, None);
+
+        if !out.is_empty() {
+            out.push('\n');
+        }
+        writeln!(out, "void {}() {{", subroutine.name).unwrap();
+        let renamed: BTreeMap<usize, &String> =
+            function.names.iter().enumerate().filter(|(x, name)| **name != format!("v{:x}", x)).collect();
+        if !renamed.is_empty() {
+            let names: Vec<String> = renamed.iter().map(|(x, name)| format!("v{:x} {}", x, name)).collect();
+            writeln!(out, "{}// {}", INDENT, names.join(", ")).unwrap();
+        }
+        function.render(&mut out, &stmts, 1, None);
+        function.render(&mut out, &before, 1, None);
+        writeln!(out, "}}").unwrap();
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::asm::assemble;
+    use crate::decompile::decompile;
+    use crate::platform::Platform;
+
+    fn decompiled(source: &str) -> String {
+        decompile(&assemble(source).unwrap(), Platform::Chip8)
+    }
+
+    #[test]
+    fn test_structure() {
+        let source = "
+            main:
+                LD V0, 0
+            loop:
+                SE V0, 5
+                JP else
+                CALL draw
+                JP done
+            else:
+                ADD V1, 1
+            done:
+                SKNP V2
+                JP loop
+            wait:
+                ADD V0, 1
+                SE V0, 10
+                JP wait
+                SNE V3, 1
+                RET
+                LD DT, V3
+                JP main
+            draw:
+                DRW V0, V1, 5
+                RET
+        ";
+        let expected = "\
+void main() {
+    // v0 counter, v2 key, v3 timer
+    while (true) {
+        counter = 0x00;
+        do {
+            if (counter == 0x05) {
+                sub_21e();
+            } else {
+                v1 += 0x01;
+            }
+        } while (key_down(key));
+        do {
+            counter += 0x01;
+        } while (counter != 0x0A);
+        if (timer == 0x01) return;
+        delay_timer = timer;
+    }
+}
+
+void sub_21e() {
+    // v0 sprite_x, v1 sprite_y
+    vf = draw(sprite_x, sprite_y, 5);
+    return;
+}
+";
+        assert_eq!(expected, decompiled(source));
+    }
+
+    #[test]
+    fn test_loop_exits() {
+        let source = "
+            main:
+                SE V0, 1
+                JP 0x300
+                SKP V1
+                SE V2, 3
+                JP main
+            data:
+                db 0x01, 0x23
+        ";
+        let expected = "\
+void main() {
+    // v1 key
+    while (true) {
+        if (v0 != 0x01) jump(0x300);
+        if (key_down(key)) continue;
+        if (v2 == 0x03) break;
+    }
+}
+";
+        assert_eq!(expected, decompiled(source));
+    }
+
+    #[test]
+    fn test_gotos() {
+        let source = "
+            main:
+                SE V0, 1
+                JP inner
+            top:
+                CLS
+            inner:
+                ADD V1, 1
+                JP top
+        ";
+        let expected = "\
+void main() {
+    if (v0 == 0x01) {
+    label_204:
+        clear_screen();
+    }
+    v1 += 0x01;
+    goto label_204;
+}
+";
+        assert_eq!(expected, decompiled(source));
+    }
+}
@@ -0,0 +1,197 @@
+// Guesses the platform a ROM was written for.
+//
+// Code is followed with the XO-CHIP profile, the widest one, and every
+// extension opcode it reaches counts for the platform that introduced it.
+// A 16x16 sprite (DRW with height 0) is weaker evidence for SUPER-CHIP and a
+// ROM too big for 4k of memory can only be XO-CHIP. Without any of that the
+// ROM is taken for CHIP-8.
+//
+// Quirk sensitive patterns are reported as hints. Shifting VY into VX and
+// reusing I after a load or store only work on the original interpreter, so
+// they count a little towards CHIP-8 as well.
+use crate::disasm::Disassembly;
+use crate::instructions::Instruction;
+use crate::memory::{MEM_SIZE, PROGRAM_LOAD_OFFSET};
+use crate::platform::{extension, instruction_len, Platform};
+use std::fmt;
+
+//instructions searched after a load or store for the next use of I
+const I_LOOKAHEAD: usize = 8;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Detection {
+    pub platform: Platform,
+    //0 to 1
+    pub confidence: f32,
+    //evidence for the platform
+    pub reasons: Vec<String>,
+    //patterns whose behaviour depends on the quirks
+    pub hints: Vec<(u16, String)>,
+}
+
+impl fmt::Display for Detection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (confidence {:.2})", self.platform.name(), self.confidence)?;
+        for reason in self.reasons.iter() {
+            write!(f, "\n  {}", reason)?;
+        }
+        for (addr, hint) in self.hints.iter() {
+            write!(f, "\n  quirk 0x{:03X}: {}", addr, hint)?;
+        }
+        Ok(())
+    }
+}
+
+// evidence weighs more the more of it there is, without ever reaching 1
+fn confidence(points: u32) -> f32 {
+    points as f32 / (points as f32 + 1.0)
+}
+
+fn reads_i(instr: Instruction) -> bool {
+    matches!(
+        instr,
+        Instruction::DRW_VX_VY_NIB
+            | Instruction::LD_B_VX
+            | Instruction::LD_I_VX
+            | Instruction::LD_VX_I
+            | Instruction::ADD_I_VX
+    )
+}
+
+fn ends_straight_line(instr: Instruction) -> bool {
+    matches!(
+        instr,
+        Instruction::JP
+            | Instruction::CALL
+            | Instruction::RET
+            | Instruction::JP_V0_ADDR
+            | Instruction::SE_VX_BT
+            | Instruction::SNE_VX_BT
+            | Instruction::SE_VX_VY
+            | Instruction::SNE_VX_VY
+            | Instruction::SKP_VX
+            | Instruction::SKNP_VX
+            | Instruction::INVALID
+    )
+}
+
+fn quirk_hints(disassembly: &Disassembly) -> Vec<(u16, String)> {
+    let decode = |addr: u16| {
+        let opcode = disassembly.fetch(addr).unwrap_or(0);
+        let (instr, value) = Instruction::decode(opcode as u32);
+        (instr, value as u16)
+    };
+    let mut hints = vec![];
+    for &addr in disassembly.code.iter() {
+        let (instr, value) = decode(addr);
+        let (x, y) = (value >> 8 & 0xF, value >> 4 & 0xF);
+        match instr {
+            Instruction::SHR_VX_VY | Instruction::SHL_VX_VY if x != y => {
+                hints.push((addr, format!("shifts V{:X} into V{:X}, only the original interpreter reads VY", y, x)));
+            }
+            Instruction::LD_I_VX | Instruction::LD_VX_I => {
+                let mut next = addr + 2;
+                for _ in 0..I_LOOKAHEAD {
+                    if !disassembly.code.contains(&next) {
+                        break;
+                    }
+                    let (instr, _) = decode(next);
+                    if reads_i(instr) {
+                        hints.push((next, format!("uses I after 0x{:03X} without setting it, I may not have moved", addr)));
+                        break;
+                    }
+                    if matches!(instr, Instruction::LD_I_ADDR | Instruction::LD_F_VX) || ends_straight_line(instr) {
+                        break;
+                    }
+                    next += 2;
+                }
+            }
+            _ => {}
+        }
+    }
+    hints
+}
+
+pub fn detect(rom: &[u8]) -> Detection {
+    let disassembly = Disassembly::for_platform(rom, Platform::XoChip);
+    let mut super_points = 0;
+    let mut xo_points = 0;
+    let mut reasons = vec![];
+    if rom.len() > MEM_SIZE - PROGRAM_LOAD_OFFSET {
+        xo_points += 4;
+        reasons.push(format!("{} bytes do not fit in 4k of memory", rom.len()));
+    }
+    let mut unresolved = false;
+    for &addr in disassembly.code.iter() {
+        let opcode = disassembly.fetch(addr).unwrap();
+        let (instr, value) = Instruction::decode(opcode as u32);
+        if let Some((platform, name)) = extension(opcode) {
+            match platform {
+                Platform::XoChip => xo_points += 2,
+                _ => super_points += 2,
+            }
+            let operand = if instruction_len(opcode) == 4 { " NNNN" } else { "" };
+            reasons.push(format!("0x{:03X}: {} (0x{:04X}{}) needs {}", addr, name, opcode, operand, platform.name()));
+        } else if instr == Instruction::DRW_VX_VY_NIB && value & 0xF == 0 {
+            super_points += 1;
+            reasons.push(format!("0x{:03X}: DRW with height 0 draws a 16x16 sprite on SUPER-CHIP", addr));
+        } else if instr == Instruction::JP_V0_ADDR {
+            unresolved = true;
+        }
+    }
+    let hints = quirk_hints(&disassembly);
+
+    let (platform, mut confidence) = if xo_points > 0 {
+        (Platform::XoChip, confidence(xo_points))
+    } else if super_points > 0 {
+        (Platform::SuperChip, confidence(super_points))
+    } else if disassembly.code.is_empty() {
+        reasons.push("no code was found".to_string());
+        (Platform::Chip8, 0.5)
+    } else {
+        reasons.push(format!("{} instructions use no extension", disassembly.code.len()));
+        (Platform::Chip8, confidence(2 + hints.len() as u32))
+    };
+    //code behind JP V0 was not seen
+    if unresolved {
+        confidence *= 0.8;
+        reasons.push("some code is only reached through JP V0 and was not checked".to_string());
+    }
+    Detection { platform, confidence, reasons, hints }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::asm::assemble;
+    use crate::detect::detect;
+    use crate::platform::Platform;
+
+    #[test]
+    fn test_detect() {
+        let chip8 = detect(&assemble("main: LD I, 0x300\n LD [I], V2\n LD V2, [I]\n SHR V1, V2\n JP main").unwrap());
+        assert_eq!(Platform::Chip8, chip8.platform);
+        assert_eq!(0.8, chip8.confidence);
+        assert_eq!(
+            vec![
+                (0x204, "uses I after 0x202 without setting it, I may not have moved".to_string()),
+                (0x206, "shifts V2 into V1, only the original interpreter reads VY".to_string()),
+            ],
+            chip8.hints
+        );
+
+        let schip = detect(&assemble("main: db 0x00, 0xFF\n DRW V0, V1, 0\n JP main").unwrap());
+        assert_eq!(Platform::SuperChip, schip.platform);
+        assert_eq!(0.75, schip.confidence);
+        assert_eq!("0x200: hires (0x00FF) needs SUPER-CHIP", schip.reasons[0]);
+
+        let xo = detect(&assemble("main: db 0xF0, 0x00\n dw 0x1234\n db 0x00, 0xFF\n JP main").unwrap());
+        assert_eq!(Platform::XoChip, xo.platform);
+        assert_eq!("0x200: i := long (0xF000 NNNN) needs XO-CHIP", xo.reasons[0]);
+        assert_eq!(Platform::XoChip, detect(&[0x12, 0x00].repeat(2000)).platform);
+
+        let hidden = detect(&assemble("JP V0, 0x300").unwrap());
+        assert_eq!(Platform::Chip8, hidden.platform);
+        assert!(hidden.confidence < 0.6);
+        assert_eq!((Platform::Chip8, 0.5), (detect(&[]).platform, detect(&[]).confidence));
+    }
+}
@@ -0,0 +1,378 @@
+// Disassembler. Code is found by following control flow from the load
+// address, every byte that is never reached is emitted as data.
+//
+// Cowgod syntax uses the mnemonics of the Instruction variants:
+//
+//   LD I, data_20e
+//   DRW V0, V1, 5
+//   db 0xF0, 0x90
+//
+// Octo syntax is accepted by the Octo assembler. Skips become conditions
+// with the opposite test, `SE V0, 5` reads `if v0 != 5 then`.
+use crate::instructions::Instruction;
+use crate::memory::{MEM_SIZE, PROGRAM_LOAD_OFFSET};
+use crate::platform::{instruction_len, Platform};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Syntax {
+    Cowgod,
+    Octo,
+}
+
+// data bytes per directive line
+const BYTES_PER_LINE: usize = 8;
+
+pub struct Disassembly {
+    rom: Vec<u8>,
+    //extension opcodes of the platform are followed like any other
+    //instruction, they are listed as data
+    pub platform: Platform,
+    //addresses of reachable instructions
+    pub code: BTreeSet<u16>,
+    pub labels: BTreeMap<u16, String>,
+}
+
+fn is_skip(instr: Instruction) -> bool {
+    matches!(
+        instr,
+        Instruction::SE_VX_BT
+        | Instruction::SNE_VX_BT
+        | Instruction::SE_VX_VY
+        | Instruction::SNE_VX_VY
+        | Instruction::SKP_VX
+        | Instruction::SKNP_VX
+    )
+}
+
+impl Disassembly {
+    pub fn new(rom: &[u8]) -> Disassembly {
+        Disassembly::for_platform(rom, Platform::Chip8)
+    }
+
+    pub fn for_platform(rom: &[u8], platform: Platform) -> Disassembly {
+        let mut disassembly = Disassembly {
+            rom: rom.to_vec(),
+            platform,
+            code: BTreeSet::new(),
+            labels: BTreeMap::new(),
+        };
+        disassembly.discover();
+        disassembly
+    }
+
+    // address after the last byte of the rom
+    pub fn rom_end(&self) -> usize {
+        (PROGRAM_LOAD_OFFSET + self.rom.len()).min(MEM_SIZE)
+    }
+
+    fn in_rom(&self, addr: u16) -> bool {
+        let addr = addr as usize;
+        addr >= PROGRAM_LOAD_OFFSET && addr < self.rom_end()
+    }
+
+    pub fn fetch(&self, addr: u16) -> Option<u16> {
+        let offset = (addr as usize).checked_sub(PROGRAM_LOAD_OFFSET)?;
+        Some((*self.rom.get(offset)? as u16) << 8 | *self.rom.get(offset + 1)? as u16)
+    }
+
+    // recursive traversal with an explicit work list
+    fn discover(&mut self) {
+        let mut jumps = BTreeSet::new();
+        let mut calls = BTreeSet::new();
+        let mut data = BTreeSet::new();
+        let mut work = vec![PROGRAM_LOAD_OFFSET as u16];
+        while let Some(mut addr) = work.pop() {
+            while !self.code.contains(&addr) {
+                let opcode = match self.fetch(addr) {
+                    Some(opcode) => opcode,
+                    None => break,
+                };
+                let (instr, value) = Instruction::decode(opcode as u32);
+                let target = (value & 0x0FFF) as u16;
+                if instr == Instruction::INVALID {
+                    if !self.platform.supports(opcode) {
+                        break;
+                    }
+                    self.code.insert(addr);
+                    //super-chip exit
+                    if opcode == 0x00FD {
+                        break;
+                    }
+                    addr += instruction_len(opcode);
+                    continue;
+                }
+                self.code.insert(addr);
+                match instr {
+                    Instruction::JP => {
+                        jumps.insert(target);
+                        work.push(target);
+                        break;
+                    }
+                    Instruction::CALL => {
+                        calls.insert(target);
+                        work.push(target);
+                    }
+                    //the target of JP V0 depends on V0, the table it jumps
+                    //into has to be found some other way
+                    Instruction::RET | Instruction::JP_V0_ADDR => break,
+                    Instruction::LD_I_ADDR => {
+                        data.insert(target);
+                    }
+                    i if is_skip(i) => work.push(addr + 4),
+                    _ => {}
+                }
+                addr += 2;
+            }
+        }
+
+        //a label in the middle of an instruction could not be placed
+        let kinds = [(data, "data"), (jumps, "label"), (calls, "sub")];
+        for (targets, prefix) in kinds.iter() {
+            for &addr in targets.iter() {
+                if self.in_rom(addr) && !self.code.contains(&addr.wrapping_sub(1)) {
+                    self.labels.insert(addr, format!("{}_{:03x}", prefix, addr));
+                }
+            }
+        }
+        if self.code.contains(&(PROGRAM_LOAD_OFFSET as u16)) {
+            self.labels.insert(PROGRAM_LOAD_OFFSET as u16, "main".to_string());
+        }
+    }
+
+    // the label of `addr` or the address itself
+    fn operand(&self, addr: u16) -> String {
+        match self.labels.get(&addr) {
+            Some(label) => label.clone(),
+            None => format!("0x{:03X}", addr),
+        }
+    }
+
+    pub fn format_instruction(&self, opcode: u16, syntax: Syntax) -> String {
+        let (instr, value) = Instruction::decode(opcode as u32);
+        let x = value >> 8 & 0xF;
+        let y = value >> 4 & 0xF;
+        let byte = value & 0xFF;
+        let addr = self.operand((value & 0xFFF) as u16);
+        match syntax {
+            Syntax::Cowgod => match instr {
+                Instruction::CLS => "CLS".to_string(),
+                Instruction::RET => "RET".to_string(),
+                Instruction::SYS => format!("SYS 0x{:03X}", value & 0xFFF),
+                Instruction::JP => format!("JP {}", addr),
+                Instruction::CALL => format!("CALL {}", addr),
+                Instruction::SE_VX_BT => format!("SE V{:X}, 0x{:02X}", x, byte),
+                Instruction::SNE_VX_BT => format!("SNE V{:X}, 0x{:02X}", x, byte),
+                Instruction::SE_VX_VY => format!("SE V{:X}, V{:X}", x, y),
+                Instruction::LD_VX_BT => format!("LD V{:X}, 0x{:02X}", x, byte),
+                Instruction::ADD_VX_BT => format!("ADD V{:X}, 0x{:02X}", x, byte),
+                Instruction::LD_VX_VY => format!("LD V{:X}, V{:X}", x, y),
+                Instruction::OR_VX_VY => format!("OR V{:X}, V{:X}", x, y),
+                Instruction::AND_VX_VY => format!("AND V{:X}, V{:X}", x, y),
+                Instruction::XOR_VX_VY => format!("XOR V{:X}, V{:X}", x, y),
+                Instruction::ADD_VX_VY => format!("ADD V{:X}, V{:X}", x, y),
+                Instruction::SUB_VX_VY => format!("SUB V{:X}, V{:X}", x, y),
+                Instruction::SHR_VX_VY => format!("SHR V{:X}, V{:X}", x, y),
+                Instruction::SUBN_VX_VY => format!("SUBN V{:X}, V{:X}", x, y),
+                Instruction::SHL_VX_VY => format!("SHL V{:X}, V{:X}", x, y),
+                Instruction::SNE_VX_VY => format!("SNE V{:X}, V{:X}", x, y),
+                Instruction::LD_I_ADDR => format!("LD I, {}", addr),
+                Instruction::JP_V0_ADDR => format!("JP V0, {}", addr),
+                Instruction::RND_VX_BT => format!("RND V{:X}, 0x{:02X}", x, byte),
+                Instruction::DRW_VX_VY_NIB => format!("DRW V{:X}, V{:X}, {}", x, y, value & 0xF),
+                Instruction::SKP_VX => format!("SKP V{:X}", x),
+                Instruction::SKNP_VX => format!("SKNP V{:X}", x),
+                Instruction::LD_VX_DT => format!("LD V{:X}, DT", x),
+                Instruction::LD_VX_K => format!("LD V{:X}, K", x),
+                Instruction::LD_DT_VX => format!("LD DT, V{:X}", x),
+                Instruction::LD_ST_VX => format!("LD ST, V{:X}", x),
+                Instruction::ADD_I_VX => format!("ADD I, V{:X}", x),
+                Instruction::LD_F_VX => format!("LD F, V{:X}", x),
+                Instruction::LD_B_VX => format!("LD B, V{:X}", x),
+                Instruction::LD_I_VX => format!("LD [I], V{:X}", x),
+                Instruction::LD_VX_I => format!("LD V{:X}, [I]", x),
+                Instruction::INVALID => format!("db 0x{:02X}, 0x{:02X}", opcode >> 8, opcode & 0xFF),
+            },
+            Syntax::Octo => match instr {
+                Instruction::CLS => "clear".to_string(),
+                Instruction::RET => "return".to_string(),
+                Instruction::JP => format!("jump {}", addr),
+                Instruction::CALL if self.labels.contains_key(&((value & 0xFFF) as u16)) => addr,
+                Instruction::CALL => format!(":call {}", addr),
+                Instruction::SE_VX_BT => format!("if v{:x} != 0x{:02X} then", x, byte),
+                Instruction::SNE_VX_BT => format!("if v{:x} == 0x{:02X} then", x, byte),
+                Instruction::SE_VX_VY => format!("if v{:x} != v{:x} then", x, y),
+                Instruction::LD_VX_BT => format!("v{:x} := 0x{:02X}", x, byte),
+                Instruction::ADD_VX_BT => format!("v{:x} += 0x{:02X}", x, byte),
+                Instruction::LD_VX_VY => format!("v{:x} := v{:x}", x, y),
+                Instruction::OR_VX_VY => format!("v{:x} |= v{:x}", x, y),
+                Instruction::AND_VX_VY => format!("v{:x} &= v{:x}", x, y),
+                Instruction::XOR_VX_VY => format!("v{:x} ^= v{:x}", x, y),
+                Instruction::ADD_VX_VY => format!("v{:x} += v{:x}", x, y),
+                Instruction::SUB_VX_VY => format!("v{:x} -= v{:x}", x, y),
+                Instruction::SHR_VX_VY => format!("v{:x} >>= v{:x}", x, y),
+                Instruction::SUBN_VX_VY => format!("v{:x} =- v{:x}", x, y),
+                Instruction::SHL_VX_VY => format!("v{:x} <<= v{:x}", x, y),
+                Instruction::SNE_VX_VY => format!("if v{:x} == v{:x} then", x, y),
+                Instruction::LD_I_ADDR => format!("i := {}", addr),
+                Instruction::JP_V0_ADDR => format!("jump0 {}", addr),
+                Instruction::RND_VX_BT => format!("v{:x} := random 0x{:02X}", x, byte),
+                Instruction::DRW_VX_VY_NIB => format!("sprite v{:x} v{:x} {}", x, y, value & 0xF),
+                Instruction::SKP_VX => format!("if v{:x} -key then", x),
+                Instruction::SKNP_VX => format!("if v{:x} key then", x),
+                Instruction::LD_VX_DT => format!("v{:x} := delay", x),
+                Instruction::LD_VX_K => format!("v{:x} := key", x),
+                Instruction::LD_DT_VX => format!("delay := v{:x}", x),
+                Instruction::LD_ST_VX => format!("buzzer := v{:x}", x),
+                Instruction::ADD_I_VX => format!("i += v{:x}", x),
+                Instruction::LD_F_VX => format!("i := hex v{:x}", x),
+                Instruction::LD_B_VX => format!("bcd v{:x}", x),
+                Instruction::LD_I_VX => format!("save v{:x}", x),
+                Instruction::LD_VX_I => format!("load v{:x}", x),
+                //octo has no machine code calls, keep the bytes
+                Instruction::SYS | Instruction::INVALID => format!("0x{:02X} 0x{:02X}", opcode >> 8, opcode & 0xFF),
+            },
+        }
+    }
+
+    fn write_label(out: &mut String, label: &str, syntax: Syntax) {
+        let _ = match syntax {
+            Syntax::Cowgod => writeln!(out, "{}:", label),
+            Syntax::Octo => writeln!(out, ": {}", label),
+        };
+    }
+
+    fn write_data(out: &mut String, bytes: &[u8], syntax: Syntax) {
+        let bytes: Vec<String> = bytes.iter().map(|byte| format!("0x{:02X}", byte)).collect();
+        let _ = match syntax {
+            Syntax::Cowgod => writeln!(out, "    db {}", bytes.join(", ")),
+            Syntax::Octo => writeln!(out, "    {}", bytes.join(" ")),
+        };
+    }
+
+    // the whole rom, assembling it gives back the same bytes
+    pub fn listing(&self, syntax: Syntax) -> String {
+        let mut out = String::new();
+        let mut addr = PROGRAM_LOAD_OFFSET as u16;
+        let end = self.rom_end() as u16;
+        while addr < end {
+            if let Some(label) = self.labels.get(&addr) {
+                Disassembly::write_label(&mut out, label, syntax);
+            }
+            if self.code.contains(&addr) {
+                let text = self.format_instruction(self.fetch(addr).unwrap(), syntax);
+                let _ = writeln!(out, "    {}", text);
+                addr += 2;
+                continue;
+            }
+            //data runs until the next label, instruction or line break
+            let mut run = vec![];
+            while addr < end && run.len() < BYTES_PER_LINE {
+                run.push(self.rom[addr as usize - PROGRAM_LOAD_OFFSET]);
+                addr += 1;
+                if self.code.contains(&addr) || self.labels.contains_key(&addr) {
+                    break;
+                }
+            }
+            Disassembly::write_data(&mut out, &run, syntax);
+        }
+        out
+    }
+}
+
+pub fn disassemble(rom: &[u8], syntax: Syntax) -> String {
+    Disassembly::new(rom).listing(syntax)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::disasm::{disassemble, Disassembly, Syntax};
+
+    fn rom() -> Vec<u8> {
+        vec![
+            // LD I, 0x20E
+            0xA2, 0x0E, //0x200
+            // CALL 0x20A
+            0x22, 0x0A, //0x202
+            // SE 0, 0x01
+            0x30, 0x01, //0x204
+            // JP 0x200
+            0x12, 0x00, //0x206
+            // JP 0x208
+            0x12, 0x08, //0x208
+            // DRW 0, 1, 3
+            0xD0, 0x13, //0x20A
+            // RET
+            0x00, 0xEE, //0x20C
+            // sprite
+            0xF0, 0x90, 0xF0, //0x20E
+        ]
+    }
+
+    #[test]
+    fn test_code_discovery() {
+        let disassembly = Disassembly::new(&rom());
+        let code: Vec<u16> = disassembly.code.iter().cloned().collect();
+        assert_eq!(vec![0x200, 0x202, 0x204, 0x206, 0x208, 0x20A, 0x20C], code);
+        assert_eq!(Some(&"data_20e".to_string()), disassembly.labels.get(&0x20E));
+        assert_eq!(Some(&"sub_20a".to_string()), disassembly.labels.get(&0x20A));
+        assert_eq!(Some(&"main".to_string()), disassembly.labels.get(&0x200));
+    }
+
+    #[test]
+    fn test_cowgod() {
+        let expected = "\
+main:
+    LD I, data_20e
+    CALL sub_20a
+    SE V0, 0x01
+    JP main
+label_208:
+    JP label_208
+sub_20a:
+    DRW V0, V1, 3
+    RET
+data_20e:
+    db 0xF0, 0x90, 0xF0
+";
+        assert_eq!(expected, disassemble(&rom(), Syntax::Cowgod));
+    }
+
+    #[test]
+    fn test_octo() {
+        let expected = "\
+: main
+    i := data_20e
+    sub_20a
+    if v0 != 0x01 then
+    jump main
+: label_208
+    jump label_208
+: sub_20a
+    sprite v0 v1 3
+    return
+: data_20e
+    0xF0 0x90 0xF0
+";
+        assert_eq!(expected, disassemble(&rom(), Syntax::Octo));
+    }
+
+    #[test]
+    fn test_data_and_misaligned_targets() {
+        let disassembly = Disassembly::new(&[
+            // JP 0x203
+            0x12, 0x03, //0x200
+            // data
+            0xFF, //0x202
+            // LD I, 0x204 -> inside this instruction
+            0xA2, 0x04, //0x203
+            // JP 0x205
+            0x12, 0x05, //0x205
+            // odd trailing byte
+            0x01, //0x207
+        ]);
+        let listing = disassembly.listing(Syntax::Cowgod);
+        assert_eq!("main:\n    JP label_203\n    db 0xFF\nlabel_203:\n    LD I, 0x204\nlabel_205:\n    JP label_205\n    db 0x01\n", listing);
+    }
+}
@@ -1,3 +1,56 @@
+pub const WIDTH: usize = 64;
+pub const HEIGHT: usize = 32;
+
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub struct Display {
+    //one byte per pixel, row major, 0 = off and 1 = on
+    pub pixels: [u8; WIDTH * HEIGHT],
+}
+
+impl Display {
+    pub fn new() -> Display {
+        Display {
+            pixels: [0; WIDTH * HEIGHT]
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.pixels = [0; WIDTH * HEIGHT];
+    }
+
+    pub fn get_pixel(&self, x: usize, y: usize) -> bool {
+        self.pixels[y * WIDTH + x] == 1
+    }
+
+    // xors the sprite onto the screen, the origin wraps around the edges
+    // while the sprite itself is clipped. returns true if a pixel was erased
+    pub fn draw(&mut self, x: u8, y: u8, sprite: &[u8]) -> bool {
+        let x = x as usize % WIDTH;
+        let y = y as usize % HEIGHT;
+        let mut collision = false;
+        for (row, bits) in sprite.iter().enumerate() {
+            if y + row >= HEIGHT {
+                break;
+            }
+            for col in 0..8 {
+                if x + col >= WIDTH {
+                    break;
+                }
+                if bits & (0x80 >> col) != 0 {
+                    let pixel = &mut self.pixels[(y + row) * WIDTH + x + col];
+                    if *pixel == 1 {
+                        collision = true;
+                    }
+                    *pixel ^= 1;
+                }
+            }
+        }
+        collision
+    }
+}
 
-}
\ No newline at end of file
+impl Default for Display {
+    fn default() -> Self {
+        Display::new()
+    }
+}
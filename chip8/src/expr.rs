@@ -0,0 +1,355 @@
+// Expressions over machine state, used for conditional breakpoints:
+//
+//   v3 == 0x10 && i > 0x300
+//   [i+2] != 0
+//
+// Operands are numbers (decimal or 0x prefixed hex), the registers v0-vf,
+// i, pc, sp, dt and st, and `[addr]` for the memory byte at addr. The
+// operators and their precedence follow C. Comparisons and logic evaluate
+// to 0 or 1, dividing by zero gives 0.
+use crate::cpu::CPU;
+use crate::memory::MEM_SIZE;
+use std::fmt;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Register {
+    V(u8),
+    I,
+    Pc,
+    Sp,
+    Dt,
+    St,
+}
+
+impl Register {
+    // case insensitive, v0-vf, i, pc, sp, dt and st
+    pub fn parse(name: &str) -> Option<Register> {
+        let name = name.to_ascii_lowercase();
+        match name.as_str() {
+            "i" => Some(Register::I),
+            "pc" => Some(Register::Pc),
+            "sp" => Some(Register::Sp),
+            "dt" => Some(Register::Dt),
+            "st" => Some(Register::St),
+            _ => {
+                let digit = name.strip_prefix('v')?;
+                if digit.len() != 1 {
+                    return None;
+                }
+                u8::from_str_radix(digit, 16).ok().map(Register::V)
+            }
+        }
+    }
+
+    pub fn read(&self, cpu: &CPU) -> usize {
+        match *self {
+            Register::V(reg) => cpu.read_register(reg as u32) as usize,
+            Register::I => cpu.get_i() as usize,
+            Register::Pc => cpu.get_pc() as usize,
+            Register::Sp => cpu.get_sp(),
+            Register::Dt => cpu.get_dt() as usize,
+            Register::St => cpu.get_st() as usize,
+        }
+    }
+}
+
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Register::V(reg) => write!(f, "v{:x}", reg),
+            Register::I => write!(f, "i"),
+            Register::Pc => write!(f, "pc"),
+            Register::Sp => write!(f, "sp"),
+            Register::Dt => write!(f, "dt"),
+            Register::St => write!(f, "st"),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum UnaryOp {
+    Not,
+    Negate,
+    Complement,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum BinaryOp {
+    Or,
+    And,
+    BitOr,
+    BitXor,
+    BitAnd,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Shl,
+    Shr,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+}
+
+// longer symbols first so "<=" is not read as "<"
+const BINARY_OPS: [(&str, BinaryOp, u8); 18] = [
+    ("||", BinaryOp::Or, 1),
+    ("&&", BinaryOp::And, 2),
+    ("==", BinaryOp::Eq, 6),
+    ("!=", BinaryOp::Ne, 6),
+    ("<=", BinaryOp::Le, 7),
+    (">=", BinaryOp::Ge, 7),
+    ("<<", BinaryOp::Shl, 8),
+    (">>", BinaryOp::Shr, 8),
+    ("|", BinaryOp::BitOr, 3),
+    ("^", BinaryOp::BitXor, 4),
+    ("&", BinaryOp::BitAnd, 5),
+    ("<", BinaryOp::Lt, 7),
+    (">", BinaryOp::Gt, 7),
+    ("+", BinaryOp::Add, 9),
+    ("-", BinaryOp::Sub, 9),
+    ("*", BinaryOp::Mul, 10),
+    ("/", BinaryOp::Div, 10),
+    ("%", BinaryOp::Rem, 10),
+];
+
+impl BinaryOp {
+    fn symbol(self) -> &'static str {
+        BINARY_OPS.iter().find(|(_, op, _)| *op == self).unwrap().0
+    }
+
+    fn apply(self, a: i64, b: i64) -> i64 {
+        match self {
+            BinaryOp::Or => (a != 0 || b != 0) as i64,
+            BinaryOp::And => (a != 0 && b != 0) as i64,
+            BinaryOp::BitOr => a | b,
+            BinaryOp::BitXor => a ^ b,
+            BinaryOp::BitAnd => a & b,
+            BinaryOp::Eq => (a == b) as i64,
+            BinaryOp::Ne => (a != b) as i64,
+            BinaryOp::Lt => (a < b) as i64,
+            BinaryOp::Le => (a <= b) as i64,
+            BinaryOp::Gt => (a > b) as i64,
+            BinaryOp::Ge => (a >= b) as i64,
+            BinaryOp::Shl => a.wrapping_shl(b as u32),
+            BinaryOp::Shr => a.wrapping_shr(b as u32),
+            BinaryOp::Add => a.wrapping_add(b),
+            BinaryOp::Sub => a.wrapping_sub(b),
+            BinaryOp::Mul => a.wrapping_mul(b),
+            BinaryOp::Div => a.checked_div(b).unwrap_or(0),
+            BinaryOp::Rem => a.checked_rem(b).unwrap_or(0),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Expr {
+    Number(i64),
+    Register(Register),
+    //byte at the address, wrapping at the end of memory
+    Memory(Box<Expr>),
+    Unary(UnaryOp, Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    pub fn parse(source: &str) -> Result<Expr, String> {
+        let mut parser = Parser { source, at: 0 };
+        let expr = parser.binary(0)?;
+        parser.skip_whitespace();
+        if parser.at < source.len() {
+            return Err(format!("unexpected {} at column {}", &source[parser.at..], parser.at + 1));
+        }
+        Ok(expr)
+    }
+
+    pub fn eval(&self, cpu: &CPU) -> i64 {
+        match self {
+            Expr::Number(value) => *value,
+            Expr::Register(register) => register.read(cpu) as i64,
+            Expr::Memory(addr) => {
+                let addr = addr.eval(cpu).rem_euclid(MEM_SIZE as i64) as usize;
+                cpu.memory.memory[addr] as i64
+            }
+            Expr::Unary(op, operand) => {
+                let value = operand.eval(cpu);
+                match op {
+                    UnaryOp::Not => (value == 0) as i64,
+                    UnaryOp::Negate => value.wrapping_neg(),
+                    UnaryOp::Complement => !value,
+                }
+            }
+            Expr::Binary(op, a, b) => op.apply(a.eval(cpu), b.eval(cpu)),
+        }
+    }
+
+    pub fn is_true(&self, cpu: &CPU) -> bool {
+        self.eval(cpu) != 0
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expr::Number(value) if *value > 9 => write!(f, "0x{:X}", value),
+            Expr::Number(value) => write!(f, "{}", value),
+            Expr::Register(register) => write!(f, "{}", register),
+            Expr::Memory(addr) => write!(f, "[{}]", addr),
+            Expr::Unary(op, operand) => {
+                let symbol = match op {
+                    UnaryOp::Not => '!',
+                    UnaryOp::Negate => '-',
+                    UnaryOp::Complement => '~',
+                };
+                match **operand {
+                    Expr::Binary(..) => write!(f, "{}({})", symbol, operand),
+                    _ => write!(f, "{}{}", symbol, operand),
+                }
+            }
+            Expr::Binary(op, a, b) => {
+                let operand = |f: &mut fmt::Formatter<'_>, e: &Expr| match e {
+                    Expr::Binary(..) => write!(f, "({})", e),
+                    _ => write!(f, "{}", e),
+                };
+                operand(f, a)?;
+                write!(f, " {} ", op.symbol())?;
+                operand(f, b)
+            }
+        }
+    }
+}
+
+struct Parser<'a> {
+    source: &'a str,
+    at: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn rest(&self) -> &'a str {
+        &self.source[self.at..]
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = self.rest();
+        self.at += rest.len() - rest.trim_start().len();
+    }
+
+    fn eat(&mut self, symbol: &str) -> bool {
+        self.skip_whitespace();
+        if self.rest().starts_with(symbol) {
+            self.at += symbol.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, symbol: &str) -> Result<(), String> {
+        if self.eat(symbol) {
+            Ok(())
+        } else {
+            Err(format!("expected {} at column {}", symbol, self.at + 1))
+        }
+    }
+
+    // precedence climbing, every operator is left associative
+    fn binary(&mut self, min_precedence: u8) -> Result<Expr, String> {
+        let mut left = self.unary()?;
+        loop {
+            self.skip_whitespace();
+            let rest = self.rest();
+            let next = BINARY_OPS.iter().find(|(symbol, _, _)| rest.starts_with(symbol));
+            match next {
+                Some(&(symbol, op, precedence)) if precedence > min_precedence => {
+                    self.at += symbol.len();
+                    let right = self.binary(precedence)?;
+                    left = Expr::Binary(op, Box::new(left), Box::new(right));
+                }
+                _ => return Ok(left),
+            }
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        let op = if self.eat("!") {
+            UnaryOp::Not
+        } else if self.eat("-") {
+            UnaryOp::Negate
+        } else if self.eat("~") {
+            UnaryOp::Complement
+        } else {
+            return self.primary();
+        };
+        Ok(Expr::Unary(op, Box::new(self.unary()?)))
+    }
+
+    fn primary(&mut self) -> Result<Expr, String> {
+        if self.eat("(") {
+            let expr = self.binary(0)?;
+            self.expect(")")?;
+            return Ok(expr);
+        }
+        if self.eat("[") {
+            let addr = self.binary(0)?;
+            self.expect("]")?;
+            return Ok(Expr::Memory(Box::new(addr)));
+        }
+        let start = self.at;
+        let word_len = self.rest().find(|c: char| !c.is_ascii_alphanumeric()).unwrap_or(self.rest().len());
+        let word = &self.rest()[..word_len];
+        if word.is_empty() {
+            return Err(format!("expected an operand at column {}", start + 1));
+        }
+        self.at += word_len;
+        if let Some(register) = Register::parse(word) {
+            return Ok(Expr::Register(register));
+        }
+        let number = match word.strip_prefix("0x").or_else(|| word.strip_prefix("0X")) {
+            Some(hex) => i64::from_str_radix(hex, 16),
+            None => word.parse(),
+        };
+        number.map(Expr::Number).map_err(|_| format!("unknown operand {} at column {}", word, start + 1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu::CPU;
+    use crate::expr::Expr;
+    use crate::memory::Memory;
+
+    #[test]
+    fn test_eval() {
+        let mut cpu = CPU::new(Memory::new());
+        cpu.write_register(0x3, 0x10);
+        cpu.set_i(0x301);
+        cpu.memory.memory[0x303] = 0x7;
+        let eval = |source: &str| Expr::parse(source).unwrap().eval(&cpu);
+        assert_eq!(1, eval("v3 == 0x10 && i > 0x300"));
+        assert_eq!(0, eval("V3 == 0x10 && I > 0x301"));
+        assert_eq!(1, eval("[i+2] != 0"));
+        assert_eq!(7, eval("[i + 2]"));
+        assert_eq!(14, eval("2 + 3 * 4"));
+        assert_eq!(20, eval("(2 + 3) * 4"));
+        assert_eq!(1, eval("1 | 2 == 2"));
+        assert_eq!(0, eval("!v3"));
+        assert_eq!(-17, eval("~v3"));
+        assert_eq!(0, eval("v3 / 0"));
+        assert_eq!(0xF0, eval("[0x1000]"));
+    }
+
+    #[test]
+    fn test_parse_errors_and_display() {
+        assert!(Expr::parse("v3 ==").is_err());
+        assert!(Expr::parse("(v3").is_err());
+        assert!(Expr::parse("vg").is_err());
+        assert!(Expr::parse("v3 v4").is_err());
+        let expr = Expr::parse("v3==0x10&&[i+2]!=0").unwrap();
+        assert_eq!("(v3 == 0x10) && ([i + 2] != 0)", expr.to_string());
+        assert_eq!(expr, Expr::parse(&expr.to_string()).unwrap());
+    }
+}
@@ -0,0 +1,640 @@
+// GDB remote serial protocol stub. The packet handling below does not know
+// about sockets, `serve` wires it up to a tcp connection.
+//
+// Registers are numbered V0-VF (0-15, 8 bit), I (16), PC (17) and SP, DT,
+// ST (18-20, 8 bit) and sent little endian, as described by TARGET_XML.
+use crate::cpu::CPU;
+use crate::debugger::{Debugger, StopReason};
+use crate::expr::Register;
+use crate::memory::MEM_SIZE;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+
+pub const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <architecture>chip8</architecture>
+  <feature name="org.chip8.core">
+    <reg name="v0" bitsize="8" regnum="0"/>
+    <reg name="v1" bitsize="8"/>
+    <reg name="v2" bitsize="8"/>
+    <reg name="v3" bitsize="8"/>
+    <reg name="v4" bitsize="8"/>
+    <reg name="v5" bitsize="8"/>
+    <reg name="v6" bitsize="8"/>
+    <reg name="v7" bitsize="8"/>
+    <reg name="v8" bitsize="8"/>
+    <reg name="v9" bitsize="8"/>
+    <reg name="va" bitsize="8"/>
+    <reg name="vb" bitsize="8"/>
+    <reg name="vc" bitsize="8"/>
+    <reg name="vd" bitsize="8"/>
+    <reg name="ve" bitsize="8"/>
+    <reg name="vf" bitsize="8"/>
+    <reg name="i" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+    <reg name="sp" bitsize="8"/>
+    <reg name="dt" bitsize="8"/>
+    <reg name="st" bitsize="8"/>
+  </feature>
+</target>
+"#;
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+//the rom ran into a fault, see cpu::Fault
+const SIGSEGV: u8 = 11;
+const REGISTER_COUNT: usize = 21;
+// instructions between two checks for an interrupt while continuing
+const CHUNK: u64 = 10_000;
+
+fn register(number: usize) -> Option<Register> {
+    match number {
+        0..=15 => Some(Register::V(number as u8)),
+        16 => Some(Register::I),
+        17 => Some(Register::Pc),
+        18 => Some(Register::Sp),
+        19 => Some(Register::Dt),
+        20 => Some(Register::St),
+        _ => None,
+    }
+}
+
+fn register_size(register: Register) -> usize {
+    match register {
+        Register::I | Register::Pc => 2,
+        _ => 1,
+    }
+}
+
+// registers are sent little endian
+fn register_value(bytes: &[u8]) -> usize {
+    bytes.iter().rev().fold(0, |value, &byte| value << 8 | byte as usize)
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte))
+}
+
+pub fn encode_packet(payload: &str) -> Vec<u8> {
+    let mut out = Vec::with_capacity(payload.len() + 4);
+    out.push(b'$');
+    for &byte in payload.as_bytes() {
+        //these would end or corrupt the packet
+        if matches!(byte, b'$' | b'#' | b'}' | b'*') {
+            out.push(b'}');
+            out.push(byte ^ 0x20);
+        } else {
+            out.push(byte);
+        }
+    }
+    let sum = checksum(&out[1..]);
+    out.extend_from_slice(format!("#{:02x}", sum).as_bytes());
+    out
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|at| u8::from_str_radix(hex.get(at..at + 2)?, 16).ok())
+        .collect()
+}
+
+fn hex_number(hex: &str) -> Option<usize> {
+    usize::from_str_radix(hex, 16).ok()
+}
+
+// "addr,len" as sent by m, M, Z and z
+fn address_and_length(args: &str) -> Option<(usize, usize)> {
+    let (addr, len) = args.split_once(',')?;
+    Some((hex_number(addr)?, hex_number(len)?))
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Event {
+    Packet(String),
+    //the packet was damaged, the sender should retransmit it
+    BadChecksum,
+    //0x03 sent outside of a packet
+    Interrupt,
+    Ack,
+    Nack,
+}
+
+// splits the incoming byte stream into events
+#[derive(Default)]
+pub struct PacketReader {
+    buffer: Vec<u8>,
+}
+
+impl PacketReader {
+    pub fn new() -> PacketReader {
+        PacketReader { buffer: vec![] }
+    }
+
+    pub fn feed(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    pub fn next_event(&mut self) -> Option<Event> {
+        loop {
+            let (&first, _) = self.buffer.split_first()?;
+            match first {
+                0x03 => {
+                    self.buffer.remove(0);
+                    return Some(Event::Interrupt);
+                }
+                b'+' => {
+                    self.buffer.remove(0);
+                    return Some(Event::Ack);
+                }
+                b'-' => {
+                    self.buffer.remove(0);
+                    return Some(Event::Nack);
+                }
+                b'$' => {
+                    let end = self.buffer.iter().position(|&byte| byte == b'#')?;
+                    if self.buffer.len() < end + 3 {
+                        return None;
+                    }
+                    let packet: Vec<u8> = self.buffer.drain(..end + 3).collect();
+                    let body = &packet[1..end];
+                    let expected = std::str::from_utf8(&packet[end + 1..]).ok().and_then(|sum| u8::from_str_radix(sum, 16).ok());
+                    if expected != Some(checksum(body)) {
+                        return Some(Event::BadChecksum);
+                    }
+                    let mut payload = Vec::with_capacity(body.len());
+                    let mut escaped = false;
+                    for &byte in body {
+                        if escaped {
+                            payload.push(byte ^ 0x20);
+                            escaped = false;
+                        } else if byte == b'}' {
+                            escaped = true;
+                        } else {
+                            payload.push(byte);
+                        }
+                    }
+                    return Some(Event::Packet(String::from_utf8_lossy(&payload).into_owned()));
+                }
+                //noise between packets
+                _ => {
+                    self.buffer.remove(0);
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Action {
+    Reply(String),
+    //run until a breakpoint or an interrupt, then send the stop reply
+    Continue,
+    //reply and close the connection
+    Detach(String),
+}
+
+pub struct GdbStub {
+    pub debugger: Debugger,
+}
+
+impl GdbStub {
+    pub fn new(cpu: CPU) -> GdbStub {
+        GdbStub { debugger: Debugger::new(cpu) }
+    }
+
+    fn read_register(&self, register: Register) -> Vec<u8> {
+        let value = register.read(&self.debugger.cpu) as u16;
+        value.to_le_bytes()[..register_size(register)].to_vec()
+    }
+
+
+    fn read_memory(&self, args: &str) -> String {
+        match address_and_length(args) {
+            Some((addr, _)) if addr >= MEM_SIZE => "E14".to_string(),
+            //short reads are fine, gdb asks again for the rest
+            Some((addr, len)) => to_hex(&self.debugger.cpu.memory.memory[addr..addr.saturating_add(len).min(MEM_SIZE)]),
+            None => "E01".to_string(),
+        }
+    }
+
+    fn write_memory(&mut self, args: &str) -> String {
+        let (range, data) = match args.split_once(':') {
+            Some(split) => split,
+            None => return "E01".to_string(),
+        };
+        match (address_and_length(range), from_hex(data)) {
+            (Some((addr, len)), Some(bytes)) if bytes.len() == len => {
+                match self.debugger.poke_memory(addr, &bytes) {
+                    Ok(()) => "OK".to_string(),
+                    Err(_) => "E14".to_string(),
+                }
+            }
+            _ => "E01".to_string(),
+        }
+    }
+
+    fn write_all_registers(&mut self, hex: &str) -> String {
+        let bytes = match from_hex(hex) {
+            Some(bytes) => bytes,
+            None => return "E01".to_string(),
+        };
+        let mut values = vec![];
+        let mut at = 0;
+        for number in 0..REGISTER_COUNT {
+            let register = register(number).unwrap();
+            let size = register_size(register);
+            if at + size > bytes.len() {
+                break;
+            }
+            values.push((register, register_value(&bytes[at..at + size])));
+            at += size;
+        }
+        match self.debugger.set_registers(&values) {
+            Ok(()) => "OK".to_string(),
+            Err(_) => "E01".to_string(),
+        }
+    }
+
+    // target.xml in chunks, "m" while more follows and "l" for the last one
+    fn read_features(&self, args: &str) -> String {
+        let (annex, range) = match args.split_once(':') {
+            Some(split) => split,
+            None => return "E01".to_string(),
+        };
+        if annex != "target.xml" {
+            return "E00".to_string();
+        }
+        match address_and_length(range) {
+            Some((offset, len)) => {
+                let xml = TARGET_XML.as_bytes();
+                let start = offset.min(xml.len());
+                let end = start.saturating_add(len).min(xml.len());
+                let chunk = String::from_utf8_lossy(&xml[start..end]);
+                format!("{}{}", if end < xml.len() { 'm' } else { 'l' }, chunk)
+            }
+            None => "E01".to_string(),
+        }
+    }
+
+    // handles one packet, execution requests are left to the caller
+    pub fn handle(&mut self, packet: &str) -> Action {
+        let (command, args) = packet.split_at(packet.chars().next().map(|c| c.len_utf8()).unwrap_or(0));
+        let reply = match command {
+            "?" => format!("S{:02x}", SIGTRAP),
+            "g" => (0..REGISTER_COUNT).map(|number| to_hex(&self.read_register(register(number).unwrap()))).collect(),
+            "G" => self.write_all_registers(args),
+            "p" => match hex_number(args).and_then(register) {
+                Some(register) => to_hex(&self.read_register(register)),
+                None => "E01".to_string(),
+            },
+            "P" => {
+                let parsed = args.split_once('=').and_then(|(number, value)| {
+                    Some((hex_number(number).and_then(register)?, from_hex(value)?))
+                });
+                match parsed {
+                    Some((register, bytes)) if self.debugger.set_registers(&[(register, register_value(&bytes))]).is_ok() => {
+                        "OK".to_string()
+                    }
+                    _ => "E01".to_string(),
+                }
+            }
+            "m" => self.read_memory(args),
+            "M" => self.write_memory(args),
+            "Z" | "z" => {
+                //only software breakpoints, anything else is unsupported
+                match args.strip_prefix("0,").and_then(address_and_length) {
+                    Some((addr, _)) if addr >= MEM_SIZE => "E01".to_string(),
+                    Some((addr, _)) => {
+                        if command == "Z" {
+                            self.debugger.breakpoints.insert(addr as u16);
+                        } else {
+                            self.debugger.breakpoints.remove(&(addr as u16));
+                        }
+                        "OK".to_string()
+                    }
+                    None => String::new(),
+                }
+            }
+            "s" | "c" => {
+                if let Some(addr) = hex_number(args) {
+                    self.debugger.cpu.set_pc(addr as u16);
+                }
+                if command == "c" {
+                    return Action::Continue;
+                }
+                match self.debugger.step() {
+                    StopReason::Fault { .. } => format!("S{:02x}", SIGSEGV),
+                    _ => format!("S{:02x}", SIGTRAP),
+                }
+            }
+            "b" => {
+                let reason = match args {
+                    "s" => self.debugger.reverse_step(),
+                    "c" => self.debugger.reverse_continue(),
+                    _ => return Action::Reply(String::new()),
+                };
+                match reason {
+                    StopReason::StartOfHistory => format!("T{:02x}replaylog:begin;", SIGTRAP),
+                    StopReason::BadSnapshot(_) => "E01".to_string(),
+                    _ => format!("S{:02x}", SIGTRAP),
+                }
+            }
+            "H" => "OK".to_string(),
+            "D" => return Action::Detach("OK".to_string()),
+            "k" => return Action::Detach(String::new()),
+            "q" => {
+                if args.starts_with("Supported") {
+                    "PacketSize=1000;qXfer:features:read+;swbreak+;ReverseStep+;ReverseContinue+".to_string()
+                } else if let Some(rest) = args.strip_prefix("Xfer:features:read:") {
+                    self.read_features(rest)
+                } else if args == "Attached" {
+                    "1".to_string()
+                } else {
+                    String::new()
+                }
+            }
+            //an empty reply tells gdb the packet is not supported
+            _ => String::new(),
+        };
+        Action::Reply(reply)
+    }
+
+    // runs until a breakpoint or until `interrupted` returns true and
+    // gives the stop reply. `interrupted` is polled between chunks
+    pub fn resume<F: FnMut() -> bool>(&mut self, mut interrupted: F) -> String {
+        let step_limit = self.debugger.step_limit;
+        self.debugger.step_limit = CHUNK;
+        let mut first = true;
+        let signal = loop {
+            //run_until never stops on the instruction it starts at
+            if !first && self.debugger.breakpoints.contains(&self.debugger.cpu.get_pc()) {
+                break SIGTRAP;
+            }
+            first = false;
+            match self.debugger.resume() {
+                StopReason::StepLimit => {}
+                //keep running, the rom is waiting for a key or spinning
+                StopReason::Halted(_) => thread::sleep(Duration::from_millis(1)),
+                StopReason::Fault { .. } => break SIGSEGV,
+                _ => break SIGTRAP,
+            }
+            if interrupted() {
+                break SIGINT;
+            }
+        };
+        self.debugger.step_limit = step_limit;
+        format!("S{:02x}", signal)
+    }
+}
+
+// serves one gdb connection until it detaches or disconnects
+pub fn serve(stream: &mut TcpStream, stub: &mut GdbStub) -> io::Result<()> {
+    let mut reader = PacketReader::new();
+    let mut buffer = [0u8; 4096];
+    loop {
+        let read = stream.read(&mut buffer)?;
+        if read == 0 {
+            return Ok(());
+        }
+        reader.feed(&buffer[..read]);
+        while let Some(event) = reader.next_event() {
+            match event {
+                Event::Packet(packet) => {
+                    stream.write_all(b"+")?;
+                    match stub.handle(&packet) {
+                        Action::Reply(reply) => stream.write_all(&encode_packet(&reply))?,
+                        Action::Continue => {
+                            let reply = continue_until_interrupt(stream, &mut reader, stub)?;
+                            stream.write_all(&encode_packet(&reply))?;
+                        }
+                        Action::Detach(reply) => {
+                            stream.write_all(&encode_packet(&reply))?;
+                            return Ok(());
+                        }
+                    }
+                }
+                Event::BadChecksum => stream.write_all(b"-")?,
+                Event::Interrupt => stream.write_all(&encode_packet(&format!("S{:02x}", SIGINT)))?,
+                //replies are not retransmitted, tcp does not lose them
+                Event::Ack | Event::Nack => {}
+            }
+        }
+    }
+}
+
+fn continue_until_interrupt(stream: &mut TcpStream, reader: &mut PacketReader, stub: &mut GdbStub) -> io::Result<String> {
+    stream.set_nonblocking(true)?;
+    let mut error = None;
+    let reply = stub.resume(|| {
+        let mut buffer = [0u8; 64];
+        match stream.read(&mut buffer) {
+            //a closed connection stops the rom too
+            Ok(0) => return true,
+            Ok(read) => reader.feed(&buffer[..read]),
+            Err(ref err) if err.kind() == ErrorKind::WouldBlock => {}
+            Err(err) => {
+                error = Some(err);
+                return true;
+            }
+        }
+        //only an interrupt is expected while running
+        let mut interrupted = false;
+        while let Some(event) = reader.next_event() {
+            interrupted |= event == Event::Interrupt;
+        }
+        interrupted
+    });
+    stream.set_nonblocking(false)?;
+    match error {
+        Some(err) => Err(err),
+        None => Ok(reply),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu::CPU;
+    use crate::gdb::{encode_packet, serve, Action, Event, GdbStub, PacketReader};
+    use crate::memory::Memory;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    fn stub() -> GdbStub {
+        let mut mem = Memory::new();
+        mem.load_program(&[
+            // LD 0, 0x2A
+            0x60, 0x2A, //0x200
+            // ADD 1, 0x01
+            0x71, 0x01, //0x202
+            // JP 0x202
+            0x12, 0x02, //0x204
+        ]);
+        GdbStub::new(CPU::new(mem))
+    }
+
+    fn reply(stub: &mut GdbStub, packet: &str) -> String {
+        match stub.handle(packet) {
+            Action::Reply(reply) => reply,
+            other => panic!("expected a reply to {}, got {:?}", packet, other),
+        }
+    }
+
+    #[test]
+    fn test_packets() {
+        let mut reader = PacketReader::new();
+        reader.feed(b"+$m200,2#");
+        assert_eq!(Some(Event::Ack), reader.next_event());
+        assert_eq!(None, reader.next_event());
+        reader.feed(b"5d\x03$g#00");
+        assert_eq!(Some(Event::Packet("m200,2".to_string())), reader.next_event());
+        assert_eq!(Some(Event::Interrupt), reader.next_event());
+        assert_eq!(Some(Event::BadChecksum), reader.next_event());
+        assert_eq!(b"$OK#9a".to_vec(), encode_packet("OK"));
+        reader.feed(&encode_packet("X#}"));
+        assert_eq!(Some(Event::Packet("X#}".to_string())), reader.next_event());
+    }
+
+    #[test]
+    fn test_registers_and_memory() {
+        let mut stub = stub();
+        assert_eq!("S05", reply(&mut stub, "s"));
+        let registers = reply(&mut stub, "g");
+        assert_eq!(21 * 2 + 2 * 2, registers.len());
+        assert!(registers.starts_with("2a00"));
+        assert_eq!("0202", reply(&mut stub, "p11"));
+        assert_eq!("OK", reply(&mut stub, "P10=0003"));
+        assert_eq!(0x300, stub.debugger.cpu.get_i());
+        assert_eq!("OK", reply(&mut stub, "Pf=07"));
+        assert_eq!(0x07, stub.debugger.cpu.read_register(0xF));
+        assert_eq!("E01", reply(&mut stub, "p15"));
+
+        let mut written = registers.clone();
+        written.replace_range(2..4, "11");
+        assert_eq!("OK", reply(&mut stub, &format!("G{}", written)));
+        assert_eq!(0x11, stub.debugger.cpu.read_register(0x1));
+        assert_eq!(0x000, stub.debugger.cpu.get_i());
+        //sp is the 19th register, after two bytes each for i and pc
+        let mut bad_sp = written.clone();
+        bad_sp.replace_range(2..4, "22");
+        bad_sp.replace_range(40..42, "11");
+        assert_eq!("E01", reply(&mut stub, &format!("G{}", bad_sp)));
+        assert_eq!(0x11, stub.debugger.cpu.read_register(0x1));
+
+        assert_eq!("602a7101", reply(&mut stub, "m200,4"));
+        assert_eq!("OK", reply(&mut stub, "M300,2:beef"));
+        assert_eq!([0xBE, 0xEF], stub.debugger.cpu.memory.memory[0x300..0x302]);
+        assert_eq!("00", reply(&mut stub, "mfff,8"));
+        assert_eq!("E14", reply(&mut stub, "m1000,1"));
+        assert_eq!((0x1000 - 0x10) * 2, reply(&mut stub, "m10,ffffffffffffffff").len());
+        assert_eq!("E14", reply(&mut stub, "Mffffffffffffffff,1:00"));
+        assert_eq!("E01", reply(&mut stub, "M300,2:be"));
+    }
+
+    #[test]
+    fn test_breakpoints_and_continue() {
+        let mut stub = stub();
+        assert_eq!("OK", reply(&mut stub, "Z0,204,2"));
+        assert_eq!(Action::Continue, stub.handle("c"));
+        assert_eq!("S05", stub.resume(|| false));
+        assert_eq!(0x204, stub.debugger.cpu.get_pc());
+        assert_eq!("S05", stub.resume(|| false));
+        assert_eq!(0x204, stub.debugger.cpu.get_pc());
+        assert_eq!(0x02, stub.debugger.cpu.read_register(0x1));
+
+        assert_eq!("S05", reply(&mut stub, "bc"));
+        assert_eq!(0x204, stub.debugger.cpu.get_pc());
+        assert_eq!(0x01, stub.debugger.cpu.read_register(0x1));
+        assert_eq!("S05", reply(&mut stub, "bs"));
+        assert_eq!(0x202, stub.debugger.cpu.get_pc());
+        assert_eq!("T05replaylog:begin;", reply(&mut stub, "bc"));
+        assert_eq!(0x200, stub.debugger.cpu.get_pc());
+
+        assert_eq!("OK", reply(&mut stub, "z0,204,2"));
+        assert_eq!("E01", reply(&mut stub, "Z0,1000,2"));
+        assert_eq!("E01", reply(&mut stub, "Z0,ffffffffffffffff,2"));
+        assert_eq!("", reply(&mut stub, "Z2,300,1"));
+        let mut polls = 0;
+        assert_eq!("S02", stub.resume(|| {
+            polls += 1;
+            polls == 3
+        }));
+        assert!(matches!(stub.debugger.cpu.get_pc(), 0x202 | 0x204));
+        assert!(stub.debugger.cycles > 2 * 10_000);
+    }
+
+    #[test]
+    fn test_faults() {
+        let mut mem = Memory::new();
+        mem.load_program(&[
+            // ADD 1, 0x01
+            0x71, 0x01, //0x200
+            // RET
+            0x00, 0xEE, //0x202
+        ]);
+        let mut stub = GdbStub::new(CPU::new(mem));
+        assert_eq!("S05", reply(&mut stub, "s"));
+        assert_eq!("S0b", reply(&mut stub, "s"));
+        assert_eq!(0x202, stub.debugger.cpu.get_pc());
+        assert_eq!(Action::Continue, stub.handle("c200"));
+        assert_eq!("S0b", stub.resume(|| false));
+        assert_eq!(0x202, stub.debugger.cpu.get_pc());
+        assert_eq!(0x02, stub.debugger.cpu.read_register(0x1));
+    }
+
+    #[test]
+    fn test_target_description() {
+        let mut stub = stub();
+        assert!(reply(&mut stub, "qSupported:multiprocess+;xmlRegisters=i386").contains("qXfer:features:read+"));
+        let first = reply(&mut stub, "qXfer:features:read:target.xml:0,a");
+        assert_eq!("m<?xml vers", first);
+        let all = reply(&mut stub, "qXfer:features:read:target.xml:0,1000");
+        assert!(all.starts_with("l<?xml"));
+        assert!(all.contains(r#"<reg name="vf" bitsize="8"/>"#));
+        assert_eq!("", reply(&mut stub, "vMustReplyEmpty"));
+    }
+
+    // sends `packet` and reads until the reply ends with `expected`
+    fn exchange(client: &mut TcpStream, packet: &[u8], expected: &[u8]) {
+        client.write_all(packet).unwrap();
+        let mut received = vec![];
+        while !received.ends_with(expected) {
+            let mut buffer = [0u8; 256];
+            let read = client.read(&mut buffer).unwrap();
+            assert!(read > 0);
+            received.extend_from_slice(&buffer[..read]);
+        }
+    }
+
+    #[test]
+    fn test_serve() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut stub = stub();
+            serve(&mut stream, &mut stub).unwrap();
+            stub.debugger.cpu.get_pc()
+        });
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        exchange(&mut client, &encode_packet("Z0,204,2"), b"$OK#9a");
+        exchange(&mut client, &encode_packet("c"), b"$S05#b8");
+        exchange(&mut client, &encode_packet("z0,204,2"), b"$OK#9a");
+        client.write_all(&encode_packet("c")).unwrap();
+        exchange(&mut client, b"\x03", b"$S02#b5");
+        exchange(&mut client, &encode_packet("D"), b"$OK#9a");
+        assert!(server.join().unwrap() >= 0x202);
+    }
+}
@@ -0,0 +1,366 @@
+// Static control flow analysis of a ROM.
+//
+// Code is found by the disassembler. Every CALL target and the load address
+// start a subroutine, which is split into basic blocks: a block ends at a
+// jump, a return, a skip or right before another block starts. A JP V0 target
+// depends on V0, so those blocks end without successors and are reported as
+// unresolved.
+//
+// Both the control flow graphs and the call graph export as Graphviz DOT,
+// the JSON export holds everything.
+use crate::disasm::{Disassembly, Syntax};
+use crate::instructions::Instruction;
+use crate::memory::PROGRAM_LOAD_OFFSET;
+use crate::platform::{instruction_len, Platform};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum EdgeKind {
+    Fallthrough,
+    Jump,
+    //taken when a skip instruction skips
+    Skip,
+}
+
+impl EdgeKind {
+    fn name(self) -> &'static str {
+        match self {
+            EdgeKind::Fallthrough => "fallthrough",
+            EdgeKind::Jump => "jump",
+            EdgeKind::Skip => "skip",
+        }
+    }
+}
+
+// how a block without successors leaves
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Exit {
+    Return,
+    //JP V0, the target is only known at run time
+    Unresolved,
+    //super-chip exit
+    Halt,
+    //runs into an invalid opcode or off the end of the rom
+    Invalid,
+}
+
+impl Exit {
+    fn name(self) -> &'static str {
+        match self {
+            Exit::Return => "return",
+            Exit::Unresolved => "unresolved",
+            Exit::Halt => "halt",
+            Exit::Invalid => "invalid",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Block {
+    pub start: u16,
+    //address after the last instruction
+    pub end: u16,
+    pub successors: Vec<(u16, EdgeKind)>,
+    pub exit: Option<Exit>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Subroutine {
+    pub entry: u16,
+    pub name: String,
+    pub blocks: BTreeMap<u16, Block>,
+    //entries of the subroutines it calls
+    pub calls: BTreeSet<u16>,
+}
+
+pub struct Analysis {
+    pub disassembly: Disassembly,
+    pub subroutines: BTreeMap<u16, Subroutine>,
+    //addresses of JP V0 instructions
+    pub unresolved: BTreeSet<u16>,
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+impl Analysis {
+    pub fn new(rom: &[u8]) -> Analysis {
+        Analysis::for_platform(rom, Platform::Chip8)
+    }
+
+    pub fn for_platform(rom: &[u8], platform: Platform) -> Analysis {
+        let disassembly = Disassembly::for_platform(rom, platform);
+        let mut entries = BTreeSet::new();
+        if disassembly.code.contains(&(PROGRAM_LOAD_OFFSET as u16)) {
+            entries.insert(PROGRAM_LOAD_OFFSET as u16);
+        }
+        let mut unresolved = BTreeSet::new();
+        for &addr in disassembly.code.iter() {
+            let (instr, value) = Instruction::decode(disassembly.fetch(addr).unwrap() as u32);
+            match instr {
+                Instruction::CALL if disassembly.code.contains(&((value & 0xFFF) as u16)) => {
+                    entries.insert((value & 0xFFF) as u16);
+                }
+                Instruction::JP_V0_ADDR => {
+                    unresolved.insert(addr);
+                }
+                _ => {}
+            }
+        }
+        let mut analysis = Analysis { disassembly, subroutines: BTreeMap::new(), unresolved };
+        for entry in entries {
+            let subroutine = analysis.subroutine(entry);
+            analysis.subroutines.insert(entry, subroutine);
+        }
+        analysis
+    }
+
+    // where the instruction at addr continues within its subroutine, or how
+    // the subroutine is left
+    fn successors(&self, addr: u16) -> (Vec<(u16, EdgeKind)>, Option<Exit>) {
+        let opcode = match self.disassembly.fetch(addr) {
+            Some(opcode) if self.disassembly.code.contains(&addr) => opcode,
+            _ => return (vec![], Some(Exit::Invalid)),
+        };
+        let (instr, value) = Instruction::decode(opcode as u32);
+        match instr {
+            Instruction::JP => (vec![((value & 0xFFF) as u16, EdgeKind::Jump)], None),
+            Instruction::RET => (vec![], Some(Exit::Return)),
+            Instruction::JP_V0_ADDR => (vec![], Some(Exit::Unresolved)),
+            Instruction::SE_VX_BT
+            | Instruction::SNE_VX_BT
+            | Instruction::SE_VX_VY
+            | Instruction::SNE_VX_VY
+            | Instruction::SKP_VX
+            | Instruction::SKNP_VX => (vec![(addr + 2, EdgeKind::Fallthrough), (addr + 4, EdgeKind::Skip)], None),
+            //only extension opcodes of the platform are code
+            Instruction::INVALID if opcode == 0x00FD => (vec![], Some(Exit::Halt)),
+            _ => (vec![(addr + instruction_len(opcode), EdgeKind::Fallthrough)], None),
+        }
+    }
+
+    fn subroutine(&self, entry: u16) -> Subroutine {
+        //instructions reachable without returning, and where blocks start
+        let mut reached = BTreeSet::new();
+        let mut leaders = BTreeSet::new();
+        let mut calls = BTreeSet::new();
+        leaders.insert(entry);
+        let mut work = vec![entry];
+        while let Some(addr) = work.pop() {
+            if !reached.insert(addr) {
+                continue;
+            }
+            if let Some(opcode) = self.disassembly.fetch(addr) {
+                if Instruction::decode(opcode as u32).0 == Instruction::CALL {
+                    calls.insert(opcode & 0xFFF);
+                }
+            }
+            let (next, _) = self.successors(addr);
+            let ends_block = !matches!(next.as_slice(), [(_, EdgeKind::Fallthrough)]);
+            for &(to, _) in next.iter() {
+                if ends_block {
+                    leaders.insert(to);
+                }
+                work.push(to);
+            }
+        }
+
+        let mut blocks = BTreeMap::new();
+        for &start in leaders.iter() {
+            let mut addr = start;
+            let block = loop {
+                let (next, exit) = self.successors(addr);
+                //only a plain fall through into a non leader continues the block
+                if let [(to, EdgeKind::Fallthrough)] = next.as_slice() {
+                    if !leaders.contains(to) {
+                        addr = *to;
+                        continue;
+                    }
+                }
+                //an invalid opcode is not part of the block
+                let end = match (exit, next.first()) {
+                    (Some(Exit::Invalid), _) => addr,
+                    (_, Some((to, EdgeKind::Fallthrough))) => *to,
+                    _ => addr + 2,
+                };
+                break Block { start, end, successors: next, exit };
+            };
+            blocks.insert(start, block);
+        }
+
+        let name = self.disassembly.labels.get(&entry).cloned().unwrap_or_else(|| format!("sub_{:03x}", entry));
+        Subroutine { entry, name, blocks, calls }
+    }
+
+    // subroutine entry to the entries it calls
+    pub fn call_graph(&self) -> BTreeMap<u16, BTreeSet<u16>> {
+        self.subroutines.iter().map(|(&entry, subroutine)| (entry, subroutine.calls.clone())).collect()
+    }
+
+    fn name(&self, entry: u16) -> String {
+        match self.subroutines.get(&entry) {
+            Some(subroutine) => subroutine.name.clone(),
+            None => format!("0x{:03X}", entry),
+        }
+    }
+
+    fn instructions(&self, block: &Block) -> Vec<String> {
+        let mut lines = vec![];
+        let mut addr = block.start;
+        while addr < block.end {
+            let opcode = self.disassembly.fetch(addr).unwrap();
+            lines.push(format!("0x{:03X}: {}", addr, self.disassembly.format_instruction(opcode, Syntax::Cowgod)));
+            addr += instruction_len(opcode);
+        }
+        lines
+    }
+
+    // one cluster per subroutine, blocks list their instructions
+    pub fn cfg_dot(&self) -> String {
+        let mut out = String::new();
+        writeln!(out, "digraph cfg {{").unwrap();
+        writeln!(out, "    node [shape=box fontname=monospace];").unwrap();
+        for subroutine in self.subroutines.values() {
+            writeln!(out, "    subgraph cluster_{:03x} {{", subroutine.entry).unwrap();
+            writeln!(out, "        label=\"{}\";", escape(&subroutine.name)).unwrap();
+            for block in subroutine.blocks.values() {
+                let mut text: String = self.instructions(block).iter().map(|line| escape(line) + "\\l").collect();
+                if let Some(exit) = block.exit {
+                    text += &format!("[{}]\\l", exit.name());
+                }
+                let style = if block.exit == Some(Exit::Unresolved) { " color=red" } else { "" };
+                writeln!(out, "        b{:03x}_{:03x} [label=\"{}\"{}];", subroutine.entry, block.start, text, style).unwrap();
+            }
+            for block in subroutine.blocks.values() {
+                for &(to, kind) in block.successors.iter() {
+                    let style = match kind {
+                        EdgeKind::Fallthrough => "",
+                        EdgeKind::Jump => " [style=bold]",
+                        EdgeKind::Skip => " [style=dashed]",
+                    };
+                    writeln!(out, "        b{:03x}_{:03x} -> b{:03x}_{:03x}{};", subroutine.entry, block.start, subroutine.entry, to, style)
+                        .unwrap();
+                }
+            }
+            writeln!(out, "    }}").unwrap();
+        }
+        writeln!(out, "}}").unwrap();
+        out
+    }
+
+    pub fn call_graph_dot(&self) -> String {
+        let mut out = String::new();
+        writeln!(out, "digraph calls {{").unwrap();
+        for (&entry, calls) in self.call_graph().iter() {
+            writeln!(out, "    \"{}\";", escape(&self.name(entry))).unwrap();
+            for &callee in calls.iter() {
+                writeln!(out, "    \"{}\" -> \"{}\";", escape(&self.name(entry)), escape(&self.name(callee))).unwrap();
+            }
+        }
+        writeln!(out, "}}").unwrap();
+        out
+    }
+
+    // addresses are plain numbers
+    pub fn to_json(&self) -> String {
+        let list = |items: Vec<String>| format!("[{}]", items.join(","));
+        let subroutines = self.subroutines.values().map(|subroutine| {
+            let blocks = subroutine.blocks.values().map(|block| {
+                let successors = block.successors.iter()
+                    .map(|(to, kind)| format!("{{\"to\":{},\"kind\":\"{}\"}}", to, kind.name()))
+                    .collect();
+                let instructions = self.instructions(block).iter().map(|line| format!("\"{}\"", escape(line))).collect();
+                let exit = block.exit.map(|exit| format!("\"{}\"", exit.name())).unwrap_or_else(|| "null".to_string());
+                format!(
+                    "{{\"start\":{},\"end\":{},\"instructions\":{},\"successors\":{},\"exit\":{}}}",
+                    block.start,
+                    block.end,
+                    list(instructions),
+                    list(successors),
+                    exit
+                )
+            });
+            format!(
+                "{{\"name\":\"{}\",\"entry\":{},\"calls\":{},\"blocks\":{}}}",
+                escape(&subroutine.name),
+                subroutine.entry,
+                list(subroutine.calls.iter().map(|call| call.to_string()).collect()),
+                list(blocks.collect())
+            )
+        });
+        format!(
+            "{{\"subroutines\":{},\"unresolved\":{}}}\n",
+            list(subroutines.collect()),
+            list(self.unresolved.iter().map(|addr| addr.to_string()).collect())
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::asm::assemble;
+    use crate::graph::{Analysis, EdgeKind, Exit};
+    use std::collections::BTreeSet;
+
+    fn rom() -> Vec<u8> {
+        assemble("
+            main:
+                LD V0, 0
+            again:
+                CALL draw
+                SE V0, 5
+                JP again
+                JP V0, 0x300
+            draw:
+                ADD V0, 1
+                SKP V1
+                CALL beep
+                RET
+            beep:
+                LD ST, V0
+                RET
+        ")
+        .unwrap()
+    }
+
+    #[test]
+    fn test_blocks() {
+        let analysis = Analysis::new(&rom());
+        assert_eq!(vec![0x200, 0x20A, 0x212], analysis.subroutines.keys().cloned().collect::<Vec<u16>>());
+        assert_eq!(BTreeSet::from([0x212]), analysis.call_graph()[&0x20A]);
+
+        let main = &analysis.subroutines[&0x200];
+        assert_eq!("main", main.name);
+        assert_eq!(vec![0x200, 0x202, 0x206, 0x208], main.blocks.keys().cloned().collect::<Vec<u16>>());
+        assert_eq!(vec![(0x202, EdgeKind::Fallthrough)], main.blocks[&0x200].successors);
+        assert_eq!(vec![(0x206, EdgeKind::Fallthrough), (0x208, EdgeKind::Skip)], main.blocks[&0x202].successors);
+        assert_eq!(vec![(0x202, EdgeKind::Jump)], main.blocks[&0x206].successors);
+        assert_eq!(Some(Exit::Unresolved), main.blocks[&0x208].exit);
+        assert_eq!(BTreeSet::from([0x208]), analysis.unresolved);
+
+        let draw = &analysis.subroutines[&0x20A];
+        assert_eq!("sub_20a", draw.name);
+        assert_eq!(vec![0x20A, 0x20E, 0x210], draw.blocks.keys().cloned().collect::<Vec<u16>>());
+        assert_eq!(0x20E, draw.blocks[&0x20A].end);
+        assert_eq!(Some(Exit::Return), draw.blocks[&0x210].exit);
+    }
+
+    #[test]
+    fn test_exports() {
+        let analysis = Analysis::new(&rom());
+        let dot = analysis.cfg_dot();
+        assert!(dot.contains("subgraph cluster_200 {"));
+        assert!(dot.contains("b200_202 -> b200_208 [style=dashed];"));
+        assert!(dot.contains("b200_208 [label=\"0x208: JP V0, 0x300\\l[unresolved]\\l\" color=red];"));
+        let calls = analysis.call_graph_dot();
+        assert!(calls.contains("\"main\" -> \"sub_20a\";"));
+        assert!(calls.contains("\"sub_20a\" -> \"sub_212\";"));
+
+        let json = analysis.to_json();
+        assert!(json.starts_with("{\"subroutines\":[{\"name\":\"main\",\"entry\":512,\"calls\":[522],\"blocks\":[{\"start\":512,"));
+        assert!(json.contains("{\"start\":530,\"end\":534,\"instructions\":[\"0x212: LD ST, V0\",\"0x214: RET\"],\"successors\":[],\"exit\":\"return\"}"));
+        assert!(json.ends_with("\"unresolved\":[520]}\n"));
+    }
+}
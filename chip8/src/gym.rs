@@ -0,0 +1,247 @@
+use crate::cpu::{Quirks, CPU, CYCLES_PER_FRAME};
+use crate::memory::{Memory, MEM_SIZE};
+
+pub type RewardFn = Box<dyn Fn(&CPU, &CPU) -> f32 + Send>;
+pub type DoneFn = Box<dyn Fn(&CPU) -> bool + Send>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Step {
+    //the framebuffer, one byte per pixel
+    pub observation: Vec<u8>,
+    pub reward: f32,
+    pub done: bool,
+    pub frame: u64,
+}
+
+// gym style wrapper around a single machine. every action is a keypad mask
+// that is held down for `frames_per_step` frames
+pub struct Environment {
+    rom: Vec<u8>,
+    pub cpu: CPU,
+    pub actions: Vec<u16>,
+    pub cycles_per_frame: usize,
+    pub frames_per_step: usize,
+    pub max_frames: Option<u64>,
+    //applied to the machine on every reset
+    pub quirks: Quirks,
+    frame: u64,
+    reward: RewardFn,
+    done: DoneFn,
+}
+
+impl Environment {
+    pub fn new(rom: &[u8], actions: Vec<u16>) -> Environment {
+        let mut env = Environment {
+            rom: rom.to_vec(),
+            cpu: CPU::new(Memory::new()),
+            actions,
+            cycles_per_frame: CYCLES_PER_FRAME,
+            frames_per_step: 1,
+            max_frames: None,
+            quirks: Quirks::default(),
+            frame: 0,
+            reward: Box::new(|_, _| 0.0),
+            done: Box::new(|_| false),
+        };
+        env.reset(0);
+        env
+    }
+
+    // one action per key plus a no-op at index 0
+    pub fn with_all_keys(rom: &[u8]) -> Environment {
+        let mut actions = vec![0x0];
+        actions.extend((0..16).map(|key| 1 << key));
+        Environment::new(rom, actions)
+    }
+
+    // called with the machine state before and after every step
+    pub fn with_reward<F: Fn(&CPU, &CPU) -> f32 + Send + 'static>(mut self, reward: F) -> Environment {
+        self.reward = Box::new(reward);
+        self
+    }
+
+    pub fn with_quirks(mut self, quirks: Quirks) -> Environment {
+        self.quirks = quirks;
+        self.cpu.quirks = quirks;
+        self
+    }
+
+    pub fn with_termination<F: Fn(&CPU) -> bool + Send + 'static>(mut self, done: F) -> Environment {
+        self.done = Box::new(done);
+        self
+    }
+
+    pub fn action_count(&self) -> usize {
+        self.actions.len()
+    }
+
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    pub fn observation(&self) -> Vec<u8> {
+        self.cpu.display.pixels.to_vec()
+    }
+
+    pub fn reset(&mut self, seed: u64) -> Vec<u8> {
+        let mut mem = Memory::new();
+        mem.load_program(&self.rom);
+        self.cpu = CPU::new(mem);
+        self.cpu.quirks = self.quirks;
+        self.cpu.seed_rng(seed);
+        self.frame = 0;
+        self.observation()
+    }
+
+    pub fn step(&mut self, action: usize) -> Step {
+        assert!(action < self.actions.len(), "action {} out of range, there are {} actions", action, self.actions.len());
+        let before = self.cpu.clone();
+        self.cpu.keys = self.actions[action];
+        for _ in 0..self.frames_per_step {
+            self.cpu.run_frame(self.cycles_per_frame);
+            self.frame += 1;
+        }
+        let truncated = self.max_frames.map(|max| self.frame >= max).unwrap_or(false);
+        Step {
+            observation: self.observation(),
+            reward: (self.reward)(&before, &self.cpu),
+            done: truncated || (self.done)(&self.cpu),
+            frame: self.frame,
+        }
+    }
+}
+
+// reads a big endian bcd number, one digit per byte as written by LD B, Vx
+pub fn read_bcd(cpu: &CPU, addr: usize, digits: usize) -> u32 {
+    (0..digits).fold(0, |acc, digit| {
+        acc * 10 + cpu.memory.memory[(addr + digit) % MEM_SIZE] as u32
+    })
+}
+
+// rewards the increase of a bcd score stored at `addr`
+pub fn bcd_score_reward(addr: usize, digits: usize) -> impl Fn(&CPU, &CPU) -> f32 {
+    move |before, after| read_bcd(after, addr, digits) as f32 - read_bcd(before, addr, digits) as f32
+}
+
+// rewards the increase of a single byte counter, `addr` wraps around the
+// end of memory like in read_bcd
+pub fn byte_reward(addr: usize) -> impl Fn(&CPU, &CPU) -> f32 {
+    let addr = addr % MEM_SIZE;
+    move |before, after| after.memory.memory[addr] as f32 - before.memory.memory[addr] as f32
+}
+
+pub fn byte_equals(addr: usize, value: u8) -> impl Fn(&CPU) -> bool {
+    let addr = addr % MEM_SIZE;
+    move |cpu| cpu.memory.memory[addr] == value
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu::Quirks;
+    use crate::gym::{bcd_score_reward, byte_equals, byte_reward, read_bcd, Environment};
+    use crate::memory::MEM_SIZE;
+
+    // adds the pressed key to a score at 0x302 that is also stored as bcd
+    // at 0x310, then waits for the key to be released
+    fn scoring_rom() -> Vec<u8> {
+        vec![
+            // LD 1, K
+            0xF1, 0x0A, //0x200
+            // ADD 2, 1
+            0x82, 0x14, //0x202
+            // LD I, 0x310
+            0xA3, 0x10, //0x204
+            // LD B, 2
+            0xF2, 0x33, //0x206
+            // LD I, 0x300
+            0xA3, 0x00, //0x208
+            // LD [I], 2
+            0xF2, 0x55, //0x20A
+            // SKNP 1
+            0xE1, 0xA1, //0x20C
+            // JP 0x20C
+            0x12, 0x0C, //0x20E
+            // JP 0x200
+            0x12, 0x00, //0x210
+        ]
+    }
+
+    #[test]
+    fn test_step_reward_and_termination() {
+        let mut env = Environment::with_all_keys(&scoring_rom())
+            .with_reward(bcd_score_reward(0x310, 3))
+            .with_termination(byte_equals(0x302, 0x0C));
+        assert_eq!(17, env.action_count());
+        assert_eq!(64 * 32, env.reset(7).len());
+
+        let step = env.step(1 + 5);
+        assert_eq!(5.0, step.reward);
+        assert!(!step.done);
+        let step = env.step(0);
+        assert_eq!(0.0, step.reward);
+        let step = env.step(1 + 4);
+        assert_eq!(4.0, step.reward);
+        let step = env.step(0);
+        assert_eq!(0.0, step.reward);
+        let step = env.step(1 + 3);
+        assert_eq!(3.0, step.reward);
+        assert!(step.done);
+        assert_eq!(12, read_bcd(&env.cpu, 0x310, 3));
+        assert_eq!(5, step.frame);
+
+        env.reset(7);
+        assert_eq!(0, env.frame());
+        assert_eq!(0, read_bcd(&env.cpu, 0x310, 3));
+    }
+
+    #[test]
+    fn test_seeded_reset() {
+        let rom = vec![
+            // RND 0, 0xFF
+            0xC0, 0xFF, //0x200
+            // LD I, 0x300
+            0xA3, 0x00, //0x202
+            // LD [I], 0
+            0xF0, 0x55, //0x204
+            // JP 0x200
+            0x12, 0x00, //0x206
+        ];
+        let mut env = Environment::with_all_keys(&rom);
+        let run = |env: &mut Environment, seed| {
+            env.reset(seed);
+            (0..8).map(|_| { env.step(0); env.cpu.memory.memory[0x300] }).collect::<Vec<u8>>()
+        };
+        let first = run(&mut env, 11);
+        assert_eq!(first, run(&mut env, 11));
+        assert_ne!(first, run(&mut env, 12));
+    }
+
+    #[test]
+    fn test_quirks_survive_reset() {
+        let quirks = Quirks {
+            shift_uses_vy: true,
+            ..Quirks::default()
+        };
+        let mut env = Environment::with_all_keys(&scoring_rom()).with_quirks(quirks);
+        assert_eq!(quirks, env.cpu.quirks);
+        env.reset(3);
+        assert_eq!(quirks, env.cpu.quirks);
+    }
+
+    #[test]
+    #[should_panic(expected = "action 17 out of range, there are 17 actions")]
+    fn test_invalid_action() {
+        Environment::with_all_keys(&scoring_rom()).step(17);
+    }
+
+    #[test]
+    fn test_wrapping_addresses() {
+        let mut env = Environment::with_all_keys(&scoring_rom());
+        let before = env.cpu.clone();
+        env.cpu.memory.memory[0x300] = 3;
+        env.cpu.memory.memory[0x301] = 4;
+        assert_eq!(34, read_bcd(&env.cpu, MEM_SIZE + 0x300, 2));
+        assert_eq!(3.0, byte_reward(MEM_SIZE + 0x300)(&before, &env.cpu));
+        assert!(byte_equals(MEM_SIZE + 0x301, 4)(&env.cpu));
+    }
+}
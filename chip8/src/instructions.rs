@@ -28,6 +28,7 @@ pub enum Instruction {
     SKP_VX,
     SKNP_VX,
     LD_VX_DT,
+    LD_VX_K,
     LD_DT_VX,
     LD_ST_VX,
     ADD_I_VX,
@@ -67,7 +68,7 @@ impl Instruction {
             (0xE09E, 0xF0FF, Instruction::SKP_VX),
             (0xE0A1, 0xF0FF, Instruction::SKNP_VX),
             (0xF007, 0xF0FF, Instruction::LD_VX_DT),
-            (0xF00A, 0xF0FF, Instruction::LD_VX_BT),
+            (0xF00A, 0xF0FF, Instruction::LD_VX_K),
             (0xF015, 0xF0FF, Instruction::LD_DT_VX),
             (0xF018, 0xF0FF, Instruction::LD_ST_VX),
             (0xF01E, 0xF0FF, Instruction::ADD_I_VX),
@@ -88,6 +89,7 @@ impl Instruction {
 }
 
 #[cfg(test)]
+#[allow(clippy::mixed_case_hex_literals)]
 mod tests {
     use crate::instructions::Instruction;
 
@@ -123,7 +125,7 @@ mod tests {
         assert_eq!(Instruction::SKP_VX, Instruction::decode(0xE19E).0);
         assert_eq!(Instruction::SKNP_VX, Instruction::decode(0xEAA1).0);
         assert_eq!(Instruction::LD_VX_DT, Instruction::decode(0xFA07).0);
-        assert_eq!(Instruction::LD_VX_BT, Instruction::decode(0xFA0A).0);
+        assert_eq!(Instruction::LD_VX_K, Instruction::decode(0xFA0A).0);
         assert_eq!(Instruction::LD_DT_VX, Instruction::decode(0xFA15).0);
         assert_eq!(Instruction::LD_ST_VX, Instruction::decode(0xFA18).0);
         assert_eq!(Instruction::ADD_I_VX, Instruction::decode(0xFA1E).0);
@@ -1,4 +1,53 @@
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
 pub mod cpu;
 pub mod memory;
 pub mod instructions;
 pub mod display;
+pub mod backend;
+pub mod rng;
+pub mod access;
+pub mod platform;
+pub mod coverage;
+#[cfg(feature = "std")]
+pub mod recompiler;
+#[cfg(feature = "std")]
+pub mod batch;
+#[cfg(feature = "std")]
+pub mod gym;
+#[cfg(feature = "std")]
+pub mod savestate;
+#[cfg(feature = "std")]
+pub mod rewind;
+#[cfg(feature = "std")]
+pub mod movie;
+#[cfg(feature = "std")]
+pub mod tas;
+#[cfg(feature = "std")]
+pub mod debugger;
+#[cfg(feature = "std")]
+pub mod expr;
+#[cfg(feature = "std")]
+pub mod gdb;
+#[cfg(feature = "std")]
+pub mod trace;
+#[cfg(feature = "std")]
+pub mod tracelog;
+#[cfg(feature = "std")]
+pub mod profile;
+#[cfg(feature = "std")]
+pub mod disasm;
+#[cfg(feature = "std")]
+pub mod graph;
+#[cfg(feature = "std")]
+pub mod lint;
+#[cfg(feature = "std")]
+pub mod detect;
+#[cfg(feature = "std")]
+pub mod decompile;
+#[cfg(feature = "std")]
+pub mod asm;
+#[cfg(feature = "std")]
+pub mod octo;
+#[cfg(all(test, feature = "std"))]
+mod testing;
@@ -0,0 +1,257 @@
+// Static checks for a ROM before it is published. Only code the
+// disassembler reaches is checked, so problems behind a JP V0 go unnoticed.
+//
+//   0x20A error: invalid opcode 0x0123
+//   0x210 warning: CALL to odd address 0x301
+use crate::disasm::Disassembly;
+use crate::graph::{Analysis, Exit};
+use crate::instructions::Instruction;
+use crate::memory::PROGRAM_LOAD_OFFSET;
+use crate::platform::{extension, Platform};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+//entries of CPU.stack
+const STACK_SIZE: usize = 16;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub enum Severity {
+    Info,
+    Warning,
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Severity::Info => "info",
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub struct Finding {
+    pub addr: u16,
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "0x{:03X} {}: {}", self.addr, self.severity, self.message)
+    }
+}
+
+struct Linter<'a> {
+    analysis: &'a Analysis,
+    findings: BTreeSet<Finding>,
+}
+
+impl Linter<'_> {
+    fn report(&mut self, addr: u16, severity: Severity, message: String) {
+        self.findings.insert(Finding { addr, severity, message });
+    }
+
+    fn disassembly(&self) -> &Disassembly {
+        &self.analysis.disassembly
+    }
+
+    // where execution leaves the code the disassembler could follow
+    fn invalid_opcodes(&mut self) {
+        let platform = self.disassembly().platform;
+        let mut ends: Vec<u16> = self.analysis.subroutines.values()
+            .flat_map(|subroutine| subroutine.blocks.values())
+            .filter(|block| block.exit == Some(Exit::Invalid))
+            .map(|block| block.end)
+            .collect();
+        let main = PROGRAM_LOAD_OFFSET as u16;
+        if !self.disassembly().code.contains(&main) && self.disassembly().rom_end() > PROGRAM_LOAD_OFFSET {
+            ends.push(main);
+        }
+        for addr in ends {
+            match self.disassembly().fetch(addr) {
+                None => self.report(addr, Severity::Error, "execution runs off the end of the rom".to_string()),
+                Some(opcode) => match extension(opcode) {
+                    Some((needs, name)) => self.report(
+                        addr,
+                        Severity::Error,
+                        format!("{} (0x{:04X}) needs {}, the profile is {}", name, opcode, needs.name(), platform.name()),
+                    ),
+                    None => self.report(addr, Severity::Error, format!("invalid opcode 0x{:04X}", opcode)),
+                },
+            }
+        }
+    }
+
+    fn targets(&mut self) {
+        let rom_end = self.disassembly().rom_end();
+        let platform = self.disassembly().platform;
+        let code: Vec<u16> = self.disassembly().code.iter().cloned().collect();
+        for addr in code {
+            let opcode = self.disassembly().fetch(addr).unwrap();
+            let (instr, value) = Instruction::decode(opcode as u32);
+            let target = (value & 0xFFF) as u16;
+            let name = match instr {
+                Instruction::JP => "JP",
+                Instruction::CALL => "CALL",
+                Instruction::JP_V0_ADDR => {
+                    self.report(addr, Severity::Info, "JP V0 is only resolved at run time, its targets are not checked".to_string());
+                    continue;
+                }
+                Instruction::DRW_VX_VY_NIB if value & 0xF == 0 && platform == Platform::Chip8 => {
+                    self.report(addr, Severity::Warning, "DRW with height 0 draws nothing on CHIP-8".to_string());
+                    continue;
+                }
+                _ => continue,
+            };
+            if (target as usize) < PROGRAM_LOAD_OFFSET || target as usize >= rom_end {
+                self.report(addr, Severity::Error, format!("{} to 0x{:03X} outside the rom", name, target));
+            } else if target % 2 == 1 {
+                self.report(addr, Severity::Warning, format!("{} to odd address 0x{:03X}", name, target));
+            }
+        }
+    }
+
+    // LD I targets are sprites, code should never run into them
+    fn code_in_data(&mut self) {
+        let mut sprites = BTreeMap::new();
+        for &addr in self.disassembly().code.iter() {
+            let opcode = self.disassembly().fetch(addr).unwrap();
+            if Instruction::decode(opcode as u32).0 == Instruction::LD_I_ADDR {
+                sprites.entry(opcode & 0xFFF).or_insert(addr);
+            }
+        }
+        let code: Vec<u16> = self.disassembly().code.iter().cloned().collect();
+        for addr in code {
+            for sprite in [addr, addr + 1].iter() {
+                if let Some(&site) = sprites.get(sprite) {
+                    self.report(
+                        addr,
+                        Severity::Warning,
+                        format!("code runs into sprite data at 0x{:03X} loaded by LD I at 0x{:03X}", sprite, site),
+                    );
+                }
+            }
+        }
+    }
+
+    // the longest call chain below entry
+    fn chain(&mut self, entry: u16, active: &mut Vec<u16>, chains: &mut BTreeMap<u16, Vec<u16>>) -> Vec<u16> {
+        if let Some(chain) = chains.get(&entry) {
+            return chain.clone();
+        }
+        active.push(entry);
+        let mut longest = vec![];
+        let callees = self.analysis.subroutines.get(&entry).map(|subroutine| subroutine.calls.clone()).unwrap_or_default();
+        for callee in callees {
+            if active.contains(&callee) {
+                let name = self.analysis.subroutines.get(&callee).map_or("?", |subroutine| subroutine.name.as_str());
+                let message = format!("{} is called recursively, the {} entry stack can overflow", name, STACK_SIZE);
+                self.report(callee, Severity::Warning, message);
+                continue;
+            }
+            let chain = self.chain(callee, active, chains);
+            if chain.len() + 1 > longest.len() {
+                longest = std::iter::once(callee).chain(chain).collect();
+            }
+        }
+        active.pop();
+        chains.insert(entry, longest.clone());
+        longest
+    }
+
+    fn call_depth(&mut self) {
+        let main = PROGRAM_LOAD_OFFSET as u16;
+        if !self.analysis.subroutines.contains_key(&main) {
+            return;
+        }
+        let chain = self.chain(main, &mut vec![], &mut BTreeMap::new());
+        if chain.len() > STACK_SIZE {
+            let names: Vec<&str> = chain.iter().map(|entry| self.analysis.subroutines[entry].name.as_str()).collect();
+            let message =
+                format!("calls nest {} deep but the stack holds {}: main -> {}", chain.len(), STACK_SIZE, names.join(" -> "));
+            self.report(main, Severity::Error, message);
+        }
+    }
+}
+
+// findings ordered by address
+pub fn lint(rom: &[u8], platform: Platform) -> Vec<Finding> {
+    let analysis = Analysis::for_platform(rom, platform);
+    let mut linter = Linter { analysis: &analysis, findings: BTreeSet::new() };
+    linter.invalid_opcodes();
+    linter.targets();
+    linter.code_in_data();
+    linter.call_depth();
+    linter.findings.into_iter().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::asm::assemble;
+    use crate::lint::{lint, Severity};
+    use crate::platform::Platform;
+
+    fn findings(source: &str, platform: Platform) -> Vec<String> {
+        lint(&assemble(source).unwrap(), platform).iter().map(|finding| finding.to_string()).collect()
+    }
+
+    #[test]
+    fn test_findings() {
+        let source = "
+            main:
+                LD I, sprite
+                CALL 0x301
+                SE V0, 1
+                JP 0x203
+                DRW V0, V1, 0
+            sprite:
+                db 0x60, 0x90
+                db 0x00, 0xFF
+        ";
+        assert_eq!(
+            vec![
+                "0x202 error: CALL to 0x301 outside the rom",
+                "0x203 error: invalid opcode 0x0130",
+                "0x206 warning: JP to odd address 0x203",
+                "0x208 warning: DRW with height 0 draws nothing on CHIP-8",
+                "0x20A warning: code runs into sprite data at 0x20A loaded by LD I at 0x200",
+                "0x20C error: hires (0x00FF) needs SUPER-CHIP, the profile is CHIP-8",
+            ],
+            findings(source, Platform::Chip8)
+        );
+        assert_eq!(
+            vec![
+                "0x202 error: CALL to 0x301 outside the rom",
+                "0x203 error: invalid opcode 0x0130",
+                "0x206 warning: JP to odd address 0x203",
+                "0x20A warning: code runs into sprite data at 0x20A loaded by LD I at 0x200",
+                "0x20E error: execution runs off the end of the rom",
+            ],
+            findings(source, Platform::SuperChip)
+        );
+        assert_eq!(vec!["0x202 error: invalid opcode 0x0123"], findings("CLS\ndb 0x01, 0x23", Platform::XoChip));
+        assert_eq!(
+            vec!["0x200 info: JP V0 is only resolved at run time, its targets are not checked"],
+            findings("JP V0, 0x300", Platform::Chip8)
+        );
+        assert_eq!(Severity::Error, lint(&[0x01, 0x23], Platform::Chip8)[0].severity);
+    }
+
+    #[test]
+    fn test_call_depth() {
+        let mut source = "main:\n CALL sub_0\n JP main\n".to_string();
+        for n in 0..17 {
+            source += &format!("sub_{}:\n CALL sub_{}\n RET\n", n, n + 1);
+        }
+        source += "sub_17:\n RET\n";
+        let found = findings(&source, Platform::Chip8);
+        assert_eq!(1, found.len());
+        assert!(found[0].starts_with("0x200 error: calls nest 18 deep but the stack holds 16: main -> sub_204 -> sub_208"));
+        assert!(findings(&source.replace("sub_17:\n RET", "sub_17:\n CALL sub_16\n RET"), Platform::Chip8)
+            .contains(&"0x244 warning: sub_244 is called recursively, the 16 entry stack can overflow".to_string()));
+    }
+}
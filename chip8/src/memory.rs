@@ -1,8 +1,9 @@
-use std::fmt::{Display, Formatter, Error, Debug};
+use core::fmt::{self, Formatter, Error, Debug};
 
 pub const MEM_SIZE: usize = 4096;
 pub const PROGRAM_LOAD_OFFSET: usize = 0x200;
-const SPRITES: [[u8; 5]; 16] = [
+pub const FONT_OFFSET: usize = 0x000;
+pub const SPRITES: [[u8; 5]; 16] = [
     [0xF0, 0x90, 0x90, 0x90, 0xF0], //0
     [0x20, 0x60, 0x20, 0x20, 0x70], //1
     [0xF0, 0x10, 0xF0, 0x80, 0xF0], //2
@@ -21,34 +22,73 @@ const SPRITES: [[u8; 5]; 16] = [
     [0xF0, 0x80, 0xF0, 0x80, 0x80], //F
 ];
 
+#[derive(Clone, Eq, PartialEq)]
 pub struct Memory {
     pub memory: [u8; MEM_SIZE]
 }
 
 impl Memory {
     pub fn new() -> Memory {
-        Memory {
+        let mut memory = Memory {
             memory: [0; MEM_SIZE]
-        }
+        };
+        memory.load_font();
+        memory
     }
 
     pub fn reset(&mut self) {
         self.memory = [0; MEM_SIZE];
+        self.load_font();
+    }
+
+    #[cfg(feature = "std")]
+    pub fn load_program_file<P: AsRef<std::path::Path>>(&mut self, path: P) -> std::io::Result<usize> {
+        let bytes = std::fs::read(path)?;
+        self.load_program(&bytes);
+        Ok(bytes.len())
+    }
+
+    // 16 bytes per line, each line prefixed with its address
+    pub fn write_hex_dump<W: fmt::Write>(&self, out: &mut W, start: usize, len: usize) -> fmt::Result {
+        let end = start.saturating_add(len).min(MEM_SIZE);
+        let mut addr = start;
+        while addr < end {
+            write!(out, "0x{:03X}:", addr)?;
+            for value in self.memory[addr..(addr + 16).min(end)].iter() {
+                write!(out, " {:02X}", value)?;
+            }
+            writeln!(out)?;
+            addr += 16;
+        }
+        Ok(())
+    }
+
+    fn load_font(&mut self) {
+        for (i, sprite) in SPRITES.iter().enumerate() {
+            let start = FONT_OFFSET + i * 5;
+            self.memory[start..start + 5].copy_from_slice(sprite);
+        }
     }
 
-    pub fn load_program(&mut self, vec: &Vec<u8>) {
+    pub fn load_program(&mut self, vec: &[u8]) {
         for (i, &bt) in vec.iter().enumerate() {
             self.memory[i + PROGRAM_LOAD_OFFSET] = bt;
         }
     }
 }
 
+impl Default for Memory {
+    fn default() -> Self {
+        Memory::new()
+    }
+}
+
 impl Debug for Memory {
     fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), Error> {
         for (i, value) in self.memory.iter().enumerate() {
             write!(f, "0x{:X} ", value)?;
             if (i + 1) % 15 == 0 {
-                write!(f, "\n")?;
+                writeln!(f)?;
             }
         }
         Ok(())
@@ -0,0 +1,370 @@
+// Movie file format, all integers little endian:
+//
+//   magic "C8MV", version: u16
+//   rom hash: u64, rng seed: u64, cycles per frame: u32, quirks: u8
+//   frame count: u32
+//   per frame: keypad mask: u16, state hash after the frame: u64
+//
+// A movie replays bit for bit because the rng is seeded from the movie and
+// the keypad is the only other input to the machine.
+//
+// State hashes are fnv1a over this image of the machine, which must never
+// change or every recorded movie desyncs:
+//
+//   memory: 4096 bytes, V0 to VF, I: u16, pc: u16, sp: u8,
+//   stack: 16 x u16, dt: u8, st: u8, keypad mask: u16,
+//   framebuffer: one byte per pixel, rng state: u64
+use crate::cpu::{Quirks, CPU};
+use crate::memory::Memory;
+use std::fmt;
+
+pub const MAGIC: [u8; 4] = *b"C8MV";
+pub const FORMAT_VERSION: u16 = 1;
+const HEADER_SIZE: usize = 4 + 2 + 8 + 8 + 4 + 1 + 4;
+const FRAME_SIZE: usize = 2 + 8;
+
+pub fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xCBF2_9CE4_8422_2325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01B3)
+    })
+}
+
+pub fn state_hash(cpu: &CPU) -> u64 {
+    let mut image = cpu.memory.memory.to_vec();
+    image.extend((0..16).map(|reg| cpu.read_register(reg)));
+    image.extend_from_slice(&cpu.get_i().to_le_bytes());
+    image.extend_from_slice(&cpu.get_pc().to_le_bytes());
+    image.push(cpu.get_sp() as u8);
+    image.extend(cpu.stack.iter().flat_map(|slot| slot.to_le_bytes()));
+    image.push(cpu.get_dt());
+    image.push(cpu.get_st());
+    image.extend_from_slice(&cpu.keys.to_le_bytes());
+    image.extend_from_slice(&cpu.display.pixels);
+    image.extend_from_slice(&cpu.rng.get_state().to_le_bytes());
+    fnv1a(&image)
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum MovieError {
+    BadMagic,
+    UnsupportedVersion(u16),
+    Truncated,
+    RomMismatch { expected: u64, actual: u64 },
+    //the first frame whose state hash did not match the recording
+    Desync { frame: usize, expected: u64, actual: u64 },
+    //the frame was never simulated, so there is nothing to check it against
+    MissingStateHash(usize),
+    //playback went past the last frame
+    EndOfMovie,
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MovieError::BadMagic => write!(f, "not a movie"),
+            MovieError::UnsupportedVersion(version) => write!(f, "unsupported movie version {}", version),
+            MovieError::Truncated => write!(f, "movie is truncated"),
+            MovieError::RomMismatch { expected, actual } => {
+                write!(f, "movie was recorded with rom {:016x}, got {:016x}", expected, actual)
+            }
+            MovieError::Desync { frame, expected, actual } => {
+                write!(f, "desync at frame {}: expected state {:016x}, got {:016x}", frame, expected, actual)
+            }
+            MovieError::MissingStateHash(frame) => write!(f, "frame {} has no state hash", frame),
+            MovieError::EndOfMovie => write!(f, "the movie has no more frames"),
+        }
+    }
+}
+
+impl std::error::Error for MovieError {}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Movie {
+    pub rom_hash: u64,
+    pub seed: u64,
+    pub cycles_per_frame: u32,
+    pub quirks: Quirks,
+    //keypad mask held during each frame
+    pub inputs: Vec<u16>,
+    //state hash after each frame
+    pub state_hashes: Vec<u64>,
+}
+
+impl Movie {
+    pub fn new(rom: &[u8], quirks: Quirks, cycles_per_frame: u32, seed: u64) -> Movie {
+        Movie {
+            rom_hash: fnv1a(rom),
+            seed,
+            cycles_per_frame,
+            quirks,
+            inputs: vec![],
+            state_hashes: vec![],
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.inputs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inputs.is_empty()
+    }
+
+    // the machine as it was when recording started
+    pub fn power_on(&self, rom: &[u8]) -> Result<CPU, MovieError> {
+        let actual = fnv1a(rom);
+        if actual != self.rom_hash {
+            return Err(MovieError::RomMismatch { expected: self.rom_hash, actual });
+        }
+        let mut mem = Memory::new();
+        mem.load_program(rom);
+        let mut cpu = CPU::new(mem);
+        cpu.seed_rng(self.seed);
+        cpu.quirks = self.quirks;
+        Ok(cpu)
+    }
+
+    // runs one frame of the movie on `cpu`
+    pub fn run_frame(&self, cpu: &mut CPU, frame: usize) {
+        cpu.keys = self.inputs[frame];
+        cpu.run_frame(self.cycles_per_frame as usize);
+    }
+
+    // every frame needs its state hash, movies that are still being edited
+    // have to be simulated to the end first
+    pub fn to_bytes(&self) -> Result<Vec<u8>, MovieError> {
+        if self.state_hashes.len() < self.len() {
+            return Err(MovieError::MissingStateHash(self.state_hashes.len()));
+        }
+        let mut out = Vec::with_capacity(HEADER_SIZE + self.len() * FRAME_SIZE);
+        out.extend_from_slice(&MAGIC);
+        out.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        out.extend_from_slice(&self.rom_hash.to_le_bytes());
+        out.extend_from_slice(&self.seed.to_le_bytes());
+        out.extend_from_slice(&self.cycles_per_frame.to_le_bytes());
+        out.push(self.quirks.to_bits());
+        out.extend_from_slice(&(self.len() as u32).to_le_bytes());
+        for (input, hash) in self.inputs.iter().zip(self.state_hashes.iter()) {
+            out.extend_from_slice(&input.to_le_bytes());
+            out.extend_from_slice(&hash.to_le_bytes());
+        }
+        Ok(out)
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Movie, MovieError> {
+        if data.len() < 6 {
+            return Err(MovieError::Truncated);
+        }
+        if data[0..4] != MAGIC {
+            return Err(MovieError::BadMagic);
+        }
+        let version = u16::from_le_bytes([data[4], data[5]]);
+        if version > FORMAT_VERSION {
+            return Err(MovieError::UnsupportedVersion(version));
+        }
+        if data.len() < HEADER_SIZE {
+            return Err(MovieError::Truncated);
+        }
+        let u64_at = |at: usize| {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(&data[at..at + 8]);
+            u64::from_le_bytes(bytes)
+        };
+        let u32_at = |at: usize| u32::from_le_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]]);
+        let frames = u32_at(27) as usize;
+        if data.len() < HEADER_SIZE + frames * FRAME_SIZE {
+            return Err(MovieError::Truncated);
+        }
+        let mut movie = Movie {
+            rom_hash: u64_at(6),
+            seed: u64_at(14),
+            cycles_per_frame: u32_at(22),
+            quirks: Quirks::from_bits(data[26]),
+            inputs: Vec::with_capacity(frames),
+            state_hashes: Vec::with_capacity(frames),
+        };
+        for frame in 0..frames {
+            let at = HEADER_SIZE + frame * FRAME_SIZE;
+            movie.inputs.push(u16::from_le_bytes([data[at], data[at + 1]]));
+            movie.state_hashes.push(u64_at(at + 2));
+        }
+        Ok(movie)
+    }
+}
+
+pub struct Recorder {
+    pub cpu: CPU,
+    movie: Movie,
+}
+
+impl Recorder {
+    pub fn new(rom: &[u8], quirks: Quirks, cycles_per_frame: u32, seed: u64) -> Recorder {
+        let movie = Movie::new(rom, quirks, cycles_per_frame, seed);
+        Recorder {
+            cpu: movie.power_on(rom).unwrap(),
+            movie,
+        }
+    }
+
+    pub fn frame(&mut self, keys: u16) {
+        self.movie.inputs.push(keys);
+        self.movie.run_frame(&mut self.cpu, self.movie.inputs.len() - 1);
+        self.movie.state_hashes.push(state_hash(&self.cpu));
+    }
+
+    pub fn movie(&self) -> &Movie {
+        &self.movie
+    }
+
+    pub fn finish(self) -> Movie {
+        self.movie
+    }
+}
+
+pub struct Player<'a> {
+    pub cpu: CPU,
+    movie: &'a Movie,
+    frame: usize,
+}
+
+impl<'a> Player<'a> {
+    pub fn new(movie: &'a Movie, rom: &[u8]) -> Result<Player<'a>, MovieError> {
+        Ok(Player {
+            cpu: movie.power_on(rom)?,
+            movie,
+            frame: 0,
+        })
+    }
+
+    pub fn frame(&self) -> usize {
+        self.frame
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.frame >= self.movie.len()
+    }
+
+    // plays the next frame and checks the resulting state
+    pub fn next_frame(&mut self) -> Result<(), MovieError> {
+        if self.is_finished() {
+            return Err(MovieError::EndOfMovie);
+        }
+        let expected = *self.movie.state_hashes.get(self.frame).ok_or(MovieError::MissingStateHash(self.frame))?;
+        self.movie.run_frame(&mut self.cpu, self.frame);
+        let actual = state_hash(&self.cpu);
+        if expected != actual {
+            return Err(MovieError::Desync { frame: self.frame, expected, actual });
+        }
+        self.frame += 1;
+        Ok(())
+    }
+
+    pub fn play_to_end(&mut self) -> Result<(), MovieError> {
+        while !self.is_finished() {
+            self.next_frame()?;
+        }
+        Ok(())
+    }
+}
+
+// replays the whole movie and reports the first desync
+pub fn verify(movie: &Movie, rom: &[u8]) -> Result<(), MovieError> {
+    Player::new(movie, rom)?.play_to_end()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu::{Quirks, CPU};
+    use crate::memory::Memory;
+    use crate::movie::{state_hash, verify, Movie, MovieError, Player, Recorder};
+
+    fn rom() -> Vec<u8> {
+        vec![
+            // RND 0, 0x3F
+            0xC0, 0x3F, //0x200
+            // SKNP 1
+            0xE1, 0xA1, //0x202
+            // ADD 2, 0
+            0x82, 0x04, //0x204
+            // LD F, 2
+            0xF2, 0x29, //0x206
+            // DRW 0, 3, 5
+            0xD0, 0x35, //0x208
+            // JP 0x200
+            0x12, 0x00, //0x20A
+        ]
+    }
+
+    fn record() -> Movie {
+        let mut recorder = Recorder::new(&rom(), Quirks::default(), 7, 1234);
+        for frame in 0..30 {
+            //SKNP 1 tests key 0 since V1 stays zero
+            recorder.frame(if frame % 3 == 0 { 1 << 0x0 } else { 0 });
+        }
+        recorder.finish()
+    }
+
+    #[test]
+    fn test_replay() {
+        let movie = record();
+        assert_eq!(30, movie.len());
+        assert_eq!(Ok(()), verify(&movie, &rom()));
+
+        let loaded = Movie::from_bytes(&movie.to_bytes().unwrap()).unwrap();
+        assert_eq!(movie, loaded);
+        assert_eq!(Ok(()), verify(&loaded, &rom()));
+    }
+
+    #[test]
+    fn test_tampered_recording() {
+        let mut recorder = Recorder::new(&rom(), Quirks::default(), 7, 1234);
+        //the movie cannot know about state poked in from the outside
+        recorder.cpu.write_register(0x1, 0x4);
+        recorder.frame(0);
+        match verify(&recorder.finish(), &rom()) {
+            Err(MovieError::Desync { frame, .. }) => assert_eq!(0, frame),
+            other => panic!("expected a desync, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_first_desync() {
+        let mut movie = record();
+        movie.inputs[13] = 1 << 0x0;
+        match verify(&movie, &rom()) {
+            Err(MovieError::Desync { frame, .. }) => assert_eq!(13, frame),
+            other => panic!("expected a desync, got {:?}", other),
+        }
+
+        let mut other_rom = rom();
+        other_rom[1] = 0x1F;
+        assert!(matches!(verify(&movie, &other_rom), Err(MovieError::RomMismatch { .. })));
+        assert_eq!(Err(MovieError::Truncated), Movie::from_bytes(&movie.to_bytes().unwrap()[..40]));
+    }
+
+    #[test]
+    fn test_incomplete_movie() {
+        let mut movie = record();
+        movie.state_hashes.truncate(20);
+        assert_eq!(Err(MovieError::MissingStateHash(20)), movie.to_bytes());
+        assert_eq!(Err(MovieError::MissingStateHash(20)), verify(&movie, &rom()));
+
+        let movie = record();
+        let mut player = Player::new(&movie, &rom()).unwrap();
+        assert_eq!(Ok(()), player.play_to_end());
+        assert_eq!(Err(MovieError::EndOfMovie), player.next_frame());
+    }
+
+    // recorded movies rely on these never changing
+    #[test]
+    fn test_state_hash() {
+        let mut mem = Memory::new();
+        mem.load_program(&rom());
+        let mut cpu = CPU::new(mem);
+        cpu.seed_rng(1234);
+        assert_eq!(0xA5DF_E602_BC50_D15C, state_hash(&cpu));
+        cpu.press_key(0x0);
+        cpu.run_frame(7);
+        assert_eq!(0xA2B7_16FF_9DEF_90FC, state_hash(&cpu));
+    }
+}
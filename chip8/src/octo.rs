@@ -0,0 +1,800 @@
+// Compiler for the Octo language.
+//
+//   :const SPEED 2
+//   :alias x v1
+//   : main
+//       x := 0
+//       loop
+//           x += SPEED
+//           if x == 0x40 then x := 0
+//           while x != 0x3E
+//       again
+//
+// Tokens are separated by whitespace and `#` starts a comment. Supported are
+// all chip-8 statements, `if`/`then`, `if`/`begin`/`else`/`end`,
+// `loop`/`while`/`again` and the directives `:alias`, `:const`, `:macro`,
+// `:calc`, `:next`, `:org` and `:call`. A bare number emits a byte, a bare
+// label calls it. When `: main` is not at the start of the program,
+// 0x200 holds a jump to it.
+//
+// `:calc name { expr }` evaluates integer arithmetic right to left without
+// precedence, as Octo does, so `2 * 3 + 1` is 8. Parentheses group.
+use crate::memory::{Memory, MEM_SIZE, PROGRAM_LOAD_OFFSET};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt;
+
+//guards against macros that expand themselves
+const MAX_MACRO_EXPANSIONS: usize = 10_000;
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct OctoError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for OctoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for OctoError {}
+
+// which source line produced each byte of the program
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct SourceMap {
+    lines: Vec<String>,
+    addrs: BTreeMap<u16, usize>,
+}
+
+impl SourceMap {
+    // line numbers start at 1
+    pub fn line(&self, addr: u16) -> Option<usize> {
+        self.addrs.get(&addr).cloned()
+    }
+
+    pub fn text(&self, line: usize) -> Option<&str> {
+        self.lines.get(line.checked_sub(1)?).map(|text| text.trim())
+    }
+
+    // first address a line compiled to, for breakpoints on source lines
+    pub fn addr(&self, line: usize) -> Option<u16> {
+        self.addrs.iter().find(|(_, &l)| l == line).map(|(&addr, _)| addr)
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Program {
+    //starts at PROGRAM_LOAD_OFFSET
+    pub rom: Vec<u8>,
+    pub source_map: SourceMap,
+}
+
+impl Program {
+    pub fn load(&self, memory: &mut Memory) {
+        memory.load_program(&self.rom);
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+    text: String,
+    line: usize,
+}
+
+struct Macro {
+    params: Vec<String>,
+    body: Vec<Token>,
+}
+
+enum Branch {
+    //address of the jump over the block
+    If(usize),
+    Else(usize),
+    //start of the loop and the jumps out of it
+    Loop(usize, Vec<usize>),
+}
+
+struct Compiler {
+    tokens: VecDeque<Token>,
+    memory: Vec<u8>,
+    here: usize,
+    end: usize,
+    line: usize,
+    labels: HashMap<String, usize>,
+    constants: HashMap<String, i64>,
+    aliases: HashMap<String, u8>,
+    macros: HashMap<String, Macro>,
+    expansions: usize,
+    //12 bit address fields of labels not defined yet
+    fixups: Vec<(usize, String, usize)>,
+    branches: Vec<(Branch, usize)>,
+    addrs: BTreeMap<u16, usize>,
+}
+
+fn tokenize(source: &str) -> VecDeque<Token> {
+    let mut tokens = VecDeque::new();
+    for (index, line) in source.lines().enumerate() {
+        let code = line.split('#').next().unwrap();
+        for word in code.split_whitespace() {
+            tokens.push_back(Token { text: word.to_string(), line: index + 1 });
+        }
+    }
+    tokens
+}
+
+fn parse_number(word: &str) -> Option<i64> {
+    let (negative, digits) = match word.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, word),
+    };
+    let lower = digits.to_ascii_lowercase();
+    let value = if let Some(hex) = lower.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok()?
+    } else if let Some(binary) = lower.strip_prefix("0b") {
+        i64::from_str_radix(binary, 2).ok()?
+    } else {
+        lower.parse().ok()?
+    };
+    Some(if negative { -value } else { value })
+}
+
+fn is_identifier(word: &str) -> bool {
+    let mut chars = word.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+fn parse_v(word: &str) -> Option<u8> {
+    let digit = word.strip_prefix('v').or_else(|| word.strip_prefix('V'))?;
+    if digit.len() != 1 {
+        return None;
+    }
+    u8::from_str_radix(digit, 16).ok()
+}
+
+const KEYWORDS: [&str; 23] = [
+    "clear", "return", "bcd", "save", "load", "sprite", "jump", "jump0", "native", "if", "then", "begin", "else",
+    "end", "loop", "while", "again", "key", "delay", "buzzer", "random", "hex", "i",
+];
+
+fn apply(op: &str, a: i64, b: i64) -> Option<i64> {
+    Some(match op {
+        "+" => a.wrapping_add(b),
+        "-" => a.wrapping_sub(b),
+        "*" => a.wrapping_mul(b),
+        "/" => a.checked_div(b)?,
+        "%" => a.checked_rem(b)?,
+        "&" => a & b,
+        "|" => a | b,
+        "^" => a ^ b,
+        "<<" => a.wrapping_shl(b as u32),
+        ">>" => a.wrapping_shr(b as u32),
+        "min" => a.min(b),
+        "max" => a.max(b),
+        "<" => (a < b) as i64,
+        ">" => (a > b) as i64,
+        "<=" => (a <= b) as i64,
+        ">=" => (a >= b) as i64,
+        "==" => (a == b) as i64,
+        "!=" => (a != b) as i64,
+        _ => return None,
+    })
+}
+
+impl Compiler {
+    fn new(source: &str) -> Compiler {
+        Compiler {
+            tokens: tokenize(source),
+            memory: vec![0; MEM_SIZE],
+            here: PROGRAM_LOAD_OFFSET,
+            end: PROGRAM_LOAD_OFFSET,
+            line: 1,
+            labels: HashMap::new(),
+            constants: HashMap::new(),
+            aliases: HashMap::new(),
+            macros: HashMap::new(),
+            expansions: 0,
+            fixups: vec![],
+            branches: vec![],
+            addrs: BTreeMap::new(),
+        }
+    }
+
+    fn error<T>(&self, message: String) -> Result<T, OctoError> {
+        Err(OctoError { line: self.line, message })
+    }
+
+    fn next(&mut self) -> Result<String, OctoError> {
+        match self.tokens.pop_front() {
+            Some(token) => {
+                self.line = token.line;
+                Ok(token.text)
+            }
+            None => self.error("unexpected end of program".to_string()),
+        }
+    }
+
+    fn expect(&mut self, word: &str) -> Result<(), OctoError> {
+        let token = self.next()?;
+        if token != word {
+            return self.error(format!("expected {} but found {}", word, token));
+        }
+        Ok(())
+    }
+
+    // tokens up to the matching closing brace
+    fn block(&mut self) -> Result<Vec<Token>, OctoError> {
+        self.expect("{")?;
+        let mut body = vec![];
+        let mut depth = 1;
+        loop {
+            let token = match self.tokens.pop_front() {
+                Some(token) => token,
+                None => return self.error("missing }".to_string()),
+            };
+            match token.text.as_str() {
+                "{" => depth += 1,
+                "}" => depth -= 1,
+                _ => {}
+            }
+            if depth == 0 {
+                return Ok(body);
+            }
+            body.push(token);
+        }
+    }
+
+    fn emit_byte(&mut self, byte: u8) -> Result<(), OctoError> {
+        if self.here >= MEM_SIZE {
+            return self.error("program does not fit in memory".to_string());
+        }
+        self.memory[self.here] = byte;
+        self.addrs.insert(self.here as u16, self.line);
+        self.here += 1;
+        self.end = self.end.max(self.here);
+        Ok(())
+    }
+
+    fn emit(&mut self, opcode: u16) -> Result<(), OctoError> {
+        self.emit_byte((opcode >> 8) as u8)?;
+        self.emit_byte(opcode as u8)
+    }
+
+    fn patch(&mut self, at: usize, addr: usize) {
+        self.memory[at] = (self.memory[at] & 0xF0) | (addr >> 8) as u8;
+        self.memory[at + 1] = addr as u8;
+    }
+
+    fn define_label(&mut self, name: &str, addr: usize) -> Result<(), OctoError> {
+        self.check_name(name)?;
+        self.labels.insert(name.to_string(), addr);
+        Ok(())
+    }
+
+    fn check_name(&self, name: &str) -> Result<(), OctoError> {
+        if !is_identifier(name) || parse_v(name).is_some() || KEYWORDS.contains(&name) {
+            return self.error(format!("invalid name {}", name));
+        }
+        if self.labels.contains_key(name) || self.constants.contains_key(name) || self.macros.contains_key(name) {
+            return self.error(format!("{} is already defined", name));
+        }
+        Ok(())
+    }
+
+    fn register(&self, word: &str) -> Option<u8> {
+        parse_v(word).or_else(|| self.aliases.get(word).cloned())
+    }
+
+    fn expect_register(&mut self) -> Result<u16, OctoError> {
+        let word = self.next()?;
+        match self.register(&word) {
+            Some(reg) => Ok(reg as u16),
+            None => self.error(format!("expected a register but found {}", word)),
+        }
+    }
+
+    fn value(&self, word: &str) -> Result<i64, OctoError> {
+        if let Some(value) = parse_number(word) {
+            return Ok(value);
+        }
+        if let Some(&value) = self.constants.get(word) {
+            return Ok(value);
+        }
+        match self.labels.get(word) {
+            Some(&addr) => Ok(addr as i64),
+            None => self.error(format!("undefined name {}", word)),
+        }
+    }
+
+    fn byte(&mut self) -> Result<u16, OctoError> {
+        let word = self.next()?;
+        let value = self.value(&word)?;
+        if !(-128..=255).contains(&value) {
+            return self.error(format!("{} does not fit in a byte", word));
+        }
+        Ok((value & 0xFF) as u16)
+    }
+
+    // an address operand, labels may be defined later
+    fn emit_addr(&mut self, opcode: u16) -> Result<(), OctoError> {
+        let word = self.next()?;
+        let addr = if parse_number(&word).is_none() && !self.constants.contains_key(&word) && !self.labels.contains_key(&word) {
+            if !is_identifier(&word) {
+                return self.error(format!("expected an address but found {}", word));
+            }
+            self.fixups.push((self.here, word, self.line));
+            0
+        } else {
+            let addr = self.value(&word)?;
+            if !(0..=0xFFF).contains(&addr) {
+                return self.error(format!("address {} is out of range", word));
+            }
+            addr as u16
+        };
+        self.emit(opcode | addr)
+    }
+
+    // the skips for a condition, taken when it holds and when it does not
+    fn condition(&mut self) -> Result<(u16, u16), OctoError> {
+        let x = self.expect_register()? << 8;
+        let op = self.next()?;
+        match op.as_str() {
+            "key" => return Ok((0xE09E | x, 0xE0A1 | x)),
+            "-key" => return Ok((0xE0A1 | x, 0xE09E | x)),
+            "==" | "!=" => {}
+            _ => return self.error(format!("unknown comparison {}", op)),
+        }
+        let (equal, not_equal) = match self.tokens.front().and_then(|token| self.register(&token.text)) {
+            Some(y) => {
+                self.next()?;
+                let y = (y as u16) << 4;
+                (0x5000 | x | y, 0x9000 | x | y)
+            }
+            None => {
+                let byte = self.byte()?;
+                (0x3000 | x | byte, 0x4000 | x | byte)
+            }
+        };
+        Ok(if op == "==" { (equal, not_equal) } else { (not_equal, equal) })
+    }
+
+    fn calc(&self, tokens: &[Token], at: &mut usize) -> Result<i64, OctoError> {
+        let token = match tokens.get(*at) {
+            Some(token) => token.text.as_str(),
+            None => return self.error("incomplete expression".to_string()),
+        };
+        *at += 1;
+        let left = match token {
+            "(" => {
+                let value = self.calc(tokens, at)?;
+                if tokens.get(*at).map(|token| token.text.as_str()) != Some(")") {
+                    return self.error("missing )".to_string());
+                }
+                *at += 1;
+                value
+            }
+            "-" => self.calc(tokens, at)?.wrapping_neg(),
+            "~" => !self.calc(tokens, at)?,
+            "!" => (self.calc(tokens, at)? == 0) as i64,
+            "HERE" => self.here as i64,
+            _ => self.value(token)?,
+        };
+        match tokens.get(*at).map(|token| token.text.as_str()) {
+            None | Some(")") => Ok(left),
+            Some(op) => {
+                *at += 1;
+                let right = self.calc(tokens, at)?;
+                match apply(op, left, right) {
+                    Some(value) => Ok(value),
+                    None if op == "/" || op == "%" => self.error("division by zero".to_string()),
+                    None => self.error(format!("unknown operator {}", op)),
+                }
+            }
+        }
+    }
+
+    fn expand(&mut self, name: &str) -> Result<(), OctoError> {
+        self.expansions += 1;
+        if self.expansions > MAX_MACRO_EXPANSIONS {
+            return self.error(format!("too many expansions of macro {}", name));
+        }
+        let line = self.line;
+        let mut args = HashMap::new();
+        for param in self.macros[name].params.clone() {
+            args.insert(param, self.next()?);
+        }
+        let body: Vec<Token> = self.macros[name].body.iter()
+            .map(|token| Token { text: args.get(&token.text).unwrap_or(&token.text).clone(), line })
+            .collect();
+        for token in body.into_iter().rev() {
+            self.tokens.push_front(token);
+        }
+        Ok(())
+    }
+
+    fn directive(&mut self, word: &str) -> Result<(), OctoError> {
+        match word {
+            ":" => {
+                let name = self.next()?;
+                self.define_label(&name, self.here)
+            }
+            ":next" => {
+                let name = self.next()?;
+                self.define_label(&name, self.here + 1)
+            }
+            ":alias" => {
+                let name = self.next()?;
+                self.check_name(&name)?;
+                let reg = self.expect_register()?;
+                self.aliases.insert(name, reg as u8);
+                Ok(())
+            }
+            ":const" => {
+                let name = self.next()?;
+                self.check_name(&name)?;
+                let word = self.next()?;
+                let value = self.value(&word)?;
+                self.constants.insert(name, value);
+                Ok(())
+            }
+            ":calc" => {
+                let name = self.next()?;
+                self.check_name(&name)?;
+                let tokens = self.block()?;
+                let mut at = 0;
+                let value = self.calc(&tokens, &mut at)?;
+                if at < tokens.len() {
+                    return self.error(format!("unexpected {} in expression", tokens[at].text));
+                }
+                self.constants.insert(name, value);
+                Ok(())
+            }
+            ":macro" => {
+                let name = self.next()?;
+                self.check_name(&name)?;
+                let mut params = vec![];
+                while self.tokens.front().is_some_and(|token| token.text != "{") {
+                    params.push(self.next()?);
+                }
+                let body = self.block()?;
+                self.macros.insert(name, Macro { params, body });
+                Ok(())
+            }
+            ":org" => {
+                let word = self.next()?;
+                let addr = self.value(&word)?;
+                if !(PROGRAM_LOAD_OFFSET as i64..MEM_SIZE as i64).contains(&addr) {
+                    return self.error(format!("address {} is out of range", word));
+                }
+                self.here = addr as usize;
+                Ok(())
+            }
+            ":call" => self.emit_addr(0x2000),
+            _ => self.error(format!("unknown directive {}", word)),
+        }
+    }
+
+    fn register_statement(&mut self, x: u16) -> Result<(), OctoError> {
+        let x = x << 8;
+        let op = self.next()?;
+        let operand = self.tokens.front().map(|token| token.text.clone()).unwrap_or_default();
+        let y = self.register(&operand).map(|y| (y as u16) << 4);
+        if y.is_some() || matches!(operand.as_str(), "delay" | "key" | "random") {
+            self.next()?;
+        }
+        let opcode = match (op.as_str(), operand.as_str(), y) {
+            (":=", "delay", _) => 0xF007 | x,
+            (":=", "key", _) => 0xF00A | x,
+            (":=", "random", _) => 0xC000 | x | self.byte()?,
+            (":=", _, Some(y)) => 0x8000 | x | y,
+            (":=", _, None) => 0x6000 | x | self.byte()?,
+            ("+=", _, Some(y)) => 0x8004 | x | y,
+            ("+=", _, None) => 0x7000 | x | self.byte()?,
+            ("-=", _, Some(y)) => 0x8005 | x | y,
+            ("-=", _, None) => 0x7000 | x | (self.byte()?.wrapping_neg() & 0xFF),
+            ("=-", _, Some(y)) => 0x8007 | x | y,
+            ("|=", _, Some(y)) => 0x8001 | x | y,
+            ("&=", _, Some(y)) => 0x8002 | x | y,
+            ("^=", _, Some(y)) => 0x8003 | x | y,
+            (">>=", _, Some(y)) => 0x8006 | x | y,
+            ("<<=", _, Some(y)) => 0x800E | x | y,
+            _ => return self.error(format!("invalid operation {} {}", op, operand)),
+        };
+        self.emit(opcode)
+    }
+
+    fn statement(&mut self) -> Result<(), OctoError> {
+        let word = self.next()?;
+        if let Some(x) = self.register(&word) {
+            return self.register_statement(x as u16);
+        }
+        match word.as_str() {
+            "clear" => self.emit(0x00E0),
+            "return" | ";" => self.emit(0x00EE),
+            "bcd" => self.expect_register().and_then(|x| self.emit(0xF033 | x << 8)),
+            "save" => self.expect_register().and_then(|x| self.emit(0xF055 | x << 8)),
+            "load" => self.expect_register().and_then(|x| self.emit(0xF065 | x << 8)),
+            "sprite" => {
+                let x = self.expect_register()?;
+                let y = self.expect_register()?;
+                let word = self.next()?;
+                let n = self.value(&word)?;
+                if !(0..=15).contains(&n) {
+                    return self.error(format!("sprite height {} is out of range", word));
+                }
+                self.emit(0xD000 | x << 8 | y << 4 | n as u16)
+            }
+            "jump" => self.emit_addr(0x1000),
+            "jump0" => self.emit_addr(0xB000),
+            "native" => self.emit_addr(0x0000),
+            "delay" | "buzzer" => {
+                self.expect(":=")?;
+                let x = self.expect_register()?;
+                self.emit(if word == "delay" { 0xF015 } else { 0xF018 } | x << 8)
+            }
+            "i" => {
+                let op = self.next()?;
+                match op.as_str() {
+                    "+=" => self.expect_register().and_then(|x| self.emit(0xF01E | x << 8)),
+                    ":=" if self.tokens.front().is_some_and(|token| token.text == "hex") => {
+                        self.next()?;
+                        self.expect_register().and_then(|x| self.emit(0xF029 | x << 8))
+                    }
+                    ":=" => self.emit_addr(0xA000),
+                    _ => self.error(format!("invalid operation i {}", op)),
+                }
+            }
+            "if" => {
+                let (when_true, when_false) = self.condition()?;
+                let then = self.next()?;
+                match then.as_str() {
+                    "then" => self.emit(when_false),
+                    "begin" => {
+                        self.emit(when_true)?;
+                        self.branches.push((Branch::If(self.here), self.line));
+                        self.emit(0x1000)
+                    }
+                    _ => self.error(format!("expected then or begin but found {}", then)),
+                }
+            }
+            "else" => match self.branches.pop() {
+                Some((Branch::If(jump), line)) => {
+                    self.branches.push((Branch::Else(self.here), line));
+                    self.emit(0x1000)?;
+                    self.patch(jump, self.here);
+                    Ok(())
+                }
+                _ => self.error("else without if".to_string()),
+            },
+            "end" => match self.branches.pop() {
+                Some((Branch::If(jump), _)) | Some((Branch::Else(jump), _)) => {
+                    self.patch(jump, self.here);
+                    Ok(())
+                }
+                _ => self.error("end without begin".to_string()),
+            },
+            "loop" => {
+                self.branches.push((Branch::Loop(self.here, vec![]), self.line));
+                Ok(())
+            }
+            "while" => {
+                let (when_true, _) = self.condition()?;
+                self.emit(when_true)?;
+                let here = self.here;
+                match self.branches.iter_mut().rev().find(|(branch, _)| matches!(branch, Branch::Loop(..))) {
+                    Some((Branch::Loop(_, exits), _)) => exits.push(here),
+                    _ => return self.error("while outside of a loop".to_string()),
+                }
+                self.emit(0x1000)
+            }
+            "again" => match self.branches.pop() {
+                Some((Branch::Loop(start, exits), _)) => {
+                    self.emit(0x1000 | start as u16)?;
+                    for exit in exits {
+                        self.patch(exit, self.here);
+                    }
+                    Ok(())
+                }
+                _ => self.error("again without loop".to_string()),
+            },
+            _ if word.starts_with(':') => self.directive(&word),
+            _ if self.macros.contains_key(&word) => self.expand(&word),
+            _ if parse_number(&word).is_some() || self.constants.contains_key(&word) => {
+                let value = self.value(&word)?;
+                if !(-128..=255).contains(&value) {
+                    return self.error(format!("{} does not fit in a byte", word));
+                }
+                self.emit_byte(value as u8)
+            }
+            _ if is_identifier(&word) && !KEYWORDS.contains(&word.as_str()) => {
+                self.tokens.push_front(Token { text: word, line: self.line });
+                self.emit_addr(0x2000)
+            }
+            _ => self.error(format!("unexpected {}", word)),
+        }
+    }
+
+    fn compile(mut self, source: &str) -> Result<Program, OctoError> {
+        let starts_with_main = self.tokens.len() >= 2 && self.tokens[0].text == ":" && self.tokens[1].text == "main";
+        let defines_main = self.tokens.iter().zip(self.tokens.iter().skip(1))
+            .any(|(colon, name)| colon.text == ":" && name.text == "main");
+        let needs_jump = defines_main && !starts_with_main;
+        if needs_jump {
+            self.emit(0x1000)?;
+        }
+        while !self.tokens.is_empty() {
+            self.statement()?;
+        }
+        if let Some((_, line)) = self.branches.last() {
+            self.line = *line;
+            return self.error("block is never closed".to_string());
+        }
+        if needs_jump {
+            //the scan above also sees `: main` inside unused macros
+            match self.labels.get("main") {
+                Some(&main) => self.patch(PROGRAM_LOAD_OFFSET, main),
+                None => return self.error("main is never defined".to_string()),
+            }
+        }
+        for (at, name, line) in std::mem::take(&mut self.fixups) {
+            self.line = line;
+            match self.labels.get(&name) {
+                Some(&addr) if addr <= 0xFFF => self.patch(at, addr),
+                Some(_) => return self.error(format!("address of {} is out of range", name)),
+                None => return self.error(format!("undefined name {}", name)),
+            }
+        }
+        Ok(Program {
+            rom: self.memory[PROGRAM_LOAD_OFFSET..self.end].to_vec(),
+            source_map: SourceMap {
+                lines: source.lines().map(|line| line.to_string()).collect(),
+                addrs: self.addrs,
+            },
+        })
+    }
+}
+
+pub fn compile(source: &str) -> Result<Program, OctoError> {
+    Compiler::new(source).compile(source)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu::CPU;
+    use crate::debugger::Debugger;
+    use crate::disasm::{disassemble, Syntax};
+    use crate::memory::Memory;
+    use crate::octo::compile;
+    use crate::rng::Rng;
+
+    fn rom(source: &str) -> Vec<u8> {
+        compile(source).unwrap().rom
+    }
+
+    #[test]
+    fn test_statements() {
+        let source = "
+            : main
+                clear
+                v0 := 5  v1 := v0  v2 += 1  v2 -= 1  v3 =- v4
+                i := smile  i := hex v1  i += v2
+                v5 := random 0x0F  v6 := delay  v7 := key
+                delay := v6  buzzer := v7
+                sprite v0 v1 5  bcd v3  save v3  load v3
+                if v0 == 1 then v1 := 2
+                if v0 != v1 then return
+                if v2 key then jump main
+                draw
+            : draw ;
+            : smile 0xF0 0b1001 -1
+        ";
+        assert_eq!(
+            vec![
+                0x00, 0xE0, 0x60, 0x05, 0x81, 0x00, 0x72, 0x01, 0x72, 0xFF, 0x83, 0x47, 0xA2, 0x34, 0xF1, 0x29,
+                0xF2, 0x1E, 0xC5, 0x0F, 0xF6, 0x07, 0xF7, 0x0A, 0xF6, 0x15, 0xF7, 0x18, 0xD0, 0x15, 0xF3, 0x33,
+                0xF3, 0x55, 0xF3, 0x65, 0x40, 0x01, 0x61, 0x02, 0x50, 0x10, 0x00, 0xEE, 0xE2, 0xA1, 0x12, 0x00,
+                0x22, 0x32, 0x00, 0xEE, 0xF0, 0x09, 0xFF,
+            ],
+            rom(source)
+        );
+    }
+
+    #[test]
+    fn test_control_flow() {
+        let source = "
+            : main
+                loop
+                    if v0 == 3 begin
+                        v1 := 1
+                    else
+                        v1 := 2
+                    end
+                    while v0 != 9
+                    v0 += 1
+                again
+        ";
+        assert_eq!(
+            vec![
+                0x30, 0x03, 0x12, 0x08, //if v0 == 3 begin
+                0x61, 0x01, 0x12, 0x0A, //else
+                0x61, 0x02, //end
+                0x40, 0x09, 0x12, 0x12, //while
+                0x70, 0x01, 0x12, 0x00, //again
+            ],
+            rom(source)
+        );
+
+        let mut cpu = CPU::new(Memory::new());
+        compile(source).unwrap().load(&mut cpu.memory);
+        for _ in 0..200 {
+            cpu.step();
+        }
+        assert_eq!((9, 2), (cpu.read_register(0), cpu.read_register(1)));
+    }
+
+    #[test]
+    fn test_directives() {
+        let source = "
+            :const WIDTH 64
+            :calc HALF { ( WIDTH / 2 ) - 1 }
+            :calc RIGHT { 2 * 3 + 1 }
+            :alias x v3
+            :macro set reg value { reg := value }
+            jump next
+            : main
+                set x HALF
+                set v0 RIGHT
+            :next target
+                v1 := 0
+                i := target
+            :org 0x220
+            : next main
+        ";
+        let rom = rom(source);
+        assert_eq!(vec![0x12, 0x04, 0x12, 0x20, 0x63, 0x1F, 0x60, 0x08, 0x61, 0x00, 0xA2, 0x09], rom[..12]);
+        assert_eq!(vec![0x22, 0x04], rom[0x20..]);
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = |source: &str| compile(source).unwrap_err();
+        assert_eq!("line 3: undefined name nowhere", error(": main\n clear\n jump nowhere").to_string());
+        assert_eq!(2, error(": main\n if v0 == 1 begin\n clear").line);
+        assert_eq!(1, error(": main end").line);
+        assert_eq!("unexpected end of program", error(": main\n v0 +=").message);
+        assert_eq!("invalid name v0", error(":const v0 1").message);
+        assert_eq!(2, error(": main\n v0 := 300").line);
+        assert_eq!(2, error(": a\n: a").line);
+        assert!(error(":macro m { m } : main m").message.contains("too many expansions"));
+        assert_eq!("division by zero", error(":calc x { 1 / 0 }").message);
+        assert_eq!("main is never defined", error(":macro m { : main }\nclear").message);
+    }
+
+    #[test]
+    fn test_source_map() {
+        let source = ": main\n  v0 := 1\n\n  loop again # spin\n";
+        let program = compile(source).unwrap();
+        assert_eq!(Some(2), program.source_map.line(0x200));
+        assert_eq!(Some(4), program.source_map.line(0x202));
+        assert_eq!(None, program.source_map.line(0x204));
+        assert_eq!(Some("loop again # spin"), program.source_map.text(4));
+        assert_eq!(Some(0x202), program.source_map.addr(4));
+
+        let mut debugger = Debugger::new(CPU::new(Memory::new()));
+        program.load(&mut debugger.cpu.memory);
+        debugger.source_map = Some(program.source_map);
+        debugger.step();
+        assert!(debugger.current_instruction().ends_with("line 4: loop again # spin"));
+    }
+
+    #[test]
+    fn test_disassembly_round_trip() {
+        let mut rng = Rng::new(42);
+        for length in 0..200 {
+            let rom: Vec<u8> = (0..length).map(|_| rng.next_u8()).collect();
+            let listing = disassemble(&rom, Syntax::Octo);
+            assert_eq!(Ok(rom), compile(&listing).map(|program| program.rom), "{}", listing);
+        }
+    }
+}
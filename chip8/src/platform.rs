@@ -0,0 +1,121 @@
+// Platform profiles. SUPER-CHIP and XO-CHIP add opcodes the Instruction
+// decoder does not know, the emulator itself only runs CHIP-8 but the
+// analysis tools follow code through the extensions of the profile.
+use crate::cpu::Quirks;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum Platform {
+    Chip8,
+    //super-chip 1.1
+    SuperChip,
+    //a superset of super-chip
+    XoChip,
+}
+
+impl Platform {
+    pub fn name(self) -> &'static str {
+        match self {
+            Platform::Chip8 => "CHIP-8",
+            Platform::SuperChip => "SUPER-CHIP",
+            Platform::XoChip => "XO-CHIP",
+        }
+    }
+
+    // chip8, schip or xochip, case insensitive
+    pub fn parse(name: &str) -> Option<Platform> {
+        let platforms = [
+            ("chip8", Platform::Chip8),
+            ("schip", Platform::SuperChip),
+            ("superchip", Platform::SuperChip),
+            ("xochip", Platform::XoChip),
+        ];
+        platforms.iter().find(|(alias, _)| alias.eq_ignore_ascii_case(name)).map(|(_, platform)| *platform)
+    }
+
+    // how the base instructions behave on the original interpreter of the
+    // platform
+    pub fn quirks(self) -> Quirks {
+        match self {
+            Platform::Chip8 => Quirks {
+                shift_uses_vy: true,
+                load_store_increments_i: true,
+                jump_uses_vx: false,
+                logic_resets_vf: true,
+            },
+            Platform::SuperChip => Quirks {
+                shift_uses_vy: false,
+                load_store_increments_i: false,
+                jump_uses_vx: true,
+                logic_resets_vf: false,
+            },
+            Platform::XoChip => Quirks {
+                shift_uses_vy: true,
+                load_store_increments_i: true,
+                jump_uses_vx: false,
+                logic_resets_vf: false,
+            },
+        }
+    }
+
+    // whether the opcode is an extension this platform has
+    pub fn supports(self, opcode: u16) -> bool {
+        extension(opcode).is_some_and(|(platform, _)| platform <= self)
+    }
+}
+
+// extension opcodes with the first platform that has them
+pub fn extension(opcode: u16) -> Option<(Platform, &'static str)> {
+    let x = opcode >> 8 & 0xF;
+    match opcode {
+        0x00C0..=0x00CF => Some((Platform::SuperChip, "scroll-down")),
+        0x00FB => Some((Platform::SuperChip, "scroll-right")),
+        0x00FC => Some((Platform::SuperChip, "scroll-left")),
+        0x00FD => Some((Platform::SuperChip, "exit")),
+        0x00FE => Some((Platform::SuperChip, "lores")),
+        0x00FF => Some((Platform::SuperChip, "hires")),
+        _ if opcode & 0xF0FF == 0xF030 => Some((Platform::SuperChip, "bighex")),
+        _ if opcode & 0xF0FF == 0xF075 && x < 8 => Some((Platform::SuperChip, "saveflags")),
+        _ if opcode & 0xF0FF == 0xF085 && x < 8 => Some((Platform::SuperChip, "loadflags")),
+        _ if opcode & 0xF0FF == 0xF075 || opcode & 0xF0FF == 0xF085 => Some((Platform::XoChip, "flags")),
+        0x00D0..=0x00DF => Some((Platform::XoChip, "scroll-up")),
+        0xF000 => Some((Platform::XoChip, "i := long")),
+        0xF002 => Some((Platform::XoChip, "audio")),
+        _ if opcode & 0xF00F == 0x5002 => Some((Platform::XoChip, "save range")),
+        _ if opcode & 0xF00F == 0x5003 => Some((Platform::XoChip, "load range")),
+        _ if opcode & 0xF0FF == 0xF001 => Some((Platform::XoChip, "plane")),
+        _ if opcode & 0xF0FF == 0xF03A => Some((Platform::XoChip, "pitch")),
+        _ => None,
+    }
+}
+
+// bytes taken by the instruction, `i := long` carries a 16 bit address
+pub fn instruction_len(opcode: u16) -> u16 {
+    if opcode == 0xF000 {
+        4
+    } else {
+        2
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::platform::{extension, Platform};
+
+    #[test]
+    fn test_profiles() {
+        assert_eq!(Some(Platform::SuperChip), Platform::parse("SChip"));
+        assert_eq!(None, Platform::parse("chip48"));
+        assert!(!Platform::Chip8.supports(0x00FF));
+        assert!(Platform::SuperChip.supports(0x00FF));
+        assert!(Platform::XoChip.supports(0x00FF));
+        assert!(!Platform::SuperChip.supports(0x5122));
+        assert!(Platform::XoChip.supports(0x5122));
+        assert!(Platform::SuperChip.supports(0xF775));
+        assert!(!Platform::SuperChip.supports(0xF875));
+        assert_eq!(None, extension(0x00E0));
+        assert_eq!(None, extension(0x0123));
+        assert!(Platform::Chip8.quirks().logic_resets_vf);
+        assert!(Platform::SuperChip.quirks().jump_uses_vx);
+        assert!(!Platform::XoChip.quirks().jump_uses_vx);
+    }
+}
@@ -0,0 +1,250 @@
+// Profiler. Counts the instructions and cycles spent at every address and
+// in every subroutine, a subroutine being a CALL target with the load
+// address as main. The call stack is followed through CPU.stack: the
+// exclusive cost of a subroutine is what ran while it was on top, the
+// inclusive cost adds everything it called.
+//
+// Every instruction takes one cycle in this emulator. A cost function that
+// weighs opcodes differently profiles for the timing of another interpreter.
+//
+// Folded stacks, one line per call stack with its cycles, are read by
+// flamegraph tools:
+//
+//   main;sub_206 40
+use crate::cpu::CPU;
+use crate::disasm::{Disassembly, Syntax};
+use crate::memory::PROGRAM_LOAD_OFFSET;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+pub struct Cost {
+    pub instructions: u64,
+    pub cycles: u64,
+}
+
+impl Cost {
+    fn add(&mut self, cycles: u64) {
+        self.instructions += 1;
+        self.cycles += cycles;
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+pub struct Hotspot {
+    //last opcode seen at the address
+    pub opcode: u16,
+    pub cost: Cost,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+pub struct SubroutineCost {
+    pub calls: u64,
+    pub inclusive: Cost,
+    pub exclusive: Cost,
+}
+
+pub fn unit_cost(_opcode: u16) -> u64 {
+    1
+}
+
+// main for the load address, sub_xxx like the disassembler otherwise
+pub fn subroutine_name(entry: u16) -> String {
+    if entry as usize == PROGRAM_LOAD_OFFSET {
+        "main".to_string()
+    } else {
+        format!("sub_{:03x}", entry)
+    }
+}
+
+fn percent(part: u64, total: u64) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        part as f64 * 100.0 / total as f64
+    }
+}
+
+pub struct Profiler {
+    pub cost: fn(u16) -> u64,
+    //entries on the call stack, main at the bottom and one per CPU.stack slot
+    frames: Vec<u16>,
+    pub total: Cost,
+    pub addresses: BTreeMap<u16, Hotspot>,
+    pub subroutines: BTreeMap<u16, SubroutineCost>,
+    //cycles per call stack
+    pub stacks: BTreeMap<Vec<u16>, u64>,
+}
+
+impl Default for Profiler {
+    fn default() -> Profiler {
+        Profiler::new()
+    }
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler::with_cost(unit_cost)
+    }
+
+    pub fn with_cost(cost: fn(u16) -> u64) -> Profiler {
+        Profiler {
+            cost,
+            frames: vec![],
+            total: Cost::default(),
+            addresses: BTreeMap::new(),
+            subroutines: BTreeMap::new(),
+            stacks: BTreeMap::new(),
+        }
+    }
+
+    fn enter(&mut self, entry: u16) {
+        self.frames.push(entry);
+        self.subroutines.entry(entry).or_default().calls += 1;
+    }
+
+    // matches the frames to the stack pointer, the stack can change behind
+    // the profiler's back when a state is loaded
+    fn sync(&mut self, cpu: &CPU) {
+        if self.frames.is_empty() {
+            self.enter(PROGRAM_LOAD_OFFSET as u16);
+        }
+        self.frames.truncate(cpu.get_sp() + 1);
+        while self.frames.len() < cpu.get_sp() + 1 {
+            self.enter(cpu.get_pc());
+        }
+    }
+
+    // executes one instruction on `cpu` and counts it
+    pub fn step(&mut self, cpu: &mut CPU) {
+        self.sync(cpu);
+        let opcode = cpu.fetch_current_instruction() as u16;
+        let cycles = (self.cost)(opcode);
+        self.total.add(cycles);
+        let hotspot = self.addresses.entry(cpu.get_pc()).or_default();
+        hotspot.opcode = opcode;
+        hotspot.cost.add(cycles);
+        let top = *self.frames.last().unwrap();
+        self.subroutines.entry(top).or_default().exclusive.add(cycles);
+        //a recursive subroutine counts once
+        let active: BTreeSet<u16> = self.frames.iter().cloned().collect();
+        for entry in active {
+            self.subroutines.entry(entry).or_default().inclusive.add(cycles);
+        }
+        *self.stacks.entry(self.frames.clone()).or_insert(0) += cycles;
+
+        //a CALL leaves the callee's entry in PC
+        cpu.step();
+        self.sync(cpu);
+    }
+
+    pub fn run_frame(&mut self, cpu: &mut CPU, cycles: usize) {
+        cpu.run_frame_with(cycles, |cpu| self.step(cpu));
+    }
+
+    pub fn run(&mut self, cpu: &mut CPU, cycles: usize) {
+        cpu.run_with(cycles, |cpu| self.step(cpu));
+    }
+
+    // one `main;sub_206 40` line per call stack
+    pub fn folded(&self) -> String {
+        let mut out = String::new();
+        for (stack, cycles) in self.stacks.iter() {
+            let names: Vec<String> = stack.iter().map(|&entry| subroutine_name(entry)).collect();
+            writeln!(out, "{} {}", names.join(";"), cycles).unwrap();
+        }
+        out
+    }
+
+    // subroutines by exclusive cycles and the `limit` hottest addresses
+    pub fn report(&self, limit: usize) -> String {
+        let total = self.total.cycles;
+        let mut out = String::new();
+        writeln!(out, "{} instructions, {} cycles", self.total.instructions, total).unwrap();
+
+        writeln!(out, "\n{:<12} {:>8} {:>18} {:>18}", "subroutine", "calls", "inclusive", "exclusive").unwrap();
+        let mut subroutines: Vec<(&u16, &SubroutineCost)> = self.subroutines.iter().collect();
+        subroutines.sort_by_key(|(&entry, cost)| (std::cmp::Reverse(cost.exclusive.cycles), entry));
+        for (&entry, cost) in subroutines {
+            writeln!(
+                out,
+                "{:<12} {:>8} {:>10} {:>6.1}% {:>10} {:>6.1}%",
+                subroutine_name(entry),
+                cost.calls,
+                cost.inclusive.cycles,
+                percent(cost.inclusive.cycles, total),
+                cost.exclusive.cycles,
+                percent(cost.exclusive.cycles, total),
+            )
+            .unwrap();
+        }
+
+        writeln!(out, "\n{:<8} {:<20} {:>8} {:>18}", "address", "instruction", "count", "cycles").unwrap();
+        let mut addresses: Vec<(&u16, &Hotspot)> = self.addresses.iter().collect();
+        addresses.sort_by_key(|(&addr, hotspot)| (std::cmp::Reverse(hotspot.cost.cycles), addr));
+        //no rom, targets are printed as addresses
+        let disassembly = Disassembly::new(&[]);
+        for (&addr, hotspot) in addresses.into_iter().take(limit) {
+            writeln!(
+                out,
+                "0x{:03X}    {:<20} {:>8} {:>10} {:>6.1}%",
+                addr,
+                disassembly.format_instruction(hotspot.opcode, Syntax::Cowgod),
+                hotspot.cost.instructions,
+                hotspot.cost.cycles,
+                percent(hotspot.cost.cycles, total),
+            )
+            .unwrap();
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::asm::assemble;
+    use crate::cpu::CPU;
+    use crate::memory::Memory;
+    use crate::profile::{Cost, Profiler, SubroutineCost};
+
+    fn profile(mut profiler: Profiler, cycles: usize) -> Profiler {
+        let mut mem = Memory::new();
+        mem.load_program(&assemble("main: LD V1, 3\n CALL sub\n JP main\nsub: ADD I, V1\n RET").unwrap());
+        let mut cpu = CPU::new(mem);
+        profiler.run(&mut cpu, cycles);
+        profiler
+    }
+
+    #[test]
+    fn test_costs() {
+        let profiler = profile(Profiler::new(), 10);
+        let cost = |n| Cost { instructions: n, cycles: n };
+        assert_eq!(cost(10), profiler.total);
+        assert_eq!(SubroutineCost { calls: 1, inclusive: cost(10), exclusive: cost(6) }, profiler.subroutines[&0x200]);
+        assert_eq!(SubroutineCost { calls: 2, inclusive: cost(4), exclusive: cost(4) }, profiler.subroutines[&0x206]);
+        assert_eq!(cost(2), profiler.addresses[&0x208].cost);
+        assert_eq!("main 6\nmain;sub_206 4\n", profiler.folded());
+
+        //CALL takes 3 cycles
+        let profiler = profile(Profiler::with_cost(|opcode| if opcode >> 12 == 2 { 3 } else { 1 }), 10);
+        assert_eq!(Cost { instructions: 10, cycles: 14 }, profiler.total);
+        assert_eq!(Cost { instructions: 6, cycles: 10 }, profiler.subroutines[&0x200].exclusive);
+        assert_eq!("main 10\nmain;sub_206 4\n", profiler.folded());
+    }
+
+    #[test]
+    fn test_report() {
+        let expected = "\
+10 instructions, 10 cycles
+
+subroutine      calls          inclusive          exclusive
+main                1         10  100.0%          6   60.0%
+sub_206             2          4   40.0%          4   40.0%
+
+address  instruction             count             cycles
+0x200    LD V1, 0x03                 2          2   20.0%
+0x202    CALL 0x206                  2          2   20.0%
+";
+        assert_eq!(expected, profile(Profiler::new(), 10).report(2));
+    }
+}
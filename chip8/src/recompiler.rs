@@ -0,0 +1,413 @@
+use crate::instructions::Instruction;
+use crate::cpu::{CPU, CYCLES_PER_FRAME};
+use crate::memory::{MEM_SIZE, PROGRAM_LOAD_OFFSET};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Block {
+    pub start: u16,
+    //exclusive, covers every byte the block was compiled from
+    pub end: u16,
+    pub instructions: Vec<(u16, Instruction, u32)>,
+    pub exit: Exit,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Exit {
+    //continue with the block starting at the given address
+    Fallthrough(u16),
+    Jump(u16),
+    Call(u16, u16),
+    Ret,
+    //(instruction, value, taken, not taken)
+    Skip(Instruction, u32, u16, u16),
+    //hand the instruction at the given address to CPU::step
+    Interpret(u16),
+}
+
+pub struct Recompiler<'a> {
+    rom: &'a [u8],
+    pub blocks: BTreeMap<u16, Block>,
+}
+
+impl<'a> Recompiler<'a> {
+    pub fn new(rom: &'a [u8]) -> Recompiler<'a> {
+        let mut recompiler = Recompiler {
+            rom,
+            blocks: BTreeMap::new(),
+        };
+        recompiler.discover();
+        recompiler
+    }
+
+    fn rom_end(&self) -> usize {
+        (PROGRAM_LOAD_OFFSET + self.rom.len()).min(MEM_SIZE)
+    }
+
+    fn in_rom(&self, addr: u16) -> bool {
+        let addr = addr as usize;
+        addr >= PROGRAM_LOAD_OFFSET && addr + 1 < self.rom_end()
+    }
+
+    fn fetch(&self, addr: u16) -> u32 {
+        let offset = addr as usize - PROGRAM_LOAD_OFFSET;
+        (self.rom[offset] as u32) << 8 | self.rom[offset + 1] as u32
+    }
+
+    // instructions that are translated to straight-line rust code
+    fn is_inlined(instr: Instruction) -> bool {
+        matches!(
+            instr,
+            Instruction::LD_VX_BT
+            | Instruction::ADD_VX_BT
+            | Instruction::LD_VX_VY
+            | Instruction::OR_VX_VY
+            | Instruction::AND_VX_VY
+            | Instruction::XOR_VX_VY
+            | Instruction::ADD_VX_VY
+            | Instruction::SUB_VX_VY
+            | Instruction::SHR_VX_VY
+            | Instruction::SUBN_VX_VY
+            | Instruction::SHL_VX_VY
+            | Instruction::LD_I_ADDR
+            | Instruction::CLS
+            | Instruction::DRW_VX_VY_NIB
+            | Instruction::LD_VX_DT
+            | Instruction::LD_DT_VX
+            | Instruction::LD_ST_VX
+        )
+    }
+
+    fn is_skip(instr: Instruction) -> bool {
+        matches!(
+            instr,
+            Instruction::SE_VX_BT
+            | Instruction::SNE_VX_BT
+            | Instruction::SE_VX_VY
+            | Instruction::SNE_VX_VY
+        )
+    }
+
+    // walks the reachable code from the load offset and collects every
+    // address a block has to start at
+    fn find_leaders(&self) -> BTreeSet<u16> {
+        let mut leaders = BTreeSet::new();
+        let mut visited = BTreeSet::new();
+        let mut work = vec![PROGRAM_LOAD_OFFSET as u16];
+        leaders.insert(PROGRAM_LOAD_OFFSET as u16);
+
+        while let Some(mut addr) = work.pop() {
+            while self.in_rom(addr) && visited.insert(addr) {
+                let (instr, value) = Instruction::decode(self.fetch(addr));
+                let next = addr + 2;
+                let mut targets = vec![];
+                let mut terminates = true;
+                match instr {
+                    Instruction::JP => targets.push((value & 0x0FFF) as u16),
+                    Instruction::CALL => {
+                        targets.push((value & 0x0FFF) as u16);
+                        targets.push(next);
+                    }
+                    Instruction::RET => {}
+                    i if Recompiler::is_skip(i) => {
+                        targets.push(next);
+                        targets.push(next + 2);
+                    }
+                    i if Recompiler::is_inlined(i) => terminates = false,
+                    Instruction::INVALID | Instruction::JP_V0_ADDR => {}
+                    _ => targets.push(next),
+                }
+                for target in targets {
+                    if self.in_rom(target) {
+                        leaders.insert(target);
+                        work.push(target);
+                    }
+                }
+                if terminates {
+                    break;
+                }
+                addr = next;
+            }
+        }
+
+        leaders
+    }
+
+    fn discover(&mut self) {
+        let leaders = self.find_leaders();
+        for &start in leaders.iter() {
+            let mut block = Block {
+                start,
+                end: start,
+                instructions: vec![],
+                exit: Exit::Interpret(start),
+            };
+            let mut addr = start;
+            loop {
+                if addr != start && leaders.contains(&addr) {
+                    block.exit = Exit::Fallthrough(addr);
+                    break;
+                }
+                if !self.in_rom(addr) {
+                    block.exit = Exit::Interpret(addr);
+                    break;
+                }
+                let (instr, value) = Instruction::decode(self.fetch(addr));
+                if Recompiler::is_inlined(instr) {
+                    block.instructions.push((addr, instr, value));
+                    addr += 2;
+                    continue;
+                }
+                block.exit = match instr {
+                    Instruction::JP => Exit::Jump((value & 0x0FFF) as u16),
+                    Instruction::CALL => Exit::Call((value & 0x0FFF) as u16, addr + 2),
+                    Instruction::RET => Exit::Ret,
+                    i if Recompiler::is_skip(i) => Exit::Skip(i, value, addr + 4, addr + 2),
+                    _ => Exit::Interpret(addr),
+                };
+                if let Exit::Interpret(_) = block.exit {
+                    break;
+                }
+                addr += 2;
+                break;
+            }
+            block.end = addr;
+            self.blocks.insert(start, block);
+        }
+    }
+
+    fn lower(out: &mut String, instr: Instruction, value: u32) {
+        let x = CPU::get_x_reg(value);
+        let y = CPU::get_y_reg(value);
+        let byte = value & 0x00FF;
+        let _ = match instr {
+            Instruction::LD_VX_BT => writeln!(out, "    cpu.write_register(0x{:X}, 0x{:02X});", x, byte),
+            Instruction::ADD_VX_BT => writeln!(
+                out,
+                "    cpu.write_register(0x{:X}, cpu.read_register(0x{:X}).wrapping_add(0x{:02X}));",
+                x, x, byte
+            ),
+            Instruction::LD_VX_VY => writeln!(
+                out,
+                "    cpu.write_register(0x{:X}, cpu.read_register(0x{:X}));",
+                x, y
+            ),
+            Instruction::OR_VX_VY | Instruction::AND_VX_VY | Instruction::XOR_VX_VY => {
+                let op = match instr {
+                    Instruction::OR_VX_VY => "|",
+                    Instruction::AND_VX_VY => "&",
+                    _ => "^",
+                };
+                writeln!(
+                    out,
+                    "    cpu.write_register(0x{:X}, cpu.read_register(0x{:X}) {} cpu.read_register(0x{:X}));\n    if cpu.quirks.logic_resets_vf {{\n        cpu.write_register(0xF, 0);\n    }}",
+                    x, x, op, y
+                )
+            }
+            Instruction::ADD_VX_VY => writeln!(
+                out,
+                "    {{\n        let result = cpu.read_register(0x{:X}) as u16 + cpu.read_register(0x{:X}) as u16;\n        cpu.write_register(0xF, (result > 0xFF) as u8);\n        cpu.write_register(0x{:X}, result as u8);\n    }}",
+                x, y, x
+            ),
+            Instruction::SUB_VX_VY | Instruction::SUBN_VX_VY => {
+                let (a, b) = if instr == Instruction::SUB_VX_VY { ("x", "y") } else { ("y", "x") };
+                writeln!(
+                    out,
+                    "    {{\n        let x = cpu.read_register(0x{:X});\n        let y = cpu.read_register(0x{:X});\n        cpu.write_register(0xF, ({} > {}) as u8);\n        cpu.write_register(0x{:X}, {}.wrapping_sub({}));\n    }}",
+                    x, y, a, b, x, a, b
+                )
+            }
+            Instruction::SHR_VX_VY => writeln!(
+                out,
+                "    {{\n        let x = cpu.read_register(cpu.shift_source(0x{:04X}));\n        cpu.write_register(0xF, x & 0x1);\n        cpu.write_register(0x{:X}, x >> 1);\n    }}",
+                value, x
+            ),
+            Instruction::SHL_VX_VY => writeln!(
+                out,
+                "    {{\n        let x = cpu.read_register(cpu.shift_source(0x{:04X}));\n        cpu.write_register(0xF, (x >= 0x80) as u8);\n        cpu.write_register(0x{:X}, x.wrapping_add(x));\n    }}",
+                value, x
+            ),
+            Instruction::LD_I_ADDR => writeln!(out, "    cpu.set_i(0x{:03X});", value & 0x0FFF),
+            Instruction::CLS => writeln!(out, "    cpu.display.clear();"),
+            Instruction::DRW_VX_VY_NIB => writeln!(
+                out,
+                "    {{\n        let x = cpu.read_register(0x{:X});\n        let y = cpu.read_register(0x{:X});\n        cpu.draw_sprite(x, y, {});\n    }}",
+                x, y, value & 0x000F
+            ),
+            Instruction::LD_VX_DT => writeln!(out, "    cpu.write_register(0x{:X}, cpu.get_dt());", x),
+            Instruction::LD_DT_VX => writeln!(out, "    cpu.set_dt(cpu.read_register(0x{:X}));", x),
+            Instruction::LD_ST_VX => writeln!(out, "    cpu.set_st(cpu.read_register(0x{:X}));", x),
+            _ => unreachable!(),
+        };
+    }
+
+    fn lower_skip(instr: Instruction, value: u32) -> String {
+        let x = CPU::get_x_reg(value);
+        let y = CPU::get_y_reg(value);
+        let byte = value & 0x00FF;
+        match instr {
+            Instruction::SE_VX_BT => format!("cpu.read_register(0x{:X}) == 0x{:02X}", x, byte),
+            Instruction::SNE_VX_BT => format!("cpu.read_register(0x{:X}) != 0x{:02X}", x, byte),
+            Instruction::SE_VX_VY => format!("cpu.read_register(0x{:X}) == cpu.read_register(0x{:X})", x, y),
+            _ => format!("cpu.read_register(0x{:X}) != cpu.read_register(0x{:X})", x, y),
+        }
+    }
+
+    fn emit_block(out: &mut String, block: &Block) {
+        //falling through or handing over to CPU::step executes nothing yet
+        let exit_ticks = matches!(block.exit, Exit::Jump(_) | Exit::Call(..) | Exit::Ret | Exit::Skip(..));
+        let cycles = if exit_ticks || !block.instructions.is_empty() { "cycles" } else { "_cycles" };
+        let _ = writeln!(out, "fn block_{:03x}(cpu: &mut CPU, {}: &mut usize) {{", block.start, cycles);
+        for &(addr, instr, value) in block.instructions.iter() {
+            let _ = writeln!(out, "    // 0x{:03X}: {:?} 0x{:04X}", addr, instr, value);
+            Recompiler::lower(out, instr, value);
+            let _ = writeln!(out, "    tick(cpu, cycles);");
+        }
+        let _ = match block.exit {
+            Exit::Fallthrough(addr) | Exit::Jump(addr) | Exit::Interpret(addr) => {
+                writeln!(out, "    cpu.set_pc(0x{:03X});", addr)
+            }
+            Exit::Call(target, ret) => {
+                writeln!(out, "    cpu.push_stack(0x{:03X});\n    cpu.set_pc(0x{:03X});", ret, target)
+            }
+            Exit::Ret => writeln!(out, "    let pc = cpu.pop_stack();\n    cpu.set_pc(pc);"),
+            Exit::Skip(instr, value, taken, not_taken) => writeln!(
+                out,
+                "    if {} {{\n        cpu.set_pc(0x{:03X});\n    }} else {{\n        cpu.set_pc(0x{:03X});\n    }}",
+                Recompiler::lower_skip(instr, value),
+                taken,
+                not_taken
+            ),
+        };
+        if exit_ticks {
+            let _ = writeln!(out, "    tick(cpu, cycles);");
+        }
+        let _ = writeln!(out, "}}\n");
+    }
+
+    // emits a standalone program that runs the rom through the compiled
+    // blocks and falls back to CPU::step for everything else
+    pub fn emit(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "// generated by chip8-vm recompile, do not edit");
+        let _ = writeln!(out, "#![allow(dead_code)]\n");
+        let _ = writeln!(out, "use chip8::{{cpu::CPU, memory::Memory}};\n");
+
+        let _ = writeln!(out, "const ROM: [u8; {}] = [", self.rom.len());
+        for chunk in self.rom.chunks(16) {
+            let bytes: Vec<String> = chunk.iter().map(|b| format!("0x{:02X}", b)).collect();
+            let _ = writeln!(out, "    {},", bytes.join(", "));
+        }
+        let _ = writeln!(out, "];\n");
+
+        let _ = writeln!(
+            out,
+            "// self-modifying code invalidates the compiled version of a block\nfn intact(cpu: &CPU, start: usize, end: usize) -> bool {{\n    cpu.memory.memory[start..end] == ROM[start - 0x{:X}..end - 0x{:X}]\n}}\n",
+            PROGRAM_LOAD_OFFSET, PROGRAM_LOAD_OFFSET
+        );
+
+        let _ = writeln!(
+            out,
+            "// counts one instruction, the timers tick after every {} like in CPU::run_frame\nfn tick(cpu: &mut CPU, cycles: &mut usize) {{\n    *cycles += 1;\n    if *cycles == {} {{\n        *cycles = 0;\n        cpu.tick_timers();\n    }}\n}}\n",
+            CYCLES_PER_FRAME, CYCLES_PER_FRAME
+        );
+
+        for block in self.blocks.values() {
+            Recompiler::emit_block(&mut out, block);
+        }
+
+        let _ = writeln!(
+            out,
+            "// `cycles` counts the instructions of the current frame\npub fn run_block(cpu: &mut CPU, cycles: &mut usize) {{\n    match cpu.get_pc() {{"
+        );
+        for block in self.blocks.values() {
+            if block.instructions.is_empty() {
+                if let Exit::Interpret(_) = block.exit {
+                    continue;
+                }
+            }
+            let _ = writeln!(
+                out,
+                "        0x{:03X} if intact(cpu, 0x{:03X}, 0x{:03X}) => block_{:03x}(cpu, cycles),",
+                block.start, block.start, block.end, block.start
+            );
+        }
+        let _ = writeln!(out, "        _ => {{\n            cpu.step();\n            tick(cpu, cycles);\n        }}\n    }}\n}}\n");
+
+        let _ = writeln!(
+            out,
+            "fn main() {{\n    let mut mem = Memory::new();\n    mem.load_program(&ROM);\n    let mut cpu = CPU::new(mem);\n    let mut cycles = 0;\n    loop {{\n        run_block(&mut cpu, &mut cycles);\n    }}\n}}"
+        );
+        out
+    }
+}
+
+pub fn recompile(rom: &[u8]) -> String {
+    Recompiler::new(rom).emit()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::recompiler::{Exit, Recompiler};
+
+    #[test]
+    fn test_block_discovery() {
+        let rom = vec![
+            // LD A, 0x01
+            0x6A, 0x01, //0x200
+            // CALL 0x20A
+            0x22, 0x0A, //0x202
+            // SE A, 0x02 -> skip
+            0x3A, 0x02, //0x204
+            // JP 0x204
+            0x12, 0x04, //0x206
+            // INVALID
+            0x00, 0x00, //0x208
+            // ADD A, 0x01
+            0x7A, 0x01, //0x20A
+            // RET
+            0x00, 0xEE, //0x20C
+        ];
+        let recompiler = Recompiler::new(&rom);
+        let starts: Vec<u16> = recompiler.blocks.keys().cloned().collect();
+        assert_eq!(vec![0x200, 0x204, 0x206, 0x208, 0x20A], starts);
+        assert_eq!(Exit::Call(0x20A, 0x204), recompiler.blocks[&0x200].exit);
+        assert_eq!(0x204, recompiler.blocks[&0x200].end);
+        assert_eq!(Exit::Jump(0x204), recompiler.blocks[&0x206].exit);
+        assert_eq!(Exit::Interpret(0x208), recompiler.blocks[&0x208].exit);
+        assert_eq!(Exit::Ret, recompiler.blocks[&0x20A].exit);
+        assert_eq!(1, recompiler.blocks[&0x20A].instructions.len());
+    }
+
+    #[test]
+    fn test_emit() {
+        let rom = vec![
+            // LD A, 0x01
+            0x6A, 0x01, //0x200
+            // RND A, 0x01 -> interpreted
+            0xCA, 0x01, //0x202
+            // JP 0x200
+            0x12, 0x00, //0x204
+        ];
+        let source = Recompiler::new(&rom).emit();
+        assert!(source.contains("fn block_200(cpu: &mut CPU, cycles: &mut usize)"));
+        assert!(source.contains("cpu.write_register(0xA, 0x01);"));
+        assert!(source.contains("0x200 if intact(cpu, 0x200, 0x202) => block_200(cpu, cycles),"));
+        assert!(source.contains("0x204 if intact(cpu, 0x204, 0x206) => block_204(cpu, cycles),"));
+        assert!(!source.contains("block_202(cpu, cycles),"));
+        assert!(source.contains("cpu.step();\n            tick(cpu, cycles);"));
+        //the write and the jump are both counted
+        assert!(source.contains("cpu.write_register(0xA, 0x01);\n    tick(cpu, cycles);"));
+        assert!(source.contains("cpu.set_pc(0x200);\n    tick(cpu, cycles);"));
+
+        let rom = vec![
+            // JP 0x202
+            0x12, 0x02, //0x200
+            // RND A, 0x01 -> a block that only hands over to CPU::step
+            0xCA, 0x01, //0x202
+        ];
+        let source = Recompiler::new(&rom).emit();
+        assert!(source.contains("fn block_202(cpu: &mut CPU, _cycles: &mut usize)"));
+    }
+}
@@ -0,0 +1,187 @@
+use crate::cpu::CPU;
+use crate::savestate::{compress, decompress};
+use std::collections::VecDeque;
+
+const REGS_SIZE: usize = 16 + 2 + 2 + 1 + 1 + 1;
+const STACK_SIZE: usize = 16 * 2;
+
+// fixed layout image of everything that changes while a rom runs. keeping
+// the layout fixed means consecutive frames xor to mostly zeros
+fn image(cpu: &CPU) -> Vec<u8> {
+    let mut out = Vec::with_capacity(cpu.memory.memory.len() + REGS_SIZE + STACK_SIZE + 10 + cpu.display.pixels.len());
+    out.extend_from_slice(&cpu.memory.memory);
+    for reg in 0..16 {
+        out.push(cpu.read_register(reg));
+    }
+    out.extend_from_slice(&cpu.get_i().to_le_bytes());
+    out.extend_from_slice(&cpu.get_pc().to_le_bytes());
+    out.push(cpu.get_sp() as u8);
+    out.push(cpu.get_dt());
+    out.push(cpu.get_st());
+    for slot in cpu.stack.iter() {
+        out.extend_from_slice(&slot.to_le_bytes());
+    }
+    out.extend_from_slice(&cpu.keys.to_le_bytes());
+    out.extend_from_slice(&cpu.rng.get_state().to_le_bytes());
+    out.extend_from_slice(&cpu.display.pixels);
+    out
+}
+
+fn apply_image(cpu: &mut CPU, image: &[u8]) {
+    let mem_size = cpu.memory.memory.len();
+    cpu.memory.memory.copy_from_slice(&image[..mem_size]);
+    let regs = &image[mem_size..mem_size + REGS_SIZE];
+    for reg in 0..16 {
+        cpu.write_register(reg, regs[reg as usize]);
+    }
+    cpu.set_i(u16::from_le_bytes([regs[16], regs[17]]));
+    cpu.set_pc(u16::from_le_bytes([regs[18], regs[19]]));
+    cpu.set_sp(regs[20] as usize);
+    cpu.set_dt(regs[21]);
+    cpu.set_st(regs[22]);
+    let mut at = mem_size + REGS_SIZE;
+    for slot in cpu.stack.iter_mut() {
+        *slot = u16::from_le_bytes([image[at], image[at + 1]]);
+        at += 2;
+    }
+    cpu.keys = u16::from_le_bytes([image[at], image[at + 1]]);
+    at += 2;
+    let mut rng = [0; 8];
+    rng.copy_from_slice(&image[at..at + 8]);
+    cpu.rng.set_state(u64::from_le_bytes(rng));
+    at += 8;
+    cpu.display.pixels.copy_from_slice(&image[at..]);
+}
+
+fn xor(a: &[u8], b: &[u8]) -> Vec<u8> {
+    a.iter().zip(b.iter()).map(|(x, y)| x ^ y).collect()
+}
+
+// keeps the newest snapshot in full and every older one as a compressed
+// xor delta against its successor, so stepping back undoes one delta
+pub struct RewindBuffer {
+    capacity: usize,
+    latest: Option<Vec<u8>>,
+    deltas: VecDeque<Vec<u8>>,
+}
+
+impl RewindBuffer {
+    // `capacity` is the number of frames that can be stepped back
+    pub fn new(capacity: usize) -> RewindBuffer {
+        RewindBuffer {
+            capacity,
+            latest: None,
+            deltas: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    // call once per frame
+    pub fn push(&mut self, cpu: &CPU) {
+        let current = image(cpu);
+        if let Some(previous) = self.latest.take() {
+            self.deltas.push_back(compress(&xor(&current, &previous)));
+            if self.deltas.len() > self.capacity {
+                self.deltas.pop_front();
+            }
+        }
+        self.latest = Some(current);
+    }
+
+    // restores the snapshot taken the frame before the newest one, the
+    // newest snapshot is dropped. returns false once nothing is left
+    pub fn step_back(&mut self, cpu: &mut CPU) -> bool {
+        let delta = match self.deltas.pop_back() {
+            Some(delta) => delta,
+            None => return false,
+        };
+        let latest = self.latest.take().unwrap();
+        let previous = xor(&latest, &decompress(&delta).unwrap());
+        apply_image(cpu, &previous);
+        self.latest = Some(previous);
+        true
+    }
+
+    // number of frames that can currently be stepped back
+    pub fn len(&self) -> usize {
+        self.deltas.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.deltas.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.latest = None;
+        self.deltas.clear();
+    }
+
+    // bytes held by snapshots and deltas
+    pub fn memory_usage(&self) -> usize {
+        self.latest.as_ref().map(|l| l.len()).unwrap_or(0) + self.deltas.iter().map(|d| d.len()).sum::<usize>()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu::CPU;
+    use crate::memory::Memory;
+    use crate::rewind::RewindBuffer;
+
+    fn counting_cpu() -> CPU {
+        let mut mem = Memory::new();
+        mem.load_program(&[
+            // ADD A, 0x01
+            0x7A, 0x01, //0x200
+            // LD I, 0x300
+            0xA3, 0x00, //0x202
+            // LD B, A
+            0xFA, 0x33, //0x204
+            // LD F, A
+            0xFA, 0x29, //0x206
+            // DRW A, A, 5
+            0xDA, 0xA5, //0x208
+            // JP 0x200
+            0x12, 0x00, //0x20A
+        ]);
+        CPU::new(mem)
+    }
+
+    #[test]
+    fn test_step_back() {
+        let mut cpu = counting_cpu();
+        let mut rewind = RewindBuffer::new(100);
+        let mut history = vec![];
+        for _ in 0..20 {
+            rewind.push(&cpu);
+            history.push(cpu.clone());
+            cpu.run_frame(6);
+        }
+        rewind.push(&cpu);
+        assert_eq!(20, rewind.len());
+        assert!(rewind.memory_usage() < 20 * 100 + 8 * 1024);
+
+        while let Some(expected) = history.pop() {
+            assert!(rewind.step_back(&mut cpu));
+            assert_eq!(expected, cpu);
+        }
+        assert!(!rewind.step_back(&mut cpu));
+        assert!(rewind.is_empty());
+    }
+
+    #[test]
+    fn test_capacity() {
+        let mut cpu = counting_cpu();
+        let mut rewind = RewindBuffer::new(3);
+        for _ in 0..10 {
+            rewind.push(&cpu);
+            cpu.run_frame(6);
+        }
+        assert_eq!(3, rewind.len());
+        let mut steps = 0;
+        while rewind.step_back(&mut cpu) {
+            steps += 1;
+        }
+        assert_eq!(3, steps);
+        assert_eq!(0x06, cpu.read_register(0xA));
+    }
+}
@@ -0,0 +1,69 @@
+// small xorshift64* generator backing RND. unlike the thread rng it can be
+// seeded and its whole state is a single word, which keeps runs reproducible
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        let mut rng = Rng { state: 0 };
+        rng.seed(seed);
+        rng
+    }
+
+    #[cfg(feature = "std")]
+    pub fn from_entropy() -> Rng {
+        Rng::new(rand::random())
+    }
+
+    pub fn seed(&mut self, seed: u64) {
+        //splitmix64 so that similar seeds still produce unrelated streams
+        let mut z = seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^= z >> 31;
+        //xorshift must never be seeded with zero
+        self.state = if z == 0 { 0x1 } else { z };
+    }
+
+    pub fn get_state(&self) -> u64 {
+        self.state
+    }
+
+    pub fn set_state(&mut self, state: u64) {
+        self.state = if state == 0 { 0x1 } else { state };
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    pub fn next_u8(&mut self) -> u8 {
+        (self.next_u64() >> 56) as u8
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::rng::Rng;
+
+    #[test]
+    fn test_seeded_sequence() {
+        let mut a = Rng::new(42);
+        let mut b = Rng::new(42);
+        let mut c = Rng::new(43);
+        let from_a: Vec<u8> = (0..16).map(|_| a.next_u8()).collect();
+        let from_b: Vec<u8> = (0..16).map(|_| b.next_u8()).collect();
+        let from_c: Vec<u8> = (0..16).map(|_| c.next_u8()).collect();
+        assert_eq!(from_a, from_b);
+        assert_ne!(from_a, from_c);
+
+        let mut restored = Rng::new(0);
+        restored.set_state(a.get_state());
+        assert_eq!(a.next_u64(), restored.next_u64());
+    }
+}
@@ -0,0 +1,331 @@
+// Save state format, all integers little endian:
+//
+//   magic "C8SS", version: u16
+//   chunk*: tag: [u8; 4], length: u32, payload: [u8; length]
+//
+// Readers skip chunks they do not know and keep the power-on state for
+// chunks that are missing, so a save written by an older version still
+// loads after new chunks were added. Chunks in version 1:
+//
+//   MEM  memory, zero run encoded
+//   REGS V0-VF, I: u16, PC: u16, SP: u8, DT: u8, ST: u8
+//   STCK 16 stack slots, u16 each
+//   KEYS keypad bitmask: u16
+//   DISP framebuffer, 8 pixels per byte, zero run encoded
+//   QRKS quirk flags: u8
+//   RNG  rng state: u64
+use crate::cpu::{Quirks, CPU};
+use crate::display::{HEIGHT, WIDTH};
+use crate::memory::{Memory, MEM_SIZE};
+use std::fmt;
+
+pub const MAGIC: [u8; 4] = *b"C8SS";
+pub const FORMAT_VERSION: u16 = 1;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum SaveStateError {
+    BadMagic,
+    UnsupportedVersion(u16),
+    Truncated,
+    Corrupt(&'static str),
+}
+
+impl fmt::Display for SaveStateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SaveStateError::BadMagic => write!(f, "not a save state"),
+            SaveStateError::UnsupportedVersion(version) => {
+                write!(f, "save state version {} is newer than {}", version, FORMAT_VERSION)
+            }
+            SaveStateError::Truncated => write!(f, "save state is truncated"),
+            SaveStateError::Corrupt(chunk) => write!(f, "corrupt {} chunk", chunk),
+        }
+    }
+}
+
+impl std::error::Error for SaveStateError {}
+
+// zeros are stored as 0x00 followed by the length of the run, everything
+// else is copied verbatim
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![];
+    let mut i = 0;
+    while i < data.len() {
+        if data[i] == 0 {
+            let mut run = 0;
+            while i < data.len() && data[i] == 0 && run < 0xFF {
+                run += 1;
+                i += 1;
+            }
+            out.push(0x00);
+            out.push(run as u8);
+        } else {
+            out.push(data[i]);
+            i += 1;
+        }
+    }
+    out
+}
+
+pub fn decompress(data: &[u8]) -> Option<Vec<u8>> {
+    let mut out = vec![];
+    let mut i = 0;
+    while i < data.len() {
+        if data[i] == 0 {
+            let run = *data.get(i + 1)?;
+            out.resize(out.len() + run as usize, 0);
+            i += 2;
+        } else {
+            out.push(data[i]);
+            i += 1;
+        }
+    }
+    Some(out)
+}
+
+fn push_chunk(out: &mut Vec<u8>, tag: &[u8; 4], payload: &[u8]) {
+    out.extend_from_slice(tag);
+    out.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    out.extend_from_slice(payload);
+}
+
+pub fn save(cpu: &CPU) -> Vec<u8> {
+    let mut out = vec![];
+    out.extend_from_slice(&MAGIC);
+    out.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+
+    push_chunk(&mut out, b"MEM ", &compress(&cpu.memory.memory));
+
+    let mut regs = vec![];
+    for reg in 0..16 {
+        regs.push(cpu.read_register(reg));
+    }
+    regs.extend_from_slice(&cpu.get_i().to_le_bytes());
+    regs.extend_from_slice(&cpu.get_pc().to_le_bytes());
+    regs.push(cpu.get_sp() as u8);
+    regs.push(cpu.get_dt());
+    regs.push(cpu.get_st());
+    push_chunk(&mut out, b"REGS", &regs);
+
+    let stack: Vec<u8> = cpu.stack.iter().flat_map(|slot| slot.to_le_bytes()).collect();
+    push_chunk(&mut out, b"STCK", &stack);
+    push_chunk(&mut out, b"KEYS", &cpu.keys.to_le_bytes());
+
+    let mut pixels = vec![0; WIDTH * HEIGHT / 8];
+    for (i, &pixel) in cpu.display.pixels.iter().enumerate() {
+        pixels[i / 8] |= (pixel & 0x1) << (7 - i % 8);
+    }
+    push_chunk(&mut out, b"DISP", &compress(&pixels));
+    push_chunk(&mut out, b"QRKS", &[cpu.quirks.to_bits()]);
+    push_chunk(&mut out, b"RNG ", &cpu.rng.get_state().to_le_bytes());
+    out
+}
+
+fn read_u16(data: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([data[at], data[at + 1]])
+}
+
+pub fn load(data: &[u8]) -> Result<CPU, SaveStateError> {
+    if data.len() < 6 {
+        return Err(SaveStateError::Truncated);
+    }
+    if data[0..4] != MAGIC {
+        return Err(SaveStateError::BadMagic);
+    }
+    let version = read_u16(data, 4);
+    if version > FORMAT_VERSION {
+        return Err(SaveStateError::UnsupportedVersion(version));
+    }
+
+    let mut cpu = CPU::new(Memory::new());
+    cpu.rng.set_state(0);
+    let mut at = 6;
+    while at < data.len() {
+        if at + 8 > data.len() {
+            return Err(SaveStateError::Truncated);
+        }
+        let tag = &data[at..at + 4];
+        let len = u32::from_le_bytes([data[at + 4], data[at + 5], data[at + 6], data[at + 7]]) as usize;
+        at += 8;
+        if at + len > data.len() {
+            return Err(SaveStateError::Truncated);
+        }
+        let payload = &data[at..at + len];
+        at += len;
+
+        match tag {
+            b"MEM " => {
+                let memory = decompress(payload).ok_or(SaveStateError::Corrupt("MEM"))?;
+                if memory.len() != MEM_SIZE {
+                    return Err(SaveStateError::Corrupt("MEM"));
+                }
+                cpu.memory.memory.copy_from_slice(&memory);
+            }
+            b"REGS" => {
+                if payload.len() < 23 {
+                    return Err(SaveStateError::Corrupt("REGS"));
+                }
+                for reg in 0..16 {
+                    cpu.write_register(reg, payload[reg as usize]);
+                }
+                cpu.set_i(read_u16(payload, 16));
+                //any pc the machine can be left with loads, CPU::fault
+                //reports the ones it cannot continue from
+                cpu.set_pc(read_u16(payload, 18));
+                if payload[20] as usize > cpu.stack.len() {
+                    return Err(SaveStateError::Corrupt("REGS"));
+                }
+                cpu.set_sp(payload[20] as usize);
+                cpu.set_dt(payload[21]);
+                cpu.set_st(payload[22]);
+            }
+            b"STCK" => {
+                if payload.len() < cpu.stack.len() * 2 {
+                    return Err(SaveStateError::Corrupt("STCK"));
+                }
+                for slot in 0..cpu.stack.len() {
+                    cpu.stack[slot] = read_u16(payload, slot * 2);
+                }
+            }
+            b"KEYS" => {
+                if payload.len() < 2 {
+                    return Err(SaveStateError::Corrupt("KEYS"));
+                }
+                cpu.keys = read_u16(payload, 0);
+            }
+            b"DISP" => {
+                let pixels = decompress(payload).ok_or(SaveStateError::Corrupt("DISP"))?;
+                if pixels.len() != WIDTH * HEIGHT / 8 {
+                    return Err(SaveStateError::Corrupt("DISP"));
+                }
+                for (i, pixel) in cpu.display.pixels.iter_mut().enumerate() {
+                    *pixel = (pixels[i / 8] >> (7 - i % 8)) & 0x1;
+                }
+            }
+            b"QRKS" => {
+                let flags = *payload.first().ok_or(SaveStateError::Corrupt("QRKS"))?;
+                cpu.quirks = Quirks::from_bits(flags);
+            }
+            b"RNG " => {
+                if payload.len() < 8 {
+                    return Err(SaveStateError::Corrupt("RNG"));
+                }
+                let mut state = [0; 8];
+                state.copy_from_slice(&payload[0..8]);
+                cpu.rng.set_state(u64::from_le_bytes(state));
+            }
+            _ => {}
+        }
+    }
+    Ok(cpu)
+}
+
+impl CPU {
+    pub fn save_state(&self) -> Vec<u8> {
+        save(self)
+    }
+
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), SaveStateError> {
+        *self = load(data)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu::{Fault, CPU};
+    use crate::memory::Memory;
+    use crate::savestate::{compress, decompress, load, save, SaveStateError};
+
+    fn running_cpu() -> CPU {
+        let mut mem = Memory::new();
+        mem.load_program(&[
+            // CALL 0x204
+            0x22, 0x04, //0x200
+            // RND 3, 0xFF
+            0xC3, 0xFF, //0x202
+            // LD I, 0x000
+            0xA0, 0x00, //0x204
+            // DRW 1, 2, 5
+            0xD1, 0x25, //0x206
+            // LD DT, 3
+            0xF3, 0x15, //0x208
+        ]);
+        let mut cpu = CPU::new(mem);
+        cpu.seed_rng(99);
+        cpu.quirks.jump_uses_vx = true;
+        cpu.write_register(0x1, 0x3E);
+        cpu.write_register(0x2, 0x05);
+        cpu.write_register(0x3, 0x21);
+        cpu.press_key(0xB);
+        for _ in 0..4 {
+            cpu.step();
+        }
+        cpu.set_st(0x09);
+        cpu
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let cpu = running_cpu();
+        let data = save(&cpu);
+        assert!(data.len() < 512);
+        let mut restored = load(&data).unwrap();
+        assert_eq!(cpu, restored);
+
+        let mut original = cpu.clone();
+        original.set_pc(0x202);
+        restored.set_pc(0x202);
+        original.step();
+        restored.step();
+        assert_eq!(original.read_register(0x3), restored.read_register(0x3));
+    }
+
+    #[test]
+    fn test_older_save_without_new_chunks() {
+        let cpu = running_cpu();
+        let data = save(&cpu);
+        //drop everything after the REGS chunk and append an unknown chunk
+        let regs_end = 6 + 8 + u32::from_le_bytes([data[10], data[11], data[12], data[13]]) as usize + 8 + 23;
+        let mut old = data[..regs_end].to_vec();
+        old.extend_from_slice(b"XTRA");
+        old.extend_from_slice(&3u32.to_le_bytes());
+        old.extend_from_slice(&[1, 2, 3]);
+
+        let restored = load(&old).unwrap();
+        assert_eq!(cpu.memory, restored.memory);
+        assert_eq!(cpu.get_pc(), restored.get_pc());
+        assert_eq!(cpu.get_dt(), restored.get_dt());
+        assert_eq!(0, restored.keys);
+        assert!(!restored.quirks.jump_uses_vx);
+    }
+
+    #[test]
+    fn test_invalid_data() {
+        let data = save(&running_cpu());
+        assert_eq!(Err(SaveStateError::BadMagic), load(b"NOPE\x01\x00").map(|_| ()));
+        assert_eq!(Err(SaveStateError::Truncated), load(&data[..data.len() - 1]).map(|_| ()));
+        let mut newer = data.clone();
+        newer[4] = 0xFF;
+        assert_eq!(Err(SaveStateError::UnsupportedVersion(0xFF)), load(&newer).map(|_| ()));
+    }
+
+    #[test]
+    fn test_faulting_pc() {
+        let mut cpu = running_cpu();
+        cpu.set_pc(0xFFF);
+        let restored = load(&save(&cpu)).unwrap();
+        assert_eq!(0xFFF, restored.get_pc());
+        assert_eq!(Some(Fault::PcOutOfMemory), restored.fault());
+    }
+
+    #[test]
+    fn test_zero_runs() {
+        let data = [0, 0, 0, 7, 0, 9, 9];
+        assert_eq!(vec![0, 3, 7, 0, 1, 9, 9], compress(&data));
+        assert_eq!(data.to_vec(), decompress(&compress(&data)).unwrap());
+        let zeros = vec![0; 600];
+        assert_eq!(zeros, decompress(&compress(&zeros)).unwrap());
+        assert_eq!(None, decompress(&[5, 0]));
+    }
+}
@@ -0,0 +1,241 @@
+use crate::cpu::CPU;
+use crate::movie::{state_hash, Movie, MovieError};
+use crate::savestate;
+use std::collections::BTreeMap;
+
+// tool-assisted editing on top of a movie. the editor keeps a save state
+// every `snapshot_interval` frames so that editing an old frame only
+// re-simulates from the closest snapshot before it
+pub struct TasEditor {
+    rom: Vec<u8>,
+    movie: Movie,
+    pub cpu: CPU,
+    //frames executed to reach `cpu`
+    frame: usize,
+    pub snapshot_interval: usize,
+    snapshots: BTreeMap<usize, Vec<u8>>,
+    branches: BTreeMap<String, Movie>,
+}
+
+impl TasEditor {
+    pub fn new(rom: &[u8], movie: Movie) -> Result<TasEditor, MovieError> {
+        let cpu = movie.power_on(rom)?;
+        let mut snapshots = BTreeMap::new();
+        snapshots.insert(0, savestate::save(&cpu));
+        Ok(TasEditor {
+            rom: rom.to_vec(),
+            movie,
+            cpu,
+            frame: 0,
+            snapshot_interval: 60,
+            snapshots,
+            branches: BTreeMap::new(),
+        })
+    }
+
+    pub fn frame(&self) -> usize {
+        self.frame
+    }
+
+    pub fn movie(&self) -> &Movie {
+        &self.movie
+    }
+
+    // runs the frame at the current position, recording its state hash
+    fn run_frame(&mut self) {
+        let frame = self.frame;
+        self.movie.run_frame(&mut self.cpu, frame);
+        let hash = state_hash(&self.cpu);
+        if frame < self.movie.state_hashes.len() {
+            self.movie.state_hashes[frame] = hash;
+        } else {
+            self.movie.state_hashes.push(hash);
+        }
+        self.frame += 1;
+        if self.frame.is_multiple_of(self.snapshot_interval.max(1)) {
+            self.snapshots.insert(self.frame, savestate::save(&self.cpu));
+        }
+    }
+
+    // plays one frame. with `keys` the input of that frame is replaced,
+    // at the end of the movie a new frame is appended
+    pub fn frame_advance(&mut self, keys: Option<u16>) {
+        if self.frame == self.movie.len() {
+            self.movie.inputs.push(keys.unwrap_or(0));
+        } else if let Some(keys) = keys {
+            self.set_input(self.frame, keys);
+        }
+        self.run_frame();
+    }
+
+    // moves to the state after `frame` frames, re-simulating from the
+    // nearest snapshot
+    pub fn seek(&mut self, frame: usize) {
+        let frame = frame.min(self.movie.len());
+        if frame < self.frame {
+            self.restore_snapshot(frame);
+        }
+        while self.frame < frame {
+            self.run_frame();
+        }
+    }
+
+    fn restore_snapshot(&mut self, at_or_before: usize) {
+        let (&start, state) = self.snapshots.range(..=at_or_before).next_back().unwrap();
+        self.cpu = savestate::load(state).unwrap();
+        self.frame = start;
+    }
+
+    // drops hashes and snapshots that depend on the input of `frame`
+    fn invalidate_from(&mut self, frame: usize) {
+        self.movie.state_hashes.truncate(frame);
+        let stale: Vec<usize> = self.snapshots.range(frame + 1..).map(|(&f, _)| f).collect();
+        for f in stale {
+            self.snapshots.remove(&f);
+        }
+        if frame < self.frame {
+            let current = self.frame.min(self.movie.len());
+            self.restore_snapshot(frame);
+            while self.frame < current {
+                self.run_frame();
+            }
+        }
+    }
+
+    // edits the input of any frame. everything simulated after it is
+    // invalidated, the editor stays at the current frame
+    pub fn set_input(&mut self, frame: usize, keys: u16) {
+        if frame >= self.movie.len() {
+            self.movie.inputs.resize(frame + 1, 0);
+        }
+        if self.movie.inputs[frame] == keys {
+            return;
+        }
+        self.movie.inputs[frame] = keys;
+        self.invalidate_from(frame);
+    }
+
+    // simulates the remaining frames so every frame has a state hash
+    pub fn finish(mut self) -> Movie {
+        let end = self.movie.len();
+        self.seek(end);
+        self.movie
+    }
+
+    pub fn save_branch(&mut self, name: &str) {
+        self.branches.insert(name.to_string(), self.movie.clone());
+    }
+
+    // replaces the current inputs with the branch and seeks back to the
+    // current frame
+    pub fn load_branch(&mut self, name: &str) -> bool {
+        let branch = match self.branches.get(name) {
+            Some(branch) => branch.clone(),
+            None => return false,
+        };
+        let first_difference = TasEditor::first_difference(&self.movie, &branch);
+        self.movie = branch;
+        if let Some(frame) = first_difference {
+            self.invalidate_from(frame);
+        }
+        true
+    }
+
+    pub fn delete_branch(&mut self, name: &str) -> bool {
+        self.branches.remove(name).is_some()
+    }
+
+    pub fn branches(&self) -> Vec<&str> {
+        self.branches.keys().map(|name| name.as_str()).collect()
+    }
+
+    pub fn branch(&self, name: &str) -> Option<&Movie> {
+        self.branches.get(name)
+    }
+
+    // first frame where the inputs of both movies differ
+    pub fn first_difference(a: &Movie, b: &Movie) -> Option<usize> {
+        let common = a.len().min(b.len());
+        (0..common)
+            .find(|&frame| a.inputs[frame] != b.inputs[frame])
+            .or(if a.len() != b.len() { Some(common) } else { None })
+    }
+
+    pub fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu::Quirks;
+    use crate::movie::{verify, Movie, Recorder};
+    use crate::tas::TasEditor;
+
+    fn rom() -> Vec<u8> {
+        vec![
+            // SKNP 0 -> key 0
+            0xE0, 0xA1, //0x200
+            // ADD 1, 0x01
+            0x71, 0x01, //0x202
+            // RND 2, 0xFF
+            0xC2, 0xFF, //0x204
+            // JP 0x200
+            0x12, 0x00, //0x206
+        ]
+    }
+
+    fn editor() -> TasEditor {
+        let mut editor = TasEditor::new(&rom(), Movie::new(&rom(), Quirks::default(), 4, 5)).unwrap();
+        editor.snapshot_interval = 4;
+        editor
+    }
+
+    #[test]
+    fn test_edit_past_frame() {
+        let mut editor = editor();
+        for frame in 0..20 {
+            editor.frame_advance(Some(if frame == 3 { 1 } else { 0 }));
+        }
+        assert_eq!(1, editor.cpu.read_register(0x1));
+
+        editor.set_input(9, 1);
+        assert_eq!(20, editor.frame());
+        assert_eq!(2, editor.cpu.read_register(0x1));
+
+        let mut recorder = Recorder::new(&rom(), Quirks::default(), 4, 5);
+        for frame in 0..20 {
+            recorder.frame(if frame == 3 || frame == 9 { 1 } else { 0 });
+        }
+        let expected = recorder.finish();
+        let movie = editor.finish();
+        assert_eq!(expected, movie);
+        assert_eq!(Ok(()), verify(&movie, &rom()));
+    }
+
+    #[test]
+    fn test_seek_and_branches() {
+        let mut editor = editor();
+        for _ in 0..10 {
+            editor.frame_advance(None);
+        }
+        editor.save_branch("idle");
+        editor.seek(2);
+        assert_eq!(2, editor.frame());
+        editor.frame_advance(Some(1));
+        editor.seek(10);
+        assert_eq!(1, editor.cpu.read_register(0x1));
+        editor.save_branch("press");
+
+        assert_eq!(vec!["idle", "press"], editor.branches());
+        assert_eq!(
+            Some(2),
+            TasEditor::first_difference(editor.branch("idle").unwrap(), editor.branch("press").unwrap())
+        );
+        assert!(editor.load_branch("idle"));
+        assert_eq!(10, editor.frame());
+        assert_eq!(0, editor.cpu.read_register(0x1));
+        assert!(!editor.load_branch("missing"));
+        assert_eq!(Ok(()), verify(&editor.finish(), &rom()));
+    }
+}
@@ -0,0 +1,200 @@
+// Builder for CPU tests written as inline assembly.
+//
+//   Machine::asm("
+//       LD VA, 0xAF
+//       ADD VA, 0x01
+//   ")
+//   .set(Register::V(0xB), 3)
+//   .steps(2)
+//   .expect(Register::V(0xA), 0xB0)
+//   .expect_memory(0x300, &[1, 5, 6]);
+//
+// Expectations describe the current state. They are checked before the
+// machine changes again and when it is dropped, every mismatch is reported
+// at once.
+use crate::asm::assemble;
+use crate::cpu::{Quirks, CPU};
+use crate::display::{HEIGHT, WIDTH};
+use crate::expr::Register;
+use crate::memory::Memory;
+
+//run_until gives up after this many instructions
+const RUN_LIMIT: usize = 10_000;
+
+enum Expectation {
+    Register(Register, usize),
+    Memory(usize, Vec<u8>),
+    //top left corner and rows of '#' and '.'
+    Screen(usize, usize, Vec<String>),
+}
+
+pub struct Machine {
+    pub cpu: CPU,
+    steps: usize,
+    expectations: Vec<Expectation>,
+}
+
+impl Machine {
+    pub fn asm(source: &str) -> Machine {
+        let rom = assemble(source).unwrap_or_else(|err| panic!("{}", err));
+        let mut memory = Memory::new();
+        memory.load_program(&rom);
+        Machine { cpu: CPU::new(memory), steps: 0, expectations: vec![] }
+    }
+
+    // any change to the machine, after checking the expectations so far
+    pub fn with<F: FnOnce(&mut CPU)>(mut self, change: F) -> Machine {
+        self.check();
+        change(&mut self.cpu);
+        self
+    }
+
+    pub fn quirks(self, quirks: Quirks) -> Machine {
+        self.with(|cpu| cpu.quirks = quirks)
+    }
+
+    pub fn set(self, register: Register, value: usize) -> Machine {
+        self.with(|cpu| match register {
+            Register::V(reg) => cpu.write_register(reg as u32, value as u8),
+            Register::I => cpu.set_i(value as u16),
+            Register::Pc => cpu.set_pc(value as u16),
+            Register::Sp => cpu.set_sp(value),
+            Register::Dt => cpu.set_dt(value as u8),
+            Register::St => cpu.set_st(value as u8),
+        })
+    }
+
+    pub fn poke(self, addr: usize, bytes: &[u8]) -> Machine {
+        self.with(|cpu| cpu.memory.memory[addr..addr + bytes.len()].copy_from_slice(bytes))
+    }
+
+    pub fn press_key(self, key: u8) -> Machine {
+        self.with(|cpu| cpu.press_key(key))
+    }
+
+    pub fn tick_timers(self) -> Machine {
+        self.with(|cpu| cpu.tick_timers())
+    }
+
+    pub fn steps(mut self, n: usize) -> Machine {
+        self.check();
+        for _ in 0..n {
+            self.cpu.step();
+            self.steps += 1;
+        }
+        self
+    }
+
+    pub fn run_until(mut self, pc: u16) -> Machine {
+        self.check();
+        for _ in 0..RUN_LIMIT {
+            if self.cpu.get_pc() == pc {
+                return self;
+            }
+            self.cpu.step();
+            self.steps += 1;
+        }
+        panic!("pc 0x{:03X} not reached after {} steps, stuck at 0x{:03X}", pc, RUN_LIMIT, self.cpu.get_pc());
+    }
+
+    pub fn expect(mut self, register: Register, value: usize) -> Machine {
+        self.expectations.push(Expectation::Register(register, value));
+        self
+    }
+
+    pub fn expect_memory(mut self, addr: usize, bytes: &[u8]) -> Machine {
+        self.expectations.push(Expectation::Memory(addr, bytes.to_vec()));
+        self
+    }
+
+    pub fn expect_screen(mut self, x: usize, y: usize, rows: &[&str]) -> Machine {
+        self.expectations.push(Expectation::Screen(x, y, rows.iter().map(|row| row.to_string()).collect()));
+        self
+    }
+
+    fn mismatch(&self, expectation: &Expectation) -> Option<String> {
+        match expectation {
+            Expectation::Register(register, value) => {
+                let actual = register.read(&self.cpu);
+                (actual != *value).then(|| format!("{}: expected 0x{:02X}, got 0x{:02X}", register, value, actual))
+            }
+            Expectation::Memory(addr, bytes) => {
+                let actual = &self.cpu.memory.memory[*addr..*addr + bytes.len()];
+                (actual != bytes.as_slice()).then(|| {
+                    let hex = |bytes: &[u8]| bytes.iter().map(|b| format!("{:02X}", b)).collect::<Vec<_>>().join(" ");
+                    format!("memory 0x{:03X}: expected [{}], got [{}]", addr, hex(bytes), hex(actual))
+                })
+            }
+            Expectation::Screen(x, y, rows) => {
+                let actual: Vec<String> = rows.iter().enumerate()
+                    .map(|(dy, row)| {
+                        (0..row.len())
+                            .map(|dx| if self.cpu.display.get_pixel((x + dx) % WIDTH, (y + dy) % HEIGHT) { '#' } else { '.' })
+                            .collect()
+                    })
+                    .collect();
+                (actual != *rows).then(|| {
+                    let mut out = format!("screen at ({}, {}), expected | got", x, y);
+                    for (expected, actual) in rows.iter().zip(actual.iter()) {
+                        let marker = if expected == actual { ' ' } else { '<' };
+                        out += &format!("\n    {} | {} {}", expected, actual, marker);
+                    }
+                    out
+                })
+            }
+        }
+    }
+
+    fn check(&mut self) {
+        let expectations = std::mem::take(&mut self.expectations);
+        let mismatches: Vec<String> = expectations.iter().filter_map(|e| self.mismatch(e)).collect();
+        if !mismatches.is_empty() {
+            panic!(
+                "{} mismatches after {} steps, pc 0x{:03X}\n  {}",
+                mismatches.len(),
+                self.steps,
+                self.cpu.get_pc(),
+                mismatches.join("\n  ")
+            );
+        }
+    }
+}
+
+impl Drop for Machine {
+    fn drop(&mut self) {
+        if !std::thread::panicking() {
+            self.check();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::expr::Register::V;
+    use crate::testing::Machine;
+
+    #[test]
+    #[should_panic(expected = "2 mismatches after 1 steps, pc 0x202\n  va: expected 0x01, got 0xAF\n  screen at (0, 0)")]
+    fn test_mismatches() {
+        Machine::asm("LD VA, 0xAF")
+            .steps(1)
+            .expect(V(0xA), 0x01)
+            .expect(V(0xB), 0x00)
+            .expect_screen(0, 0, &["#."]);
+    }
+
+    #[test]
+    fn test_run_until() {
+        Machine::asm("
+            LD V0, 0
+        count:
+            ADD V0, 1
+            SE V0, 5
+            JP count
+        done:
+            JP done
+        ")
+        .run_until(0x208)
+        .expect(V(0x0), 5);
+    }
+}
@@ -0,0 +1,365 @@
+// Execution trace file format, all integers little endian:
+//
+//   magic "C8TR", version: u16, step count: u32
+//   per step: pc: u16, opcode: u16, effect count: u8, effects
+//   register effect: register: u8 (0-15 V, 16 I, 17 DT, 18 ST),
+//                    old and new value, u16 for I and u8 otherwise
+//   memory effect:   0xFF, addr: u16, old: u8, new: u8
+//
+// Step n is the instruction executed at cycle n. Effects list what the
+// instruction wrote, writes that keep the old value included. Timer ticks
+// between frames are not instructions and are not recorded.
+use crate::access::Access;
+use crate::cpu::CPU;
+use crate::expr::Register;
+use std::fmt;
+
+pub const MAGIC: [u8; 4] = *b"C8TR";
+pub const FORMAT_VERSION: u16 = 1;
+const MEMORY_TAG: u8 = 0xFF;
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum TraceError {
+    BadMagic,
+    UnsupportedVersion(u16),
+    Truncated,
+    Corrupt(u64),
+}
+
+impl fmt::Display for TraceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TraceError::BadMagic => write!(f, "not a trace"),
+            TraceError::UnsupportedVersion(version) => write!(f, "unsupported trace version {}", version),
+            TraceError::Truncated => write!(f, "trace is truncated"),
+            TraceError::Corrupt(cycle) => write!(f, "corrupt step at cycle {}", cycle),
+        }
+    }
+}
+
+impl std::error::Error for TraceError {}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Effect {
+    Register { register: Register, old: u16, new: u16 },
+    Memory { addr: u16, old: u8, new: u8 },
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Step {
+    pub pc: u16,
+    pub opcode: u16,
+    //index of the first effect in Trace::effects
+    first_effect: u32,
+}
+
+// a register changing its value, see Trace::register_changes
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Change {
+    pub cycle: u64,
+    pub old: u16,
+    pub new: u16,
+}
+
+fn register_tag(register: Register) -> u8 {
+    match register {
+        Register::V(reg) => reg,
+        Register::I => 16,
+        Register::Dt => 17,
+        Register::St => 18,
+        Register::Pc | Register::Sp => unreachable!("pc and sp are not traced"),
+    }
+}
+
+fn tag_register(tag: u8) -> Option<Register> {
+    match tag {
+        0..=15 => Some(Register::V(tag)),
+        16 => Some(Register::I),
+        17 => Some(Register::Dt),
+        18 => Some(Register::St),
+        _ => None,
+    }
+}
+
+// steps are stored in one vector and their effects in another so a long
+// trace does not need an allocation per instruction
+#[derive(Debug, Clone, Eq, PartialEq, Default)]
+pub struct Trace {
+    steps: Vec<Step>,
+    effects: Vec<Effect>,
+}
+
+impl Trace {
+    pub fn new() -> Trace {
+        Trace { steps: vec![], effects: vec![] }
+    }
+
+    pub fn len(&self) -> usize {
+        self.steps.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    // executes one instruction on `cpu` and records it
+    pub fn record(&mut self, cpu: &mut CPU) {
+        let access = Access::of(cpu);
+        let mem_size = cpu.memory.memory.len();
+        let written: Vec<usize> = match access.memory_written {
+            Some((start, len)) => (start..start + len).map(|addr| addr % mem_size).collect(),
+            None => vec![],
+        };
+        //only the values the instruction can overwrite are kept
+        let old_registers: Vec<u8> = (0..16).map(|reg| cpu.read_register(reg)).collect();
+        let old_memory: Vec<u8> = written.iter().map(|&addr| cpu.memory.memory[addr]).collect();
+        let (old_i, old_dt, old_st) = (cpu.get_i(), cpu.get_dt(), cpu.get_st());
+        self.steps.push(Step {
+            pc: cpu.get_pc(),
+            opcode: cpu.fetch_current_instruction() as u16,
+            first_effect: self.effects.len() as u32,
+        });
+        cpu.step();
+        for reg in 0..16 {
+            if access.writes_register(reg) {
+                self.effects.push(Effect::Register {
+                    register: Register::V(reg as u8),
+                    old: old_registers[reg as usize] as u16,
+                    new: cpu.read_register(reg) as u16,
+                });
+            }
+        }
+        if access.i_written {
+            self.effects.push(Effect::Register { register: Register::I, old: old_i, new: cpu.get_i() });
+        }
+        let timers = [(Register::Dt, old_dt, access.dt_written), (Register::St, old_st, access.st_written)];
+        for &(register, old, written) in timers.iter() {
+            if written {
+                self.effects.push(Effect::Register { register, old: old as u16, new: register.read(cpu) as u16 });
+            }
+        }
+        for (&addr, &old) in written.iter().zip(old_memory.iter()) {
+            self.effects.push(Effect::Memory { addr: addr as u16, old, new: cpu.memory.memory[addr] });
+        }
+    }
+
+    pub fn record_frame(&mut self, cpu: &mut CPU, cycles: usize) {
+        cpu.run_frame_with(cycles, |cpu| self.record(cpu));
+    }
+
+    pub fn step(&self, cycle: u64) -> Option<&Step> {
+        self.steps.get(cycle as usize)
+    }
+
+    pub fn effects(&self, cycle: u64) -> &[Effect] {
+        let cycle = cycle as usize;
+        if cycle >= self.steps.len() {
+            return &[];
+        }
+        let start = self.steps[cycle].first_effect as usize;
+        let end = self.steps.get(cycle + 1).map(|step| step.first_effect as usize).unwrap_or(self.effects.len());
+        &self.effects[start..end]
+    }
+
+    // (cycle, step, effects) for every recorded instruction
+    pub fn iter(&self) -> impl Iterator<Item = (u64, &Step, &[Effect])> {
+        self.steps.iter().enumerate().map(move |(cycle, step)| (cycle as u64, step, self.effects(cycle as u64)))
+    }
+
+    // the last instruction before `cycle` that wrote to `addr`
+    pub fn last_write_before(&self, addr: u16, cycle: u64) -> Option<u64> {
+        let end = (cycle as usize).min(self.steps.len());
+        (0..end as u64).rev().find(|&cycle| {
+            self.effects(cycle).iter().any(|effect| matches!(effect, Effect::Memory { addr: a, .. } if *a == addr))
+        })
+    }
+
+    pub fn writes_to(&self, addr: u16) -> Vec<u64> {
+        self.iter()
+            .filter(|(_, _, effects)| effects.iter().any(|effect| matches!(effect, Effect::Memory { addr: a, .. } if *a == addr)))
+            .map(|(cycle, _, _)| cycle)
+            .collect()
+    }
+
+    // every write to `register` that gave it a different value
+    pub fn register_changes(&self, register: Register) -> Vec<Change> {
+        let mut changes = vec![];
+        for (cycle, _, effects) in self.iter() {
+            for effect in effects {
+                match *effect {
+                    Effect::Register { register: r, old, new } if r == register && old != new => {
+                        changes.push(Change { cycle, old, new })
+                    }
+                    _ => {}
+                }
+            }
+        }
+        changes
+    }
+
+    // cycles at which the instruction at `pc` ran
+    pub fn executions_of(&self, pc: u16) -> Vec<u64> {
+        self.iter().filter(|(_, step, _)| step.pc == pc).map(|(cycle, _, _)| cycle).collect()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(10 + self.steps.len() * 5 + self.effects.len() * 5);
+        out.extend_from_slice(&MAGIC);
+        out.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        out.extend_from_slice(&(self.steps.len() as u32).to_le_bytes());
+        for (_, step, effects) in self.iter() {
+            out.extend_from_slice(&step.pc.to_le_bytes());
+            out.extend_from_slice(&step.opcode.to_le_bytes());
+            out.push(effects.len() as u8);
+            for effect in effects {
+                match *effect {
+                    Effect::Register { register: Register::I, old, new } => {
+                        out.push(register_tag(Register::I));
+                        out.extend_from_slice(&old.to_le_bytes());
+                        out.extend_from_slice(&new.to_le_bytes());
+                    }
+                    Effect::Register { register, old, new } => {
+                        out.extend_from_slice(&[register_tag(register), old as u8, new as u8]);
+                    }
+                    Effect::Memory { addr, old, new } => {
+                        out.push(MEMORY_TAG);
+                        out.extend_from_slice(&addr.to_le_bytes());
+                        out.extend_from_slice(&[old, new]);
+                    }
+                }
+            }
+        }
+        out
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Trace, TraceError> {
+        if data.len() < 6 {
+            return Err(TraceError::Truncated);
+        }
+        if data[0..4] != MAGIC {
+            return Err(TraceError::BadMagic);
+        }
+        let version = u16::from_le_bytes([data[4], data[5]]);
+        if version > FORMAT_VERSION {
+            return Err(TraceError::UnsupportedVersion(version));
+        }
+        let bytes = |at: usize, len: usize| data.get(at..at + len).ok_or(TraceError::Truncated);
+        let u16_at = |at: usize| bytes(at, 2).map(|b| u16::from_le_bytes([b[0], b[1]]));
+        let count = bytes(6, 4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))?;
+        let mut trace = Trace::new();
+        let mut at = 10;
+        for cycle in 0..count as u64 {
+            let effects = bytes(at + 4, 1)?[0];
+            trace.steps.push(Step {
+                pc: u16_at(at)?,
+                opcode: u16_at(at + 2)?,
+                first_effect: trace.effects.len() as u32,
+            });
+            at += 5;
+            for _ in 0..effects {
+                let tag = bytes(at, 1)?[0];
+                let effect = match tag_register(tag) {
+                    Some(Register::I) => {
+                        at += 4;
+                        Effect::Register { register: Register::I, old: u16_at(at - 3)?, new: u16_at(at - 1)? }
+                    }
+                    Some(register) => {
+                        let values = bytes(at + 1, 2)?;
+                        at += 2;
+                        Effect::Register { register, old: values[0] as u16, new: values[1] as u16 }
+                    }
+                    None if tag == MEMORY_TAG => {
+                        let values = bytes(at + 3, 2)?;
+                        at += 4;
+                        Effect::Memory { addr: u16_at(at - 3)?, old: values[0], new: values[1] }
+                    }
+                    None => return Err(TraceError::Corrupt(cycle)),
+                };
+                at += 1;
+                trace.effects.push(effect);
+            }
+        }
+        Ok(trace)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cpu::CPU;
+    use crate::expr::Register;
+    use crate::memory::Memory;
+    use crate::trace::{Change, Effect, Trace, TraceError};
+
+    fn record(cycles: usize) -> Trace {
+        let mut mem = Memory::new();
+        mem.load_program(&[
+            // ADD 5, 0x01
+            0x75, 0x01, //0x200
+            // LD I, 0x3A0
+            0xA3, 0xA0, //0x202
+            // LD [I], 0
+            0xF0, 0x55, //0x204
+            // LD B, 5
+            0xF5, 0x33, //0x206
+            // LD DT, 5
+            0xF5, 0x15, //0x208
+            // JP 0x200
+            0x12, 0x00, //0x20A
+        ]);
+        let mut cpu = CPU::new(mem);
+        let mut trace = Trace::new();
+        for _ in 0..cycles / 6 {
+            trace.record_frame(&mut cpu, 6);
+        }
+        trace
+    }
+
+    #[test]
+    fn test_queries() {
+        let trace = record(60);
+        assert_eq!(60, trace.len());
+        assert_eq!(0xF055, trace.step(2).unwrap().opcode);
+        assert_eq!(
+            &[Effect::Memory { addr: 0x3A0, old: 0x00, new: 0x00 }],
+            trace.effects(2)
+        );
+        //LD B, 5 writes 0x3A0 with the hundreds digit as well
+        assert_eq!(Some(27), trace.last_write_before(0x3A0, 30));
+        assert_eq!(Some(26), trace.last_write_before(0x3A0, 27));
+        assert_eq!(None, trace.last_write_before(0x3A0, 2));
+        assert_eq!(20, trace.writes_to(0x3A0).len());
+        assert_eq!(vec![3, 9, 15], trace.writes_to(0x3A2)[..3].to_vec());
+
+        let changes = trace.register_changes(Register::V(5));
+        assert_eq!(10, changes.len());
+        assert_eq!(Change { cycle: 6, old: 1, new: 2 }, changes[1]);
+        //I is only ever loaded with the same value after the first pass
+        assert_eq!(1, trace.register_changes(Register::I).len());
+        assert_eq!(10, trace.register_changes(Register::Dt).len());
+        assert_eq!(vec![4, 10], trace.executions_of(0x208)[..2].to_vec());
+    }
+
+    #[test]
+    fn test_unchanged_timer_write() {
+        let mut mem = Memory::new();
+        mem.load_program(&[
+            // LD ST, 0
+            0xF0, 0x18, //0x200
+        ]);
+        let mut cpu = CPU::new(mem);
+        let mut trace = Trace::new();
+        trace.record(&mut cpu);
+        assert_eq!(&[Effect::Register { register: Register::St, old: 0, new: 0 }], trace.effects(0));
+        assert!(trace.register_changes(Register::St).is_empty());
+    }
+
+    #[test]
+    fn test_file_round_trip() {
+        let trace = record(600);
+        let bytes = trace.to_bytes();
+        assert!(bytes.len() < 600 * 12);
+        assert_eq!(Ok(trace.clone()), Trace::from_bytes(&bytes));
+        assert_eq!(Err(TraceError::Truncated), Trace::from_bytes(&bytes[..bytes.len() - 1]));
+        assert_eq!(Err(TraceError::BadMagic), Trace::from_bytes(b"C8SS\x01\x00"));
+    }
+}
@@ -0,0 +1,253 @@
+// Text execution log, one line per instruction with the machine state
+// before it executes:
+//
+//   PC=0200 OP=A21E V0=00 V1=00 ... VF=00 I=0000 SP=0 DT=00 ST=00 ; LD I, 0x21E
+//
+// Every field is KEY=VALUE with the value in hex and fields are separated by
+// spaces. PC and OP are always present, CYCLE (the step number), the V
+// registers, I, SP, the timers DT and ST and the mnemonic after `;` can be
+// left out with Columns.
+//
+// The diff reads this format and logs of other emulators that use KEY=VALUE
+// or KEY:VALUE fields. Only fields both lines have are compared, keys ignore
+// case, text after `;` and lines that are empty or start with `#` are
+// skipped.
+use crate::cpu::CPU;
+use crate::disasm::{Disassembly, Syntax};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{self, Write};
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Columns {
+    pub cycle: bool,
+    //V0 to VF
+    pub registers: bool,
+    pub index: bool,
+    pub stack_pointer: bool,
+    pub timers: bool,
+    pub mnemonic: bool,
+}
+
+impl Default for Columns {
+    fn default() -> Columns {
+        Columns { cycle: false, registers: true, index: true, stack_pointer: true, timers: true, mnemonic: true }
+    }
+}
+
+// the line for the instruction at PC
+pub fn format_line(cpu: &CPU, cycle: u64, columns: Columns) -> String {
+    let opcode = cpu.fetch_current_instruction() as u16;
+    let mut line = String::new();
+    if columns.cycle {
+        line += &format!("CYCLE={:X} ", cycle);
+    }
+    line += &format!("PC={:04X} OP={:04X}", cpu.get_pc(), opcode);
+    if columns.registers {
+        for register in 0..16 {
+            line += &format!(" V{:X}={:02X}", register, cpu.read_register(register));
+        }
+    }
+    if columns.index {
+        line += &format!(" I={:04X}", cpu.get_i());
+    }
+    if columns.stack_pointer {
+        line += &format!(" SP={:X}", cpu.get_sp());
+    }
+    if columns.timers {
+        line += &format!(" DT={:02X} ST={:02X}", cpu.get_dt(), cpu.get_st());
+    }
+    if columns.mnemonic {
+        //no rom, targets are printed as addresses
+        line += &format!(" ; {}", Disassembly::new(&[]).format_instruction(opcode, Syntax::Cowgod));
+    }
+    line
+}
+
+pub struct Tracer<W: Write> {
+    out: W,
+    pub columns: Columns,
+    //instructions logged so far
+    cycle: u64,
+}
+
+impl<W: Write> Tracer<W> {
+    pub fn new(out: W, columns: Columns) -> Tracer<W> {
+        Tracer { out, columns, cycle: 0 }
+    }
+
+    pub fn cycle(&self) -> u64 {
+        self.cycle
+    }
+
+    // logs the instruction at PC and executes it
+    pub fn step(&mut self, cpu: &mut CPU) -> io::Result<()> {
+        writeln!(self.out, "{}", format_line(cpu, self.cycle, self.columns))?;
+        cpu.step();
+        self.cycle += 1;
+        Ok(())
+    }
+
+    // nothing else is executed once writing failed
+    fn logged_step(&mut self, cpu: &mut CPU, result: &mut io::Result<()>) {
+        if result.is_ok() {
+            *result = self.step(cpu);
+        }
+    }
+
+    pub fn run_frame(&mut self, cpu: &mut CPU, cycles: usize) -> io::Result<()> {
+        let mut result = Ok(());
+        cpu.run_frame_with(cycles, |cpu| self.logged_step(cpu, &mut result));
+        result
+    }
+
+    pub fn run(&mut self, cpu: &mut CPU, cycles: usize) -> io::Result<()> {
+        let mut result = Ok(());
+        cpu.run_with(cycles, |cpu| self.logged_step(cpu, &mut result));
+        result?;
+        self.out.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+// KEY=VALUE and KEY:VALUE fields before any `;`, keys in upper case
+fn fields(line: &str) -> BTreeMap<String, u64> {
+    let line = line.split(';').next().unwrap_or("");
+    line.split_whitespace()
+        .filter_map(|field| {
+            let (key, value) = field.split_once('=').or_else(|| field.split_once(':'))?;
+            let value = value.trim_start_matches("0x").trim_start_matches("0X");
+            Some((key.to_ascii_uppercase(), u64::from_str_radix(value, 16).ok()?))
+        })
+        .collect()
+}
+
+// 1 based line numbers and the text of the lines that differ, None where a
+// trace ended
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Mismatch {
+    pub left_line: usize,
+    pub right_line: usize,
+    pub left: Option<String>,
+    pub right: Option<String>,
+    //keys with different values
+    pub fields: Vec<String>,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.left, &self.right) {
+            (Some(_), None) => write!(f, "right trace ends after line {}", self.right_line - 1)?,
+            (None, Some(_)) => write!(f, "left trace ends after line {}", self.left_line - 1)?,
+            _ if self.fields.is_empty() => {
+                write!(f, "line {} and line {} have no fields in common", self.left_line, self.right_line)?
+            }
+            _ => write!(f, "line {} and line {} differ in {}", self.left_line, self.right_line, self.fields.join(", "))?,
+        }
+        if let Some(left) = &self.left {
+            write!(f, "\n< {}", left)?;
+        }
+        if let Some(right) = &self.right {
+            write!(f, "\n> {}", right)?;
+        }
+        Ok(())
+    }
+}
+
+fn records(trace: &str) -> impl Iterator<Item = (usize, &str)> {
+    trace
+        .lines()
+        .enumerate()
+        .map(|(index, line)| (index + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+}
+
+// the first pair of lines that disagree, None when the traces match
+pub fn diff(left: &str, right: &str) -> Option<Mismatch> {
+    let mut lefts = records(left);
+    let mut rights = records(right);
+    //line numbers past the end of each trace
+    let (left_end, right_end) = (left.lines().count() + 1, right.lines().count() + 1);
+    loop {
+        let mismatch = match (lefts.next(), rights.next()) {
+            (None, None) => return None,
+            (Some((left_line, left)), None) => {
+                Mismatch { left_line, right_line: right_end, left: Some(left.to_string()), right: None, fields: vec![] }
+            }
+            (None, Some((right_line, right))) => {
+                Mismatch { left_line: left_end, right_line, left: None, right: Some(right.to_string()), fields: vec![] }
+            }
+            (Some((left_line, left)), Some((right_line, right))) => {
+                let (a, b) = (fields(left), fields(right));
+                let common: Vec<&String> = a.keys().filter(|key| b.contains_key(*key)).collect();
+                let differing: Vec<String> = common.iter().filter(|key| a[**key] != b[**key]).map(|key| key.to_string()).collect();
+                if !common.is_empty() && differing.is_empty() {
+                    continue;
+                }
+                Mismatch { left_line, right_line, left: Some(left.to_string()), right: Some(right.to_string()), fields: differing }
+            }
+        };
+        return Some(mismatch);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::asm::assemble;
+    use crate::cpu::CPU;
+    use crate::memory::Memory;
+    use crate::tracelog::{diff, Columns, Tracer};
+
+    fn trace(cycles: usize, columns: Columns) -> String {
+        let mut mem = Memory::new();
+        mem.load_program(&assemble("main: LD V1, 3\n LD DT, V1\n CALL sub\n JP main\nsub: ADD I, V1\n RET").unwrap());
+        let mut cpu = CPU::new(mem);
+        let mut tracer = Tracer::new(vec![], columns);
+        tracer.run(&mut cpu, cycles).unwrap();
+        assert_eq!(cycles as u64, tracer.cycle());
+        String::from_utf8(tracer.into_inner()).unwrap()
+    }
+
+    #[test]
+    fn test_format() {
+        let columns = Columns { cycle: true, registers: false, ..Columns::default() };
+        let expected = "\
+CYCLE=0 PC=0200 OP=6103 I=0000 SP=0 DT=00 ST=00 ; LD V1, 0x03
+CYCLE=1 PC=0202 OP=F115 I=0000 SP=0 DT=00 ST=00 ; LD DT, V1
+CYCLE=2 PC=0204 OP=2208 I=0000 SP=0 DT=03 ST=00 ; CALL 0x208
+CYCLE=3 PC=0208 OP=F11E I=0000 SP=1 DT=03 ST=00 ; ADD I, V1
+CYCLE=4 PC=020A OP=00EE I=0003 SP=1 DT=03 ST=00 ; RET
+";
+        assert_eq!(expected, trace(5, columns));
+        let line = trace(1, Columns::default());
+        assert!(line.starts_with("PC=0200 OP=6103 V0=00 V1=00 V2=00"));
+        assert!(line.ends_with("VF=00 I=0000 SP=0 DT=00 ST=00 ; LD V1, 0x03\n"));
+    }
+
+    #[test]
+    fn test_diff() {
+        let ours = trace(30, Columns::default());
+        assert_eq!(None, diff(&ours, &ours));
+
+        //another emulator with its own layout and fewer fields
+        let theirs: String = ours
+            .lines()
+            .map(|line| line.split(';').next().unwrap().replace("PC=", "pc:0x").replace("DT=", "dt:") + "\n")
+            .collect();
+        assert_eq!(None, diff(&ours, &format!("# header\n{}", theirs)));
+
+        let changed = theirs.replacen("dt:03", "dt:02", 1);
+        let mismatch = diff(&ours, &changed).unwrap();
+        assert_eq!((3, 3), (mismatch.left_line, mismatch.right_line));
+        assert_eq!(vec!["DT".to_string()], mismatch.fields);
+        assert!(mismatch.to_string().starts_with("line 3 and line 3 differ in DT\n< PC=0204 OP=2208"));
+
+        let short: String = ours.lines().take(10).map(|line| line.to_string() + "\n").collect();
+        let mismatch = diff(&ours, &short).unwrap();
+        assert_eq!((11, None), (mismatch.left_line, mismatch.right.as_deref()));
+        assert!(mismatch.to_string().starts_with("right trace ends after line 10"));
+    }
+}
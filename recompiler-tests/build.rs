@@ -0,0 +1,22 @@
+// assembles the roms in roms/ and recompiles each of them into
+// $OUT_DIR/<name>.rs, src/lib.rs pulls them in through $OUT_DIR/roms.rs
+use chip8::{asm, recompiler};
+use std::env;
+use std::fs;
+use std::path::Path;
+
+fn main() {
+    let out_dir = env::var("OUT_DIR").unwrap();
+    let mut modules = String::new();
+    println!("cargo:rerun-if-changed=roms");
+    let mut entries: Vec<_> = fs::read_dir("roms").unwrap().map(|entry| entry.unwrap().path()).collect();
+    entries.sort();
+    for path in entries {
+        let name = path.file_stem().unwrap().to_str().unwrap().to_string();
+        let rom = asm::assemble_file(path.to_str().unwrap()).unwrap_or_else(|error| panic!("{:?}", error));
+        let out = Path::new(&out_dir).join(format!("{}.rs", name));
+        fs::write(&out, recompiler::recompile(&rom)).unwrap();
+        modules += &format!("#[path = {:?}]\npub mod {};\n", out, name);
+    }
+    fs::write(Path::new(&out_dir).join("roms.rs"), modules).unwrap();
+}
@@ -0,0 +1,29 @@
+; every inlined instruction, calls, skips and interpreted RND and LD B
+    LD V0, 0xF0
+    LD V1, 0x1F
+loop:
+    ADD V0, V1
+    SUB V1, V0
+    SUBN V2, V1
+    SHR V3, V0
+    SHL V4, V1
+    OR V5, V0
+    AND V6, V1
+    XOR V7, V2
+    CALL draw
+    SNE V0, V1
+    CLS
+    SE V2, 0x10
+    ADD V8, 3
+    RND V9, 0x3F
+    LD I, digits
+    LD B, V8
+    JP loop
+draw:
+    LD I, digits
+    LD VA, V2
+    LD VB, V9
+    DRW VA, VB, 3
+    RET
+digits:
+    db 0xA5, 0x5A, 0xFF
@@ -0,0 +1,13 @@
+; rewrites an instruction of a compiled block
+main:
+    CALL count
+    LD I, patch
+    LD V0, 0x73    ; ADD V3, 5
+    LD V1, 0x05
+    LD [I], V1
+    JP main
+count:
+    ADD V4, 1
+patch:
+    ADD V3, 1
+    RET
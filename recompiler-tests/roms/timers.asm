@@ -0,0 +1,12 @@
+; busy waits on the delay timer across many frames
+    LD V0, 30
+    LD DT, V0
+    LD ST, V0
+wait:
+    LD V1, DT
+    ADD V2, 1
+    ADD V3, V1
+    SE V1, 0
+    JP wait
+    ADD V4, 1
+    JP 0x200
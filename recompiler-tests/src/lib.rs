@@ -0,0 +1,57 @@
+// one module per rom in roms/, holding what `recompile` emitted for it
+include!(concat!(env!("OUT_DIR"), "/roms.rs"));
+
+#[cfg(test)]
+mod tests {
+    use chip8::asm::assemble_file;
+    use chip8::cpu::{CPU, CYCLES_PER_FRAME};
+    use chip8::memory::Memory;
+    use chip8::recompiler::Recompiler;
+
+    // runs `instructions` through the compiled `run_block` and through
+    // CPU::step with a timer tick every CYCLES_PER_FRAME, comparing the
+    // machines after every block
+    fn assert_matches_interpreter(rom: &str, run_block: fn(&mut CPU, &mut usize), instructions: usize) {
+        let rom = assemble_file(&format!("{}/roms/{}.asm", env!("CARGO_MANIFEST_DIR"), rom)).unwrap();
+        //the frame counter run_block keeps only tells how many instructions
+        //a block ran if no block fills a whole frame
+        for block in Recompiler::new(&rom).blocks.values() {
+            assert!(block.instructions.len() + 1 < CYCLES_PER_FRAME, "block at 0x{:03X} is too long", block.start);
+        }
+        let mut mem = Memory::new();
+        mem.load_program(&rom);
+        let mut compiled = CPU::new(mem);
+        compiled.seed_rng(7);
+        let mut reference = compiled.clone();
+        let (mut cycles, mut executed) = (0, 0);
+        while executed < instructions {
+            let (pc, before) = (compiled.get_pc(), cycles);
+            run_block(&mut compiled, &mut cycles);
+            for _ in 0..(cycles + CYCLES_PER_FRAME - before) % CYCLES_PER_FRAME {
+                reference.step();
+                executed += 1;
+                if executed % CYCLES_PER_FRAME == 0 {
+                    reference.tick_timers();
+                }
+            }
+            if let Some(difference) = reference.diff(&compiled) {
+                panic!("block at 0x{:03X} ending at cycle {}: {}", pc, executed, difference);
+            }
+        }
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        assert_matches_interpreter("arithmetic", crate::arithmetic::run_block, 2000);
+    }
+
+    #[test]
+    fn test_timers() {
+        assert_matches_interpreter("timers", crate::timers::run_block, 3000);
+    }
+
+    #[test]
+    fn test_self_modifying() {
+        assert_matches_interpreter("self_modifying", crate::self_modifying::run_block, 500);
+    }
+}
@@ -1,27 +1,326 @@
-use chip8::{cpu::CPU, memory::Memory};
+use chip8::{asm, coverage::Coverage, cpu::CPU, debugger::Debugger, decompile, detect, disasm, gdb, graph, lint, memory::Memory, octo, platform::Platform, profile, recompiler, tracelog};
+use std::env;
 use std::path::PathBuf;
 use std::fs::File;
-use std::io::Read;
+use std::io::{self, BufRead, Read, Write};
+use std::net::TcpListener;
+
+mod tas;
 
 fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.first().map(|s| s.as_str()) {
+        Some("recompile") => recompile(&args[1..]),
+        Some("tas") => tas::run(&args[1..]),
+        Some("debug") => debug(&args[1..]),
+        Some("gdb") => gdb_server(&args[1..]),
+        Some("disasm") => disassemble(&args[1..]),
+        Some("asm") => assemble(&args[1..]),
+        Some("graph") => export_graph(&args[1..]),
+        Some("lint") => lint_rom(&args[1..]),
+        Some("octo") => compile_octo(&args[1..]),
+        Some("detect") => detect_platform(&args[1..]),
+        Some("decompile") => decompile_rom(&args[1..]),
+        Some("trace") => trace_rom(&args[1..]),
+        Some("trace-diff") => diff_traces(&args[1..]),
+        Some("profile") => profile_rom(&args[1..]),
+        Some("coverage") => coverage(&args[1..]),
+        _ => run(&args),
+    }
+}
+
+// chip8-vm <rom> [--platform name]
+fn run(args: &[String]) {
     let mem = Memory::new();
     let mut cpu = CPU::new(mem);
-    let path = get_file_path(&"./div.ch8".to_string()).unwrap();
-    let file_bytes = read_file(&path);
-    cpu.memory.load_program(&file_bytes);
+    let rom = args.first().cloned().unwrap_or_else(|| "./div.ch8".to_string());
+    let path = get_file_path(&rom).unwrap();
+    let rom = read_file(&path);
+    cpu.memory.load_program(&rom);
+    cpu.quirks = platform_arg(args, &rom).quirks();
     cpu.step();
     cpu.step();
     cpu.step();
 }
 
+// chip8-vm recompile <rom> [out.rs]
+fn recompile(args: &[String]) {
+    let rom = args.first().expect("usage: chip8-vm recompile <rom> [out.rs]");
+    let path = get_file_path(rom).unwrap();
+    let source = recompiler::recompile(&read_file(&path));
+    match args.get(1) {
+        Some(out) => {
+            let mut file = File::create(out).unwrap();
+            file.write_all(source.as_bytes()).unwrap();
+        }
+        None => print!("{}", source),
+    }
+}
+
+// the value following `--name`
+fn option<'a>(args: &'a [String], name: &str) -> Option<&'a String> {
+    args.iter().position(|arg| arg == name).map(|at| args.get(at + 1).unwrap_or_else(|| panic!("missing value for {}", name)))
+}
+
+// --platform chip8|schip|xochip, detected from the rom when not given
+fn platform_arg(args: &[String], rom: &[u8]) -> Platform {
+    match option(args, "--platform") {
+        Some(name) => Platform::parse(name).unwrap_or_else(|| panic!("unknown platform {}", name)),
+        None => {
+            let detection = detect::detect(rom);
+            eprintln!("detected {} (confidence {:.2})", detection.platform.name(), detection.confidence);
+            detection.platform
+        }
+    }
+}
+
+// chip8-vm detect <rom>
+fn detect_platform(args: &[String]) {
+    let rom = args.first().expect("usage: chip8-vm detect <rom>");
+    println!("{}", detect::detect(&read_file(&get_file_path(rom).unwrap())));
+}
+
+// chip8-vm disasm <rom> [--octo] [--platform name]
+fn disassemble(args: &[String]) {
+    let rom = args.first().expect("usage: chip8-vm disasm <rom> [--octo] [--platform chip8|schip|xochip]");
+    let syntax = if args.iter().any(|arg| arg == "--octo") { disasm::Syntax::Octo } else { disasm::Syntax::Cowgod };
+    let rom = read_file(&get_file_path(rom).unwrap());
+    print!("{}", disasm::Disassembly::for_platform(&rom, platform_arg(args, &rom)).listing(syntax));
+}
+
+// chip8-vm decompile <rom> [--platform name]
+fn decompile_rom(args: &[String]) {
+    let rom = args.first().expect("usage: chip8-vm decompile <rom> [--platform chip8|schip|xochip]");
+    let rom = read_file(&get_file_path(rom).unwrap());
+    print!("{}", decompile::decompile(&rom, platform_arg(args, &rom)));
+}
+
+// chip8-vm graph <rom> [--calls | --json] [--platform name], control flow as dot by default
+fn export_graph(args: &[String]) {
+    let rom = args.first().expect("usage: chip8-vm graph <rom> [--calls | --json] [--platform chip8|schip|xochip]");
+    let rom = read_file(&get_file_path(rom).unwrap());
+    let analysis = graph::Analysis::for_platform(&rom, platform_arg(args, &rom));
+    if args.iter().any(|arg| arg == "--json") {
+        print!("{}", analysis.to_json());
+    } else if args.iter().any(|arg| arg == "--calls") {
+        print!("{}", analysis.call_graph_dot());
+    } else {
+        print!("{}", analysis.cfg_dot());
+    }
+}
+
+// chip8-vm lint <rom> [--platform chip8|schip|xochip], exits with 1 on errors
+fn lint_rom(args: &[String]) {
+    let rom = args.first().expect("usage: chip8-vm lint <rom> [--platform chip8|schip|xochip]");
+    let rom = read_file(&get_file_path(rom).unwrap());
+    let findings = lint::lint(&rom, platform_arg(args, &rom));
+    for finding in findings.iter() {
+        println!("{}", finding);
+    }
+    if findings.iter().any(|finding| finding.severity == lint::Severity::Error) {
+        std::process::exit(1);
+    }
+}
+
+// chip8-vm trace <rom> [--cycles N] [--seed N] [--numbered] [--no-mnemonic]
+fn trace_rom(args: &[String]) {
+    let rom = args.first().expect("usage: chip8-vm trace <rom> [--cycles N] [--seed N] [--numbered] [--no-mnemonic]");
+    let cycles = option(args, "--cycles").map_or(1000, |cycles| cycles.parse().expect("invalid cycle count"));
+    let mut mem = Memory::new();
+    mem.load_program(&read_file(&get_file_path(rom).unwrap()));
+    let mut cpu = CPU::new(mem);
+    if let Some(seed) = option(args, "--seed") {
+        cpu.seed_rng(seed.parse().expect("invalid seed"));
+    }
+    let columns = tracelog::Columns {
+        cycle: args.iter().any(|arg| arg == "--numbered"),
+        mnemonic: !args.iter().any(|arg| arg == "--no-mnemonic"),
+        ..tracelog::Columns::default()
+    };
+    let mut tracer = tracelog::Tracer::new(io::BufWriter::new(io::stdout().lock()), columns);
+    tracer.run(&mut cpu, cycles).unwrap();
+}
+
+// chip8-vm trace-diff <ours> <theirs>, exits with 1 at the first mismatch
+fn diff_traces(args: &[String]) {
+    let (ours, theirs) = match args {
+        [ours, theirs, ..] => (ours, theirs),
+        _ => panic!("usage: chip8-vm trace-diff <ours> <theirs>"),
+    };
+    let read = |path: &String| String::from_utf8(read_file(&get_file_path(path).unwrap())).expect("trace is not utf-8");
+    match tracelog::diff(&read(ours), &read(theirs)) {
+        Some(mismatch) => {
+            println!("{}", mismatch);
+            std::process::exit(1);
+        }
+        None => println!("traces match"),
+    }
+}
+
+// chip8-vm profile <rom> [--cycles N] [--seed N] [--top N] [--folded out.folded]
+fn profile_rom(args: &[String]) {
+    let rom = args.first().expect("usage: chip8-vm profile <rom> [--cycles N] [--seed N] [--top N] [--folded out.folded]");
+    let cycles = option(args, "--cycles").map_or(10000, |cycles| cycles.parse().expect("invalid cycle count"));
+    let top = option(args, "--top").map_or(20, |top| top.parse().expect("invalid count"));
+    let mut mem = Memory::new();
+    mem.load_program(&read_file(&get_file_path(rom).unwrap()));
+    let mut cpu = CPU::new(mem);
+    if let Some(seed) = option(args, "--seed") {
+        cpu.seed_rng(seed.parse().expect("invalid seed"));
+    }
+    let mut profiler = profile::Profiler::new();
+    profiler.run(&mut cpu, cycles);
+    print!("{}", profiler.report(top));
+    if let Some(out) = option(args, "--folded") {
+        File::create(out).unwrap().write_all(profiler.folded().as_bytes()).unwrap();
+    }
+}
+
+// chip8-vm coverage run <rom> <out.cov> [--cycles N] [--seed N]
+// chip8-vm coverage merge <out.cov> <in.cov>...
+// chip8-vm coverage report <rom> <in.cov>...
+fn coverage(args: &[String]) {
+    let usage = "usage: chip8-vm coverage run <rom> <out.cov> [--cycles N] [--seed N] | merge <out.cov> <in.cov>... | report <rom> <in.cov>...";
+    let load = |paths: &[String]| {
+        let mut merged = Coverage::new();
+        for path in paths.iter() {
+            match Coverage::from_bytes(&read_file(&get_file_path(path).unwrap())) {
+                Ok(coverage) => merged.merge(&coverage),
+                Err(err) => panic!("{}: {}", path, err),
+            }
+        }
+        merged
+    };
+    match args {
+        [command, rom, out, ..] if command == "run" => {
+            let cycles: usize = option(args, "--cycles").map_or(10000, |cycles| cycles.parse().expect("invalid cycle count"));
+            let mut mem = Memory::new();
+            mem.load_program(&read_file(&get_file_path(rom).unwrap()));
+            let mut cpu = CPU::new(mem);
+            if let Some(seed) = option(args, "--seed") {
+                cpu.seed_rng(seed.parse().expect("invalid seed"));
+            }
+            let mut coverage = Coverage::new();
+            coverage.run(&mut cpu, cycles);
+            File::create(out).unwrap().write_all(&coverage.to_bytes()).unwrap();
+        }
+        [command, out, inputs @ ..] if command == "merge" && !inputs.is_empty() => {
+            File::create(out).unwrap().write_all(&load(inputs).to_bytes()).unwrap();
+        }
+        [command, rom, inputs @ ..] if command == "report" && !inputs.is_empty() => {
+            let rom = read_file(&get_file_path(rom).unwrap());
+            let coverage = load(inputs);
+            print!("{}", coverage.listing(&rom));
+            println!("\n{}", coverage.summary(&rom));
+        }
+        _ => panic!("{}", usage),
+    }
+}
+
+// chip8-vm asm <source> <out.ch8>
+fn assemble(args: &[String]) {
+    let (source, out) = match args {
+        [source, out, ..] => (source, out),
+        _ => panic!("usage: chip8-vm asm <source> <out.ch8>"),
+    };
+    match asm::assemble_file(source) {
+        Ok(rom) => File::create(out).unwrap().write_all(&rom).unwrap(),
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    }
+}
+
+// chip8-vm octo <source.8o> <out.ch8>
+fn compile_octo(args: &[String]) {
+    let (source, out) = match args {
+        [source, out, ..] => (source, out),
+        _ => panic!("usage: chip8-vm octo <source.8o> <out.ch8>"),
+    };
+    let program = load_octo(source);
+    File::create(out).unwrap().write_all(&program.rom).unwrap();
+}
+
+fn load_octo(source: &str) -> octo::Program {
+    let text = String::from_utf8(read_file(&get_file_path(source).unwrap())).expect("source is not utf-8");
+    match octo::compile(&text) {
+        Ok(program) => program,
+        Err(err) => {
+            eprintln!("{}: {}", source, err);
+            std::process::exit(1);
+        }
+    }
+}
+
+// chip8-vm debug <rom or source.8o> [--platform name]
+fn debug(args: &[String]) {
+    let rom = args.first().expect("usage: chip8-vm debug <rom> [--platform chip8|schip|xochip]");
+    let mut mem = Memory::new();
+    let mut source_map = None;
+    let rom = if rom.ends_with(".8o") {
+        let program = load_octo(rom);
+        source_map = Some(program.source_map);
+        program.rom
+    } else {
+        read_file(&get_file_path(rom).unwrap())
+    };
+    mem.load_program(&rom);
+    let mut cpu = CPU::new(mem);
+    cpu.quirks = platform_arg(args, &rom).quirks();
+    let mut debugger = Debugger::new(cpu);
+    debugger.source_map = source_map;
+    println!("{}", debugger.current_instruction());
+    repl(|line| debugger.execute_command(line));
+}
+
+// chip8-vm gdb <rom> [port] [--platform name]
+fn gdb_server(args: &[String]) {
+    let rom = args.first().expect("usage: chip8-vm gdb <rom> [port] [--platform chip8|schip|xochip]");
+    let port = args.get(1)
+        .filter(|arg| !arg.starts_with("--"))
+        .map(|port| port.parse::<u16>().expect("invalid port"))
+        .unwrap_or(1234);
+    let rom = read_file(&get_file_path(rom).unwrap());
+    let mut mem = Memory::new();
+    mem.load_program(&rom);
+    let mut cpu = CPU::new(mem);
+    cpu.quirks = platform_arg(args, &rom).quirks();
+    let mut stub = gdb::GdbStub::new(cpu);
+    let listener = TcpListener::bind(("127.0.0.1", port)).unwrap();
+    println!("waiting for gdb on 127.0.0.1:{}", port);
+    let (mut stream, peer) = listener.accept().unwrap();
+    println!("gdb connected from {}", peer);
+    gdb::serve(&mut stream, &mut stub).unwrap();
+}
+
+// reads commands from stdin until quit or end of input
+fn repl<F: FnMut(&str) -> Result<String, String>>(mut execute: F) {
+    let stdin = io::stdin();
+    print!("> ");
+    io::stdout().flush().unwrap();
+    for line in stdin.lock().lines() {
+        let line = line.unwrap();
+        if line.trim() == "quit" {
+            break;
+        }
+        match execute(&line) {
+            Ok(out) => println!("{}", out),
+            Err(err) => println!("error: {}", err),
+        }
+        print!("> ");
+        io::stdout().flush().unwrap();
+    }
+}
+
 fn read_file(path: &PathBuf) -> Vec<u8> {
     let mut file = File::open(path).unwrap();
     let mut buf: Vec<u8> = vec![];
-    file.read_to_end(&mut buf);
+    file.read_to_end(&mut buf).unwrap();
     buf
 }
 
-fn get_file_path(file_or_path: &String) -> Result<PathBuf, String> {
+fn get_file_path(file_or_path: &str) -> Result<PathBuf, String> {
     let passed_path = PathBuf::from(file_or_path);
 
     if passed_path.is_file() {
@@ -30,4 +329,3 @@ fn get_file_path(file_or_path: &String) -> Result<PathBuf, String> {
 
     Err("Invalid path".to_string())
 }
-
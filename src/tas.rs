@@ -0,0 +1,136 @@
+use crate::{get_file_path, read_file, repl};
+use chip8::cpu::{Quirks, CYCLES_PER_FRAME};
+use chip8::debugger::parse_number;
+use chip8::movie::{verify, Movie};
+use chip8::tas::TasEditor;
+use std::fs;
+
+const HELP: &str = "commands:
+  advance [count] [keys]    play frames, optionally holding the keypad mask
+  input <frame> <keys>      replace the keypad mask of a frame
+  seek <frame>              go to the state after the given number of frames
+  status                    show the current frame and registers
+  branch save|load|delete <name>
+  branch list
+  branch diff <a> <b>       first frame where two branches differ
+  verify                    replay the movie from power on
+  write [path]              save the movie
+  quit";
+
+// chip8-vm tas <rom> <movie>
+pub fn run(args: &[String]) {
+    if args.len() < 2 {
+        eprintln!("usage: chip8-vm tas <rom> <movie>");
+        return;
+    }
+    let movie_path = args[1].clone();
+    let mut editor = match open(&args[0], &movie_path) {
+        Ok(editor) => editor,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    };
+    repl(|line| {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        execute(&mut editor, &words, &movie_path)
+    });
+}
+
+// a movie that does not exist yet starts out empty
+fn open(rom_path: &str, movie_path: &str) -> Result<TasEditor, String> {
+    let rom = read_file(&get_file_path(rom_path).map_err(|err| format!("{}: {}", rom_path, err))?);
+    let movie = match fs::read(movie_path) {
+        Ok(bytes) => Movie::from_bytes(&bytes).map_err(|err| format!("{}: {}", movie_path, err))?,
+        Err(_) => Movie::new(&rom, Quirks::default(), CYCLES_PER_FRAME as u32, 0),
+    };
+    TasEditor::new(&rom, movie).map_err(|err| format!("{}: {}", movie_path, err))
+}
+
+fn number(words: &[&str], index: usize) -> Result<usize, String> {
+    let word = words.get(index).ok_or("missing argument")?;
+    parse_number(word).ok_or_else(|| format!("invalid number {}", word))
+}
+
+// a keypad mask, one bit per key
+fn keys(words: &[&str], index: usize) -> Result<u16, String> {
+    match number(words, index)? {
+        keys if keys <= 0xFFFF => Ok(keys as u16),
+        _ => Err(format!("invalid keypad mask {}", words[index])),
+    }
+}
+
+fn status(editor: &TasEditor) -> String {
+    let mut out = format!("frame {}/{}", editor.frame(), editor.movie().len());
+    if let Some(keys) = editor.movie().inputs.get(editor.frame()) {
+        out.push_str(&format!(" next input 0x{:04X}", keys));
+    }
+    out.push_str(&format!("\nPC 0x{:03X} I 0x{:03X}", editor.cpu.get_pc(), editor.cpu.get_i()));
+    for reg in 0..16 {
+        out.push_str(&format!(" V{:X} {:02X}", reg, editor.cpu.read_register(reg)));
+    }
+    out
+}
+
+fn execute(editor: &mut TasEditor, words: &[&str], movie_path: &str) -> Result<String, String> {
+    match words.first().cloned() {
+        None => Ok(String::new()),
+        Some("advance") => {
+            let count = if words.len() > 1 { number(words, 1)? } else { 1 };
+            let keys = if words.len() > 2 { Some(keys(words, 2)?) } else { None };
+            for _ in 0..count {
+                editor.frame_advance(keys);
+            }
+            Ok(status(editor))
+        }
+        Some("input") => {
+            editor.set_input(number(words, 1)?, keys(words, 2)?);
+            Ok(status(editor))
+        }
+        Some("seek") => {
+            editor.seek(number(words, 1)?);
+            Ok(status(editor))
+        }
+        Some("status") => Ok(status(editor)),
+        Some("branch") => {
+            let name = words.get(2).cloned().unwrap_or("");
+            match words.get(1).cloned() {
+                Some("save") if !name.is_empty() => {
+                    editor.save_branch(name);
+                    Ok(format!("saved branch {}", name))
+                }
+                Some("load") if editor.load_branch(name) => Ok(status(editor)),
+                Some("delete") if editor.delete_branch(name) => Ok(format!("deleted branch {}", name)),
+                Some("list") => Ok(editor.branches().join("\n")),
+                Some("diff") => {
+                    let a = editor.branch(name).ok_or("unknown branch")?;
+                    let b = editor.branch(words.get(3).cloned().unwrap_or("")).ok_or("unknown branch")?;
+                    Ok(match TasEditor::first_difference(a, b) {
+                        Some(frame) => format!("branches differ from frame {}", frame),
+                        None => "branches are identical".to_string(),
+                    })
+                }
+                _ => Err("unknown branch command or name".to_string()),
+            }
+        }
+        Some("verify") => {
+            let current = editor.frame();
+            editor.seek(editor.movie().len());
+            let result = verify(editor.movie(), editor.rom());
+            editor.seek(current);
+            result.map(|_| "movie replays without desync".to_string()).map_err(|err| err.to_string())
+        }
+        Some("write") => {
+            let path = words.get(1).cloned().unwrap_or(movie_path);
+            let current = editor.frame();
+            editor.seek(editor.movie().len());
+            let bytes = editor.movie().to_bytes();
+            editor.seek(current);
+            let bytes = bytes.map_err(|err| err.to_string())?;
+            fs::write(path, bytes).map_err(|err| err.to_string())?;
+            Ok(format!("wrote {} frames to {}", editor.movie().len(), path))
+        }
+        Some("help") => Ok(HELP.to_string()),
+        Some(other) => Err(format!("unknown command {}, try help", other)),
+    }
+}